@@ -1,45 +1,51 @@
-use std::{collections::HashMap, iter::Peekable};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context, Result};
 use shared::instruction::{NativeFunctions, OpCode, Operation, Variant};
 use shared::{
+    error::CompileError,
     lexer::Lexer,
+    program,
     token::{Token, TokenSpan, TokenType},
 };
 
-macro_rules! error_at {
-    ($loc:expr, $msg:expr,  $($items:expr),*) => {{
-        let msg = format!($msg, $($items),*);
-        let msg = format!("{}, at {}:{}:{}", msg, $loc.file, $loc.start_line, $loc.start_column);
-        anyhow::anyhow!(msg)
-    }
-    };
-    ($loc:expr, $msg:expr) => {{
-        let msg = format!("{}, at {}:{}:{}", $msg, $loc.file, $loc.start_line, $loc.start_column);
-        anyhow::anyhow!(msg)
-    }
-    };
-}
-
 #[derive(Debug)]
 pub struct Assembler {
-    lexer: Peekable<Lexer>,
+    // The last lexer is for the file being read, the ones before it are files including it
+    lexers: Vec<Lexer>,
+    files: Vec<PathBuf>,
+    pending: Vec<Token>, // the rest of a substituted define, read before the lexer
     current: Token,
+    instruction: Token,
 
+    defines: HashMap<String, Vec<Token>>,
     labels: HashMap<String, usize>,
     unresolved_labels: Vec<UnresolvedLabel>,
+    entry_label: Option<Token>, // set by .entry, the program starts at 0 without it
+    entry: usize,
     program: Vec<usize>,
 }
 
 impl Assembler {
     pub fn new(mut lexer: Lexer) -> Result<Self> {
-        let current = lexer.next().with_context(|| format!("Lexer was empty"))?;
+        let current = lexer.next().with_context(|| format!("Lexer was empty"))??;
+        let file = canonical(&current.span.file);
 
         Ok(Self {
-            lexer: lexer.peekable(),
+            lexers: vec![lexer],
+            files: vec![file],
+            pending: vec![],
+            instruction: current.clone(),
             current,
+            defines: HashMap::new(),
             labels: HashMap::new(),
             unresolved_labels: Vec::new(),
+            entry_label: None,
+            entry: 0,
             program: vec![],
         })
     }
@@ -47,23 +53,38 @@ impl Assembler {
     fn advance(&mut self) -> Result<Token> {
         // println!("advancing from {:#?}", self.current);
         let old = self.current.clone();
-        self.current = self
-            .lexer
-            .next()
-            .with_context(|| error_at!(self.current.span, "Ran out of tokens"))?;
-        Ok(old)
+        if let Some(token) = self.pending.pop() {
+            self.current = token;
+            return Ok(old);
+        }
+
+        loop {
+            self.current = self.lexers.last_mut().unwrap().next().ok_or_else(|| {
+                CompileError::UnexpectedEof {
+                    span: self.current.span.clone(),
+                }
+            })??;
+
+            // An included file ends where the .include was
+            if self.current.r#type != TokenType::EoF || self.lexers.len() == 1 {
+                return Ok(old);
+            }
+            self.lexers.pop();
+            self.files.pop();
+        }
     }
 
     fn eat(&mut self, expected: TokenType) -> Result<Token> {
         if self.current.r#type == expected {
             self.advance()
         } else {
-            Err(error_at!(
-                self.current.span,
-                "Expected {:?} but got {:?}",
+            Err(CompileError::UnexpectedToken {
                 expected,
-                self.current.r#type
-            ))
+                found: self.current.r#type,
+                value: self.current.value.clone(),
+                span: self.current.span.clone(),
+            }
+            .into())
         }
     }
 
@@ -78,11 +99,25 @@ impl Assembler {
         Ok(self.program.clone())
     }
 
+    // Where the program starts, only known after `assemble`
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
     fn next(&mut self) -> Result<Vec<usize>> {
         match self.current.r#type {
             TokenType::Dot => {
                 self.eat(TokenType::Dot)?;
                 let label = self.eat(TokenType::Identifier)?;
+                // Directives, anything followed by a colon is a label
+                match (label.value.as_str(), self.current.r#type) {
+                    ("string", TokenType::String) => return self.handle_string(),
+                    ("align", TokenType::Number) => return self.handle_align(),
+                    ("include", TokenType::String) => return self.handle_include(),
+                    ("define", TokenType::Identifier) => return self.handle_define(&label),
+                    ("entry", TokenType::Dot) => return self.handle_entry(),
+                    _ => {}
+                }
                 self.eat(TokenType::Colon)?;
                 self.labels.insert(label.value, self.program.len());
                 return Ok(vec![]);
@@ -90,71 +125,146 @@ impl Assembler {
             TokenType::Identifier => {
                 return self.handle_instruction();
             }
-            _ => return Err(anyhow!("dwajdkaw")),
+            _ => {
+                return Err(CompileError::UnexpectedToken {
+                    expected: TokenType::Identifier,
+                    found: self.current.r#type,
+                    value: self.current.value.clone(),
+                    span: self.current.span.clone(),
+                }
+                .into())
+            }
         }
     }
 
     fn handle_instruction(&mut self) -> Result<Vec<usize>> {
         let instruction = self.eat(TokenType::Identifier)?;
+        self.instruction = instruction.clone();
 
-        match instruction.value.as_str() {
+        let code = match instruction.value.as_str() {
             "mov" => self.handle_mov(),
             "push" => self.handle_push(),
-            "dup" => self.handle_zero_operands(Operation::Dup),
+            "push_n" => self.handle_push_n(vec![]),
+            "pop_n" => self.handle_pop_n(),
+            "dup" => self.handle_dup(),
             "add" => self.handle_zero_operands(Operation::Add),
             "sub" => self.handle_zero_operands(Operation::Sub),
             "mult" => self.handle_zero_operands(Operation::Mult),
             "div" => self.handle_zero_operands(Operation::Div),
             "mod" => self.handle_zero_operands(Operation::Mod),
+            "and" => self.handle_zero_operands(Operation::And),
+            "or" => self.handle_zero_operands(Operation::Or),
+            "xor" => self.handle_zero_operands(Operation::Xor),
             "jmp" => self.handle_jmp(Operation::Jmp),
             "jmp_if" => self.handle_jmp(Operation::JmpIf),
+            "jmp_if_not" => self.handle_jmp(Operation::JmpIfNot),
+            "cmp" => self.handle_cmp(Operation::Cmp),
             "cmp_eq" => self.handle_cmp(Operation::CmpEq),
             "cmp_ne" => self.handle_cmp(Operation::CmpNe),
             "cmp_gt" => self.handle_cmp(Operation::CmpGt),
             "cmp_lt" => self.handle_cmp(Operation::CmpLt),
             "cmp_gte" => self.handle_cmp(Operation::CmpGte),
             "cmp_lte" => self.handle_cmp(Operation::CmpLte),
+            "cmp_str_eq" => self.handle_cmp(Operation::CmpStrEq),
             "call" => self.handle_call(),
             "ret" => self.handle_zero_operands(Operation::Ret),
             "not" => self.handle_zero_operands(Operation::Not),
-            "swap" => self.handle_zero_operands(Operation::Swap),
+            "swap" => self.handle_swap(),
             "pop" => self.handle_zero_operands(Operation::Pop),
-            other => Err(error_at!(
-                self.current.span,
-                "Unknown instruction {}",
-                other
-            )),
+            "halt" => self.handle_zero_operands(Operation::Halt),
+            "nop" => self.handle_zero_operands(Operation::Nop),
+            "alloc" => self.handle_zero_operands(Operation::Alloc),
+            "load" => self.handle_zero_operands(Operation::Load),
+            "store" => self.handle_zero_operands(Operation::Store),
+            other => Err(CompileError::UnknownInstruction {
+                name: other.to_string(),
+                span: instruction.span.clone(),
+            }
+            .into()),
+        }?;
+
+        // The disassembler and the VM go by the opcode to know where the next instruction starts
+        let opcode = OpCode::from_usize(code[0]);
+        if opcode.operand_words(code.get(1).copied()) != Some(code.len() - 1) {
+            return Err(anyhow!(
+                "{} was assembled to {} words, which doesn't match its opcode",
+                instruction.value,
+                code.len()
+            ));
+        }
+
+        self.expect_line_end()?;
+        Ok(code)
+    }
+
+    fn on_instruction_line(&self) -> bool {
+        self.current.r#type != TokenType::EoF
+            && self.current.span.file == self.instruction.span.file
+            && self.current.span.start_line == self.instruction.span.start_line
+    }
+
+    // Makes sure that the next operand is on the same line as the instruction
+    fn expect_operand(&self) -> Result<()> {
+        if self.on_instruction_line() {
+            Ok(())
+        } else {
+            Err(CompileError::MissingOperand {
+                instruction: self.instruction.value.clone(),
+                span: self.instruction.span.clone(),
+            }
+            .into())
+        }
+    }
+
+    // Makes sure that nothing is left on the line after the instruction
+    fn expect_line_end(&self) -> Result<()> {
+        if self.on_instruction_line() {
+            Err(CompileError::TooManyOperands {
+                instruction: self.instruction.value.clone(),
+                span: self.current.span.clone(),
+            }
+            .into())
+        } else {
+            Ok(())
         }
     }
 
     fn capture_operand(&mut self) -> Result<Operand> {
-        let current = self.advance()?;
+        self.expect_operand()?;
+        let mut current = self.advance()?;
+
+        // Defines are replaced by what they were defined as, put where the name was
+        if let Some(tokens) = self
+            .defines
+            .get(&current.value)
+            .filter(|_| current.r#type == TokenType::Identifier)
+        {
+            let mut tokens: Vec<Token> = tokens
+                .iter()
+                .map(|t| Token::new(t.r#type, current.span.clone(), t.value.clone()))
+                .collect();
+            self.pending.push(self.current.clone());
+            self.current = tokens.remove(0);
+            self.pending.extend(tokens.into_iter().rev());
+            current = self.advance()?;
+        }
 
         match current.r#type {
-            TokenType::Number => {
-                let num = current.value.parse::<usize>().with_context(|| {
-                    error_at!(self.current.span, "{} is not a valid number", current.value)
-                })?;
-                Ok(Operand::Direct(num))
-            }
+            TokenType::Number | TokenType::Char => Ok(Operand::Direct(current.number()?)),
             TokenType::Identifier => {
                 let id = current;
                 self.eat(TokenType::LParen)?;
-                let num = self
-                    .eat(TokenType::Number)?
-                    .value
-                    .parse::<usize>()
-                    .with_context(|| format!("{} is not a valid number", id.value))?;
+                let num = self.eat(TokenType::Number)?.number()?;
                 self.eat(TokenType::RParen)?;
                 match id.value.as_str() {
                     "s" => Ok(Operand::Stack(num)),
-                    "sa" => Ok(Operand::StackRelative(num)),
+                    "sa" => Ok(Operand::StackAbsolute(num)),
                     "r" => Ok(Operand::Register(num)),
-                    other => Err(error_at!(
-                        self.current.span,
-                        "Unknown operation '{:?}'",
-                        other
-                    )),
+                    other => Err(CompileError::UnknownOperandKind {
+                        name: other.to_string(),
+                        span: id.span.clone(),
+                    }
+                    .into()),
                 }
             }
             TokenType::Dot => {
@@ -165,24 +275,36 @@ impl Assembler {
                 let label = self.eat(TokenType::Identifier)?;
                 Ok(Operand::Native(label.value))
             }
-            other => Err(error_at!(
-                self.current.span,
-                "Operand cant start with {:?}",
-                other
-            )),
+            other => Err(CompileError::InvalidOperand {
+                found: other,
+                span: current.span.clone(),
+            }
+            .into()),
         }
     }
 
+    // Any of r(n), s(n) and sa(n) can be written, from any of those or a plain number
     fn handle_mov(&mut self) -> Result<Vec<usize>> {
+        let first_span = self.current.span.clone();
         let first = self.capture_operand()?;
+        self.expect_operand()?;
         self.eat(TokenType::Comma)?;
+        let second_span = self.current.span.clone();
         let second = self.capture_operand()?;
-        let variants = [first.as_variant()?, second.as_variant()?, Variant::None];
+
+        if !first.is_writable() {
+            return Err(CompileError::InvalidMovDestination { span: first_span }.into());
+        }
+        if !second.is_readable() {
+            return Err(CompileError::InvalidMovSource { span: second_span }.into());
+        }
+        let (first, second) = (first.encode()?, second.encode()?);
+        let variants = [first.variant, second.variant, Variant::None];
 
         Ok(vec![
             OpCode::new(Operation::Mov, variants).as_usize(),
-            first.as_usize()?,
-            second.as_usize()?,
+            first.value,
+            second.value,
         ])
     }
 
@@ -200,25 +322,185 @@ impl Assembler {
     }
 
     fn handle_push(&mut self) -> Result<Vec<usize>> {
+        let span = self.current.span.clone();
         let operand = self.capture_operand()?;
-        let variants = [operand.as_variant()?, Variant::None, Variant::None];
+        // push a, b, c is the same as push_n a, b, c
+        if self.on_instruction_line() && self.current.r#type == TokenType::Comma {
+            self.eat(TokenType::Comma)?;
+            let first = self.direct_value(operand, span, "Values pushed together")?;
+            return self.handle_push_n(vec![first]);
+        }
+
+        let operand = operand.encode()?;
+
+        let variants = [operand.variant, Variant::None, Variant::None];
 
         Ok(vec![
             OpCode::new(Operation::Push, variants).as_usize(),
-            operand.as_usize()?,
+            operand.value,
+        ])
+    }
+
+    // push_n a, b, c pushes every value with one instruction. `values` are the ones already read
+    fn handle_push_n(&mut self, mut values: Vec<usize>) -> Result<Vec<usize>> {
+        loop {
+            self.expect_operand()?;
+            let span = self.current.span.clone();
+            let operand = self.capture_operand()?;
+            values.push(self.direct_value(operand, span, "Values pushed together")?);
+
+            if !self.on_instruction_line() || self.current.r#type != TokenType::Comma {
+                break;
+            }
+            self.eat(TokenType::Comma)?;
+        }
+
+        let mut code = vec![
+            OpCode::new(Operation::PushN, [Variant::None; 3]).as_usize(),
+            values.len(),
+        ];
+        code.append(&mut values);
+        Ok(code)
+    }
+
+    fn handle_pop_n(&mut self) -> Result<Vec<usize>> {
+        self.expect_operand()?;
+        let span = self.current.span.clone();
+        let operand = self.capture_operand()?;
+        let variants = [Variant::Direct, Variant::None, Variant::None];
+
+        Ok(vec![
+            OpCode::new(Operation::PopN, variants).as_usize(),
+            self.direct_value(operand, span, "pop_n counts")?,
+        ])
+    }
+
+    // For operands stored as they are, without a variant to say how to read them
+    fn direct_value(&self, operand: Operand, span: TokenSpan, what: &'static str) -> Result<usize> {
+        match operand {
+            Operand::Direct(value) => Ok(value),
+            _ => Err(CompileError::DirectOperandOnly { what, span }.into()),
+        }
+    }
+
+    // swap on its own swaps the top two, swap n swaps the top with the value n below it
+    fn handle_swap(&mut self) -> Result<Vec<usize>> {
+        if !self.on_instruction_line() {
+            return self.handle_zero_operands(Operation::Swap);
+        }
+
+        let span = self.current.span.clone();
+        let operand = self.capture_operand()?;
+        let variants = [Variant::Direct, Variant::None, Variant::None];
+
+        Ok(vec![
+            OpCode::new(Operation::Swap, variants).as_usize(),
+            self.direct_value(operand, span, "swap depths")?,
         ])
     }
 
     fn handle_dup(&mut self) -> Result<Vec<usize>> {
+        let span = self.current.span.clone();
         let operand = self.capture_operand()?;
-        let variants = [operand.as_variant()?, Variant::None, Variant::None];
+        if !operand.is_readable() {
+            return Err(CompileError::InvalidDupOperand { span }.into());
+        }
+        let operand = operand.encode()?;
+        let variants = [operand.variant, Variant::None, Variant::None];
 
         Ok(vec![
             OpCode::new(Operation::Dup, variants).as_usize(),
-            operand.as_usize()?,
+            operand.value,
         ])
     }
 
+    // .string "text" embeds the string as data, its length followed by one word per character
+    fn handle_string(&mut self) -> Result<Vec<usize>> {
+        let string = self.eat(TokenType::String)?;
+        let mut data = vec![string.value.chars().count()];
+        data.extend(string.value.chars().map(|c| c as usize));
+        Ok(data)
+    }
+
+    // .align n pads with nops until the program length is a multiple of n
+    fn handle_align(&mut self) -> Result<Vec<usize>> {
+        let token = self.eat(TokenType::Number)?;
+        let alignment = token.number()?;
+        if alignment == 0 {
+            return Err(CompileError::InvalidNumber {
+                value: token.value,
+                span: token.span,
+            }
+            .into());
+        }
+
+        let padding = (alignment - self.program.len() % alignment) % alignment;
+        let nop = OpCode::new(
+            Operation::Nop,
+            [Variant::None, Variant::None, Variant::None],
+        );
+        Ok(vec![nop.as_usize(); padding])
+    }
+
+    // .include "file.rasm" reads the file in place of the directive, relative to the file it's in
+    fn handle_include(&mut self) -> Result<Vec<usize>> {
+        let token = self.current.clone();
+        let path = Path::new(&token.span.file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&token.value);
+
+        let source = fs::read_to_string(&path).map_err(|err| CompileError::IncludeFailed {
+            path: path.display().to_string(),
+            reason: err.to_string(),
+            span: token.span.clone(),
+        })?;
+        let file = canonical(&path.display().to_string());
+        if self.files.contains(&file) {
+            return Err(CompileError::CircularInclude {
+                path: path.display().to_string(),
+                span: token.span,
+            }
+            .into());
+        }
+
+        self.lexers
+            .push(Lexer::new(&source, path.display().to_string()));
+        self.files.push(file);
+        self.advance()?; // the path, the next token comes from the included file
+        Ok(vec![])
+    }
+
+    // .define NAME tokens... makes NAME stand for the rest of the line when used as an operand
+    fn handle_define(&mut self, directive: &Token) -> Result<Vec<usize>> {
+        let name = self.eat(TokenType::Identifier)?;
+
+        let mut tokens = vec![];
+        while self.current.r#type != TokenType::EoF
+            && self.current.span.file == directive.span.file
+            && self.current.span.start_line == directive.span.start_line
+        {
+            tokens.push(self.advance()?);
+        }
+
+        if tokens.is_empty() {
+            return Err(CompileError::EmptyDefine {
+                name: name.value,
+                span: name.span,
+            }
+            .into());
+        }
+        self.defines.insert(name.value, tokens);
+        Ok(vec![])
+    }
+
+    // .entry .label makes the program start at the label instead of at the top
+    fn handle_entry(&mut self) -> Result<Vec<usize>> {
+        self.eat(TokenType::Dot)?;
+        self.entry_label = Some(self.eat(TokenType::Identifier)?);
+        Ok(vec![])
+    }
+
     fn handle_zero_operands(&mut self, op: Operation) -> Result<Vec<usize>> {
         let variants = [Variant::None, Variant::None, Variant::None];
         Ok(vec![OpCode::new(op, variants).as_usize()])
@@ -245,12 +527,10 @@ impl Assembler {
                 }
             }
             _ => {
-                let variants = [operand.as_variant()?, Variant::None, Variant::None];
+                let operand = operand.encode()?;
+                let variants = [operand.variant, Variant::None, Variant::None];
 
-                Ok(vec![
-                    OpCode::new(op, variants).as_usize(),
-                    operand.as_usize()?,
-                ])
+                Ok(vec![OpCode::new(op, variants).as_usize(), operand.value])
             }
         }
     }
@@ -269,7 +549,7 @@ impl Assembler {
                 } else {
                     self.unresolved_labels.push(UnresolvedLabel {
                         label,
-                        location: self.program.len(),
+                        location: self.program.len() + 1, // +1 for +0 is where the operation goes,
                         // not the operand
                         span: self.current.span.clone(),
                     });
@@ -281,17 +561,21 @@ impl Assembler {
                 let variants = [Variant::Native, Variant::None, Variant::None];
                 Ok(vec![
                     OpCode::new(Operation::Call, variants).as_usize(),
-                    NativeFunctions::from_string(&name).with_context(|| {
-                        error_at!(self.current.span, "Unknown native function {}", name)
+                    NativeFunctions::from_string(&name).ok_or_else(|| {
+                        CompileError::UnknownNativeFunction {
+                            name: name.clone(),
+                            span: self.current.span.clone(),
+                        }
                     })? as usize,
                 ])
             }
             _ => {
-                let variants = [operand.as_variant()?, Variant::None, Variant::None];
+                let operand = operand.encode()?;
+                let variants = [operand.variant, Variant::None, Variant::None];
 
                 Ok(vec![
                     OpCode::new(Operation::Call, variants).as_usize(),
-                    operand.as_usize()?,
+                    operand.value,
                 ])
             }
         }
@@ -299,13 +583,27 @@ impl Assembler {
 
     pub fn resolve_labels(&mut self) -> Result<()> {
         for label in &self.unresolved_labels {
-            let label_loc = self
-                .labels
-                .get(&label.label)
-                .with_context(|| error_at!(label.span, "Couldn't find label '{}'", label.label))?;
+            let label_loc =
+                self.labels
+                    .get(&label.label)
+                    .ok_or_else(|| CompileError::UnknownLabel {
+                        label: label.label.clone(),
+                        span: label.span.clone(),
+                    })?;
             self.program[label.location] = *label_loc;
         }
 
+        if let Some(label) = &self.entry_label {
+            let entry =
+                self.labels
+                    .get(&label.value)
+                    .ok_or_else(|| CompileError::UnknownLabel {
+                        label: label.value.clone(),
+                        span: label.span.clone(),
+                    })?;
+            self.entry = *entry;
+        }
+
         Ok(())
     }
 }
@@ -314,7 +612,7 @@ enum Operand {
     Register(usize),
     Direct(usize),
     Stack(usize),
-    StackRelative(usize),
+    StackAbsolute(usize),
     Label(String),
     Native(String),
 }
@@ -325,7 +623,7 @@ impl Operand {
             Operand::Register(_) => Ok(Variant::Register),
             Operand::Direct(_) => Ok(Variant::Direct),
             Operand::Stack(_) => Ok(Variant::Stack),
-            Operand::StackRelative(_) => Ok(Variant::StackAbsoulute),
+            Operand::StackAbsolute(_) => Ok(Variant::StackAbsolute),
             Operand::Native(_) => Ok(Variant::Native),
             _ => Err(anyhow!("Operand cant be a variant")),
         }
@@ -336,11 +634,26 @@ impl Operand {
             Operand::Register(v) => Ok(*v),
             Operand::Direct(v) => Ok(*v),
             Operand::Stack(v) => Ok(*v),
-            Operand::StackRelative(v) => Ok(*v),
+            Operand::StackAbsolute(v) => Ok(*v),
             _ => Err(anyhow!("Operand cant be a usize")),
         }
     }
 
+    // The operand as it's stored in the program, decoded the same way the VM and disassembler
+    // will. Labels and natives are resolved by the instructions that take them
+    pub fn encode(&self) -> Result<program::Operand> {
+        program::Operand::from_variant_value(self.as_variant()?, self.as_usize()?)
+    }
+
+    // Labels are addresses, but only jmp and call resolve them
+    pub fn is_readable(&self) -> bool {
+        self.as_variant().is_ok_and(|v| v.is_readable())
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.as_variant().is_ok_and(|v| v.is_writable())
+    }
+
     pub fn as_string(&self) -> Result<String> {
         match self {
             Operand::Label(s) => Ok(s.clone()),
@@ -350,9 +663,299 @@ impl Operand {
     }
 }
 
+// Used to spot circular includes, falls back to the path as given for files that don't exist
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
 #[derive(Debug)]
 struct UnresolvedLabel {
     pub label: String,
     pub location: usize,
     pub span: TokenSpan,
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::{error::CompileError, lexer::Lexer};
+
+    use super::*;
+
+    fn assemble(source: &str) -> Result<Vec<usize>> {
+        Assembler::new(Lexer::new(source, "test.rasm".to_string()))?.assemble()
+    }
+
+    fn error(source: &str) -> CompileError {
+        match assemble(source) {
+            Ok(_) => panic!("assembled: {}", source),
+            Err(err) => err
+                .downcast_ref::<CompileError>()
+                .cloned()
+                .unwrap_or_else(|| panic!("not a CompileError: {:#}", err)),
+        }
+    }
+
+    #[test]
+    fn too_many_operands() {
+        for (source, instruction) in [
+            ("add 5", "add"),
+            ("mov r(0), 1, 2", "mov"),
+            ("push 1 2", "push"),
+        ] {
+            match error(source) {
+                CompileError::TooManyOperands {
+                    instruction: found, ..
+                } => {
+                    assert_eq!(found, instruction)
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn too_few_operands() {
+        for (source, instruction) in [("mov r(0)", "mov"), ("mov", "mov"), ("push", "push")] {
+            match error(source) {
+                CompileError::MissingOperand {
+                    instruction: found, ..
+                } => {
+                    assert_eq!(found, instruction)
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn operands_end_at_the_line() {
+        assert!(assemble("push 1\nadd\nmov r(0), 1\nhalt").is_ok());
+    }
+
+    #[test]
+    fn unknown_instruction() {
+        assert_eq!(
+            error("push 1\nfly 2"),
+            CompileError::UnknownInstruction {
+                name: "fly".to_string(),
+                span: TokenSpan::new("test.rasm".to_string(), 2, 1, 2, 4),
+            }
+        );
+    }
+
+    #[test]
+    fn nop_emits_its_opcode() {
+        let nop = OpCode::new(Operation::Nop, [Variant::None; 3]).as_usize();
+        assert_eq!(assemble("nop").unwrap(), [nop]);
+    }
+
+    #[test]
+    fn align_pads_with_nops() {
+        let nop = OpCode::new(Operation::Nop, [Variant::None; 3]).as_usize();
+        let halt = OpCode::new(Operation::Halt, [Variant::None; 3]).as_usize();
+        // Already aligned at 0 and 4, padded from 1, 2 and 3
+        assert_eq!(assemble(".align 4\nhalt").unwrap(), [halt]);
+        for before in 1..=4 {
+            let source = format!("{}.align 4\nhalt", "halt\n".repeat(before));
+            let program = assemble(&source).unwrap();
+            assert_eq!(program.len(), 5, "{}", source);
+            assert!(program[before..4].iter().all(|&word| word == nop));
+            assert_eq!(program[4], halt);
+        }
+        assert!(matches!(
+            error(".align 0"),
+            CompileError::InvalidNumber { .. }
+        ));
+    }
+
+    // Writes each file into a fresh directory and assembles the first one
+    fn assemble_files(name: &str, files: &[(&str, &str)]) -> Result<Vec<usize>> {
+        let dir = std::env::temp_dir().join(format!("asm_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir)?;
+        for (file, source) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, source)?;
+        }
+        let main = dir.join(files[0].0).display().to_string();
+        let result = Assembler::new(Lexer::new(files[0].1, main)).and_then(|mut a| a.assemble());
+        fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    fn include_error(name: &str, files: &[(&str, &str)]) -> CompileError {
+        let err = assemble_files(name, files).unwrap_err();
+        err.downcast_ref::<CompileError>()
+            .cloned()
+            .unwrap_or_else(|| panic!("not a CompileError: {:#}", err))
+    }
+
+    #[test]
+    fn include_and_define() {
+        let files = [
+            (
+                "main.rasm",
+                ".include \"lib/consts.rasm\"\npush COUNT\ncall .double\nhalt",
+            ),
+            (
+                "lib/consts.rasm",
+                ".define COUNT 3\n.include \"double.rasm\"",
+            ),
+            (
+                "lib/double.rasm",
+                "jmp .end\n.double:\npush 2\nmult\nret\n.end:",
+            ),
+        ];
+        let program = assemble_files("include_and_define", &files).unwrap();
+        let inline =
+            assemble("jmp .end\n.double:\npush 2\nmult\nret\n.end:\npush 3\ncall .double\nhalt");
+        assert_eq!(program, inline.unwrap());
+    }
+
+    #[test]
+    fn include_errors() {
+        let files = [("main.rasm", ".include \"missing.rasm\"")];
+        assert!(matches!(
+            include_error("missing", &files),
+            CompileError::IncludeFailed { .. }
+        ));
+
+        let files = [
+            ("a.rasm", ".include \"b.rasm\"\nhalt"),
+            ("b.rasm", ".include \"a.rasm\""),
+        ];
+        match include_error("circular", &files) {
+            CompileError::CircularInclude { path, .. } => assert!(path.ends_with("a.rasm")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn inline_comments_after_every_instruction_form() {
+        let commented = "
+            .main: ; label
+            push 5 ; one operand
+            push 1, 2 ; several
+            mov r(0), s(1) ; two operands
+            add ; none
+            swap 2 ; depth
+            jmp_if .main ; label operand
+            call $print ; native
+            halt ; last line, no newline";
+        let plain = "
+            .main:
+            push 5
+            push 1, 2
+            mov r(0), s(1)
+            add
+            swap 2
+            jmp_if .main
+            call $print
+            halt";
+        assert_eq!(assemble(commented).unwrap(), assemble(plain).unwrap());
+        assert_eq!(assemble("halt ;").unwrap(), assemble("halt").unwrap());
+        assert_eq!(assemble("push 5;x").unwrap(), assemble("push 5").unwrap());
+    }
+
+    #[test]
+    fn prefixed_number_operands() {
+        assert_eq!(
+            assemble("push 0x10\npush 0b101\nmov r(0x1), 0xff").unwrap(),
+            assemble("push 16\npush 5\nmov r(1), 255").unwrap()
+        );
+        assert_eq!(assemble("push 0xffffffffffffffff").unwrap()[1], usize::MAX);
+        for source in ["push 18446744073709551616", "push 0x10000000000000000"] {
+            match error(source) {
+                CompileError::NumberTooLarge { span, .. } => assert_eq!(span.start_column, 6),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn mov_operands_are_checked() {
+        for source in ["mov 5, r(0)", "mov $print, r(0)", "mov .main, 1\n.main:"] {
+            assert!(
+                matches!(error(source), CompileError::InvalidMovDestination { .. }),
+                "{}",
+                source
+            );
+        }
+        assert!(matches!(
+            error("mov r(0), $print"),
+            CompileError::InvalidMovSource { .. }
+        ));
+        for source in [
+            "mov r(0), 5",
+            "mov r(1), r(0)",
+            "mov r(0), s(0)",
+            "mov s(0), 5",
+            "mov sa(0), 5",
+        ] {
+            assert!(assemble(source).is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn dup_of_a_native_is_rejected() {
+        assert!(matches!(
+            error("dup $print"),
+            CompileError::InvalidDupOperand { .. }
+        ));
+    }
+
+    // push_n's operand is the count of values after it, so it's one word longer than that
+    #[test]
+    fn operand_count_matches_what_is_emitted() {
+        for operation in Operation::iter() {
+            let operands = match operation {
+                Operation::Mov => " r(0), 1",
+                Operation::Jmp | Operation::JmpIf | Operation::JmpIfNot | Operation::Call => {
+                    " .end\n.end:"
+                }
+                Operation::Push | Operation::Swap | Operation::PopN | Operation::PushN => " 2",
+                Operation::Dup => " s(0)",
+                _ => "",
+            };
+            let source = format!("{}{}", operation.to_asm(), operands);
+            let words = assemble(&source).unwrap();
+            let extra = usize::from(operation == Operation::PushN);
+            assert_eq!(
+                words.len(),
+                1 + operation.operand_count() + extra,
+                "{}",
+                source
+            );
+        }
+    }
+
+    // Disassembled back into the same mnemonic
+    #[test]
+    fn bitwise_round_trip() {
+        for (mnemonic, operation) in [
+            ("and", Operation::And),
+            ("or", Operation::Or),
+            ("xor", Operation::Xor),
+        ] {
+            let words = assemble(mnemonic).unwrap();
+            assert_eq!(
+                words,
+                [OpCode::new(operation, [Variant::None; 3]).as_usize()]
+            );
+            let program = shared::program::ProgramParser::new(words).parse().unwrap();
+            assert_eq!(program.actions[0].format().trim_end(), mnemonic);
+        }
+    }
+
+    // A nop that is jumped over isn't string data, it has to come back as a nop
+    #[test]
+    fn jump_over_nop_round_trip() {
+        let words = assemble("jmp .a\nnop\n.a:").unwrap();
+        let program = shared::program::ProgramParser::new(words.clone())
+            .parse()
+            .unwrap();
+        let text = program.to_asm(0).unwrap();
+        assert!(!text.contains(".string"), "{}", text);
+        assert_eq!(assemble(&text).unwrap(), words);
+    }
+}
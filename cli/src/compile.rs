@@ -1,55 +1,264 @@
 use std::{fs::File, io::Write};
 
+use anyhow::{anyhow, Result};
 use asm::assembler::Assembler;
-use risp::{codegen::CodeGen, parser::Parser};
+use clap::ValueEnum;
+use risp::parser::Parser;
 use shared::{fileformat::FileFormat, lexer::Lexer, program::ProgramParser};
 
+use crate::run::{self, is_risp, print_warnings, read_file, read_format, read_source};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Target {
+    Bin,
+    Rasm, // assembly that can be assembled back into the same program
+}
+
 pub struct CompileArgs {
     pub input_path: String,
     pub output_path: Option<String>,
+    pub target: Option<Target>,
+    pub tokens: bool,
     pub ast: bool,
     pub asm: bool,
+    pub dump_bytecode: bool,
+    pub optimize: u8,
+    pub strict: bool,
+    pub entry: Option<String>,
+    pub defines: Vec<String>,
+    pub check: bool,
 }
 
-pub fn compile(args: CompileArgs) {
-    let output = args.output_path.unwrap_or("a.bin".to_string());
+// Goes through the whole pipeline like a normal compile, but only to see if it fails. The result
+// is also checked for jumps and calls into the middle of instructions
+fn check(args: &CompileArgs) -> Result<()> {
+    let (program, entry) = run::load(
+        &args.input_path,
+        args.optimize,
+        args.strict,
+        args.entry.as_deref(),
+        &args.defines,
+    )?;
+    ProgramParser::new(program).parse()?.verify_targets(entry)
+}
 
-    if args.input_path.ends_with(".rasm") {
-        let lexer = Lexer::new_from_path(args.input_path.to_string());
-        let mut asm = Assembler::new(lexer).unwrap();
-        let program = asm.assemble().unwrap();
+// Every word with its index, as decimal, hex and binary
+fn dump_bytecode(program: &[usize]) -> String {
+    program
+        .iter()
+        .enumerate()
+        .map(|(i, value)| format!("{}: {} {:#X} {:#b}\n", i, value, value, value))
+        .collect()
+}
 
-        let format = FileFormat::new(program);
-        format.write_to_file(output).unwrap(); // TODO: output should be
-    } else if args.input_path.ends_with(".risp") {
-        // Lisp
-        let lexer = Lexer::new_from_path(args.input_path.to_string());
-        let ast = Parser::parse(lexer).unwrap();
+pub fn compile(args: CompileArgs) -> Result<()> {
+    if args.entry.is_some() && !is_risp(&args.input_path) {
+        return Err(anyhow!(
+            "--entry only works with .risp, functions aren't named otherwise"
+        ));
+    }
+
+    if args.check {
+        // --check then nothing is written
+        return check(&args);
+    }
 
+    // Without --target it goes by the extension of the output
+    let target = args.target.unwrap_or(match &args.output_path {
+        Some(path) if path.ends_with(".rasm") => Target::Rasm,
+        _ => Target::Bin,
+    });
+    let output = args.output_path.unwrap_or(match target {
+        Target::Bin => "a.bin".to_string(),
+        Target::Rasm => "a.rasm".to_string(),
+    });
+
+    if args.tokens {
+        // --tokens then print the tokens, works the same for both .rasm and .risp
+        let (source, filename) = read_source(&args.input_path)?;
+        for token in Lexer::new(&source, filename) {
+            let token = token?;
+            println!("{} {:?} {:?}", token.span, token.r#type, token.value);
+        }
+        return Ok(());
+    }
+
+    let (program, entry) = if args.input_path.ends_with(".rasm") {
+        let source = read_file(&args.input_path)?;
+        let lexer = Lexer::new(&source, args.input_path.to_string());
+        let mut asm = Assembler::new(lexer)?;
+        let program = asm.assemble()?;
+        (program, asm.entry())
+    } else if is_risp(&args.input_path) {
+        // Lisp
+        let (source, filename) = read_source(&args.input_path)?;
         if args.ast {
             // --ast  then print the ast
+            let lexer = Lexer::new(&source, filename);
+            let ast = Parser::parse(lexer)?;
             println!("{:#?}", ast);
-            return;
+            return Ok(());
         }
 
-        let program = CodeGen::new().generate(ast).unwrap();
+        let compiled = risp::compile_source(
+            &source,
+            &filename,
+            args.optimize,
+            args.strict,
+            args.entry.as_deref(),
+            &args.defines,
+        )?;
+        print_warnings(&compiled.warnings);
 
         if args.asm {
-            let program = ProgramParser::new(program.0.clone()).parse().unwrap();
+            let program = ProgramParser::new(compiled.program.clone()).parse()?;
             println!("{}", program.to_string());
         }
+        (compiled.program, compiled.entry)
+    } else {
+        // Compiled in any supported version, written back out in the current one
+        let format = read_format(&args.input_path)?;
+        (format.program, format.entry)
+    };
+
+    if args.dump_bytecode {
+        print!("{}", dump_bytecode(&program));
+    }
 
-        // Output as rasm
-        if output.ends_with(".rasm") {
-            let mut f = File::create(output).unwrap();
-            let program = ProgramParser::new(program.0).parse().unwrap();
-            let text = program.to_string().bytes().collect::<Vec<u8>>();
-            f.write_all(&text).unwrap()
-        } else {
-            let format = FileFormat::new(program.0);
-            format.write_to_file(output).unwrap();
+    match target {
+        Target::Rasm => {
+            let mut f = File::create(output)?;
+            let text = ProgramParser::new(program).parse()?.to_asm(entry)?;
+            f.write_all(text.as_bytes())?;
         }
-    } else {
-        panic!("Unknown file format");
+        Target::Bin => {
+            let format = FileFormat::new(program).with_entry(entry);
+            format.write_to_file(output)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::instruction::{OpCode, Operation, Variant};
+    use vm::vm::VM;
+
+    fn args(input_path: &str, output_path: &str, target: Target) -> CompileArgs {
+        CompileArgs {
+            input_path: input_path.to_string(),
+            output_path: Some(output_path.to_string()),
+            target: Some(target),
+            tokens: false,
+            ast: false,
+            asm: false,
+            dump_bytecode: false,
+            optimize: 0,
+            strict: false,
+            entry: None,
+            defines: vec![],
+            check: false,
+        }
+    }
+
+    // What's left on the stack after running, printing goes nowhere
+    fn run(program: Vec<usize>, entry: usize) -> Vec<usize> {
+        let mut vm = VM::new(program, entry).with_output(Box::new(std::io::sink()));
+        vm.run().unwrap();
+        vm.stack().to_vec()
+    }
+
+    #[test]
+    fn risp_to_rasm_to_bin() {
+        let dir = std::env::temp_dir().join(format!("rasm_target_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+
+        let source = "(defun fib $n { (if (< $n 2) { (return $n) }) (return (+ (fib (- $n 1)) (fib (- $n 2)))) })
+            (defun main { (print_str \"fib\") (return (fib 10)) })";
+        std::fs::write(path("fib.risp"), source).unwrap();
+        compile(args(&path("fib.risp"), &path("fib.rasm"), Target::Rasm)).unwrap();
+        compile(args(&path("fib.rasm"), &path("fib.bin"), Target::Bin)).unwrap();
+
+        let compiled = risp::compile_source(source, "fib.risp", 0, false, None, &[]).unwrap();
+        let format = read_format(&path("fib.bin")).unwrap();
+        assert_eq!(format.program, compiled.program);
+        assert_eq!(format.entry, compiled.entry);
+        let stack = run(format.program, format.entry);
+        assert_eq!(stack.last(), Some(&55));
+        assert_eq!(stack, run(compiled.program, compiled.entry));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_writes_nothing() {
+        let dir = std::env::temp_dir().join(format!("check_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        std::fs::write(path("good.risp"), "(print (+ 1 2))").unwrap();
+        std::fs::write(path("bad.risp"), "(print $nope)").unwrap();
+
+        let check = |input: &str| {
+            compile(CompileArgs {
+                check: true,
+                ..args(&path(input), &path("out.bin"), Target::Bin)
+            })
+        };
+        check("good.risp").unwrap();
+        let error = check("bad.risp").unwrap_err();
+        assert!(error.to_string().contains("nope"), "{}", error);
+        assert!(!dir.join("out.bin").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_bin_is_an_error() {
+        let path = std::env::temp_dir().join(format!("corrupt_{}.bin", std::process::id()));
+        let encoded = FileFormat::new(vec![usize::MAX]).encode().unwrap();
+        std::fs::write(&path, encoded).unwrap();
+        let path = path.display().to_string();
+
+        let error = compile(CompileArgs {
+            check: true,
+            ..args(&path, "out.bin", Target::Bin)
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid opcode 0xffffffffffffffff at 0");
+        let error = crate::disassemble::disassemble(crate::disassemble::DisassembleArgs {
+            input_path: path.clone(),
+            output_path: None,
+            json: false,
+            annotate: false,
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid opcode 0xffffffffffffffff at 0");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // From the 5 pushed on, indexed from where the dump starts
+    #[test]
+    fn bytecode_dump() {
+        let lexer = Lexer::new("push 5\nhalt", "test.rasm".to_string());
+        let program = Assembler::new(lexer).unwrap().assemble().unwrap();
+        let push = OpCode::new(
+            Operation::Push,
+            [Variant::Direct, Variant::None, Variant::None],
+        );
+        let halt = OpCode::new(Operation::Halt, [Variant::None; 3]);
+        let line = |i: usize, value: usize| format!("{}: {} {:#X} {:#b}\n", i, value, value, value);
+        assert_eq!(
+            dump_bytecode(&program),
+            [
+                line(0, push.as_usize()),
+                line(1, 5),
+                line(2, halt.as_usize())
+            ]
+            .concat()
+        );
     }
 }
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, Write},
+};
+
+use anyhow::{anyhow, Result};
+use shared::program::ProgramParser;
+use vm::vm::VM;
+
+use crate::run;
+
+pub struct DebugArgs {
+    pub filepath: String,
+}
+
+const HELP: &str = "Commands:
+  step, s          execute one instruction
+  continue, c      run until a breakpoint or the end
+  stack            print the stack
+  regs             print the registers
+  break <pc>, b    pause continue when pc is reached
+  quit, q          stop debugging";
+
+// Reads commands from stdin until quit or end of input
+pub fn debug(args: DebugArgs) -> Result<()> {
+    if args.filepath == run::STDIN {
+        return Err(anyhow!("Can't debug stdin, commands are read from it"));
+    }
+
+    // Unoptimized, so what's stepped through matches the source
+    let (program, entry) = run::load(&args.filepath, 0, false, None, &[])?;
+    session(
+        VM::new(program, entry),
+        io::stdin().lock(),
+        &mut io::stdout(),
+    )
+}
+
+// Runs the commands in `input`, writing the prompt and what they show to `out`
+fn session(mut vm: VM, mut input: impl BufRead, out: &mut impl Write) -> Result<()> {
+    let mut breakpoints: HashSet<usize> = HashSet::new();
+    let mut finished = false;
+
+    print_next(&vm, out)?;
+    loop {
+        write!(out, "(debug) ")?;
+        out.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+
+        match parts.as_slice() {
+            [] => {}
+            ["step" | "s"] => {
+                if finished {
+                    writeln!(out, "Program has finished")?;
+                    continue;
+                }
+                finished = !step(&mut vm, out)?;
+                print_state(&vm, finished, out)?;
+            }
+            ["continue" | "c"] => {
+                if finished {
+                    writeln!(out, "Program has finished")?;
+                    continue;
+                }
+                // Always take one step so that continuing from a breakpoint moves on
+                loop {
+                    if !step(&mut vm, out)? {
+                        finished = true;
+                        break;
+                    }
+                    if breakpoints.contains(&vm.pc()) {
+                        writeln!(out, "Breakpoint at {:#x}", vm.pc())?;
+                        break;
+                    }
+                }
+                print_state(&vm, finished, out)?;
+            }
+            ["stack"] => write!(out, "{}", vm.format_stack())?,
+            ["regs"] => write!(out, "{}", vm.format_registers())?,
+            ["break" | "b", pc] => match parse_pc(pc) {
+                Some(pc) => {
+                    breakpoints.insert(pc);
+                    writeln!(out, "Breakpoint set at {:#x}", pc)?;
+                }
+                None => writeln!(out, "Invalid pc {}", pc)?,
+            },
+            ["quit" | "q"] => break,
+            _ => writeln!(out, "{}", HELP)?,
+        }
+    }
+    Ok(())
+}
+
+// Runtime errors end the program, same as when running normally
+fn step(vm: &mut VM, out: &mut impl Write) -> io::Result<bool> {
+    match vm.step() {
+        Ok(running) => Ok(running),
+        Err(err) => {
+            writeln!(out, "Error: {}", err)?;
+            Ok(false)
+        }
+    }
+}
+
+// Accepts both decimal and 0x prefixed hex
+fn parse_pc(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn print_state(vm: &VM, finished: bool, out: &mut impl Write) -> io::Result<()> {
+    if finished {
+        writeln!(out, "Program has finished")
+    } else {
+        print_next(vm, out)
+    }
+}
+
+fn print_next(vm: &VM, out: &mut impl Write) -> io::Result<()> {
+    let rest = vm.program().get(vm.pc()..).unwrap_or_default();
+    if rest.is_empty() {
+        return writeln!(out, "{:#x}: end of program", vm.pc());
+    }
+
+    match ProgramParser::new(rest.to_vec()).step() {
+        Ok(action) => writeln!(out, "{:#x}: {}", vm.pc(), action.format()),
+        Err(_) => writeln!(out, "{:#x}: ?", vm.pc()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use asm::assembler::Assembler;
+    use shared::lexer::Lexer;
+
+    fn session_output(source: &str, commands: &str) -> String {
+        let mut asm = Assembler::new(Lexer::new(source, "test.rasm".to_string())).unwrap();
+        let vm = VM::new(asm.assemble().unwrap(), asm.entry());
+        let mut out = vec![];
+        session(vm, commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let output = session_output(
+            "push 1\npush 2\nadd\nhalt",
+            "step\nstep\nstack\nbreak 5\ncontinue\nstack\ncontinue\nstep\nquit\n",
+        );
+        let replies: Vec<&str> = output.split("(debug) ").collect();
+        assert_eq!(replies[3], "Stack:\n  0: 1\n  1: 2\n");
+        assert!(replies[5].starts_with("Breakpoint at 0x5\n0x5: halt"));
+        assert_eq!(replies[6], "Stack:\n  0: 3\n");
+        assert_eq!(replies[7], "Program has finished\n");
+        assert_eq!(replies[8], "Program has finished\n");
+    }
+}
@@ -1,23 +1,57 @@
 use std::{fs::File, io::Write};
 
-use shared::program::ProgramParser;
+use anyhow::Result;
+use shared::{fileformat::FileFormat, program::ProgramParser};
+
+use crate::run;
 
 pub struct DisassembleArgs {
     pub input_path: String,
     pub output_path: Option<String>,
+    pub json: bool,
+    pub annotate: bool,
 }
 
-pub fn disassemble(args: DisassembleArgs) {
-    let program = shared::fileformat::FileFormat::from_file(args.input_path)
-        .unwrap()
-        .program;
-    let program = ProgramParser::new(program).parse().unwrap();
+pub fn disassemble(args: DisassembleArgs) -> Result<()> {
+    let format = run::read_format(&args.input_path)?;
+    let text = render(format, args.json, args.annotate)?;
 
     if let Some(output_path) = args.output_path {
-        let mut f = File::open(output_path).unwrap();
-        let text = program.to_string().bytes().collect::<Vec<u8>>();
-        f.write_all(&text).unwrap()
+        let mut f = File::create(output_path)?;
+        f.write_all(text.as_bytes())?;
+    } else {
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+pub(crate) fn render(format: FileFormat, json: bool, annotate: bool) -> Result<String> {
+    let program = ProgramParser::new(format.program).parse()?;
+    Ok(if json {
+        program.to_json()
+    } else if annotate {
+        format!("; entry: {:#x}\n{}", format.entry, program.to_annotated())
     } else {
-        println!("{}", program.to_string());
+        format!("; entry: {:#x}\n{}", format.entry, program.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_is_mains_address() {
+        let source = "(defun square $x { (return (* $x $x)) }) (defun main { (print (square 3)) })";
+        let compiled = risp::compile_source(source, "test.risp", 0, false, None, &[]).unwrap();
+        assert_ne!(compiled.entry, 0);
+
+        let encoded = FileFormat::new(compiled.program)
+            .with_entry(compiled.entry)
+            .encode()
+            .unwrap();
+        let text = render(FileFormat::decode(encoded).unwrap(), false, false).unwrap();
+        let expected = format!("; entry: {:#x}", compiled.entry);
+        assert_eq!(text.lines().next(), Some(expected.as_str()));
     }
 }
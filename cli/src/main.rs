@@ -2,8 +2,11 @@ use clap::{Parser, Subcommand};
 use run::RunArgs;
 use shared::program::ProgramParser;
 mod compile;
+mod debug;
 mod disassemble;
 mod run;
+mod snapshot;
+mod watch;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,6 +25,52 @@ enum Commands {
 
         #[arg(short = 'd', long)]
         dump: bool,
+
+        #[arg(long)]
+        dump_json: bool,
+
+        #[arg(long)]
+        show_asm: bool,
+
+        /// Print the program like --show-asm and stop there
+        #[arg(long, conflicts_with_all = ["max_instructions", "dump", "dump_json", "trace_file", "coverage", "stats"])]
+        no_run: bool,
+
+        /// Run again every time the file changes
+        #[arg(short = 'w', long)]
+        watch: bool,
+
+        /// -O1 folds constants and runs the peephole pass on .risp, off by default
+        #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=1))]
+        optimize: u8,
+
+        /// Only allow comparisons as if and while conditions in .risp
+        #[arg(long)]
+        strict: bool,
+
+        /// Start at this function in .risp instead of main
+        #[arg(long)]
+        entry: Option<String>,
+
+        /// Turns on (#if NAME { ... }) blocks in .risp, can be given more than once
+        #[arg(short = 'D', long = "define", value_name = "NAME")]
+        defines: Vec<String>,
+
+        /// Write every executed instruction to this file
+        #[arg(long)]
+        trace_file: Option<String>,
+
+        /// Afterwards list every instruction, marking the ones that never ran
+        #[arg(long)]
+        coverage: bool,
+
+        /// Afterwards print how many instructions ran, and of which operations
+        #[arg(long)]
+        stats: bool,
+
+        /// What add, sub, mult and div do when they overflow
+        #[arg(long, value_enum, default_value_t = run::Arithmetic::Wrapping)]
+        arithmetic: run::Arithmetic,
     },
     Compile {
         input_path: String,
@@ -29,17 +78,66 @@ enum Commands {
         #[arg(short = 'o', long)]
         output_path: Option<String>,
 
+        #[arg(short = 't', long, value_enum)]
+        target: Option<compile::Target>,
+
+        #[arg(long)]
+        tokens: bool,
+
         #[arg(long)]
         ast: bool,
 
         #[arg(long)]
         asm: bool,
+
+        /// Print the program's raw words
+        #[arg(long)]
+        dump_bytecode: bool,
+
+        /// -O1 folds constants and runs the peephole pass on .risp, off by default
+        #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=1))]
+        optimize: u8,
+
+        /// Only allow comparisons as if and while conditions in .risp
+        #[arg(long)]
+        strict: bool,
+
+        /// Start at this function in .risp instead of main
+        #[arg(long)]
+        entry: Option<String>,
+
+        /// Turns on (#if NAME { ... }) blocks in .risp, can be given more than once
+        #[arg(short = 'D', long = "define", value_name = "NAME")]
+        defines: Vec<String>,
+
+        /// Only report errors, without writing anything
+        #[arg(long, conflicts_with_all = ["output_path", "target", "tokens", "ast", "asm", "dump_bytecode"])]
+        check: bool,
     },
     Disassemble {
         input_path: String,
 
         #[arg(short = 'o', long)]
         output_path: Option<String>,
+
+        #[arg(long)]
+        json: bool,
+
+        /// Show each instruction's offset and raw words next to it
+        #[arg(long, conflicts_with = "json")]
+        annotate: bool,
+    },
+    Debug {
+        file: String,
+    },
+    /// Compares what every .risp in the directory compiles to with its .snap file
+    Snapshot {
+        #[arg(default_value = "test_files/snapshots")]
+        dir: String,
+
+        /// Write the .snap files instead of comparing
+        #[arg(long)]
+        bless: bool,
     },
 }
 
@@ -51,32 +149,162 @@ fn main() {
             file,
             max_instructions,
             dump,
+            dump_json,
+            show_asm,
+            no_run,
+            watch,
+            optimize,
+            strict,
+            entry,
+            defines,
+            trace_file,
+            coverage,
+            stats,
+            arithmetic,
         } => {
-            run::run(RunArgs {
+            let args = RunArgs {
                 filepath: file.to_string(),
                 max_instructions: *max_instructions,
                 dump: *dump,
-            });
+                dump_json: *dump_json,
+                show_asm: *show_asm,
+                no_run: *no_run,
+                optimize: *optimize,
+                strict: *strict,
+                entry: entry.clone(),
+                defines: defines.clone(),
+                trace_file: trace_file.clone(),
+                coverage: *coverage,
+                stats: *stats,
+                arithmetic: *arithmetic,
+            };
+
+            if *watch && file == run::STDIN {
+                eprintln!("Error: Can't watch stdin");
+                std::process::exit(1);
+            } else if *watch {
+                watch::watch(args);
+            } else {
+                match run::run(args) {
+                    Ok(code) => std::process::exit(code),
+                    Err(err) => {
+                        eprintln!("{}", error_line(&err));
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
         Commands::Compile {
             input_path,
             output_path,
+            target,
+            tokens,
             ast,
             asm,
+            dump_bytecode,
+            optimize,
+            strict,
+            entry,
+            defines,
+            check,
         } => {
-            compile::compile(compile::CompileArgs {
+            exit_on_error(compile::compile(compile::CompileArgs {
                 input_path: input_path.to_string(),
                 output_path: output_path.clone(),
+                target: *target,
+                tokens: *tokens,
                 ast: *ast,
                 asm: *asm,
-            });
+                dump_bytecode: *dump_bytecode,
+                optimize: *optimize,
+                strict: *strict,
+                entry: entry.clone(),
+                defines: defines.clone(),
+                check: *check,
+            }));
         }
         Commands::Disassemble {
             input_path,
             output_path,
-        } => disassemble::disassemble(disassemble::DisassembleArgs {
+            json,
+            annotate,
+        } => exit_on_error(disassemble::disassemble(disassemble::DisassembleArgs {
             input_path: input_path.to_string(),
             output_path: output_path.clone(),
-        }),
+            json: *json,
+            annotate: *annotate,
+        })),
+        Commands::Debug { file } => exit_on_error(debug::debug(debug::DebugArgs {
+            filepath: file.to_string(),
+        })),
+        Commands::Snapshot { dir, bless } => {
+            exit_on_error(snapshot::snapshot(snapshot::SnapshotArgs {
+                dir: dir.to_string(),
+                bless: *bless,
+            }))
+        }
+    }
+}
+
+// Errors are printed on one line instead of panicking with a backtrace
+fn exit_on_error(result: anyhow::Result<()>) {
+    if let Err(err) = result {
+        eprintln!("{}", error_line(&err));
+        std::process::exit(1);
+    }
+}
+
+// The error with its context, `{:#}` keeps it on one line
+fn error_line(err: &anyhow::Error) -> String {
+    format!("Error: {:#}", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The line main prints for a .risp file that fails to compile or run
+    fn error(name: &str, source: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}.risp", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+
+        let args = RunArgs {
+            filepath: path.display().to_string(),
+            max_instructions: None,
+            dump: false,
+            dump_json: false,
+            show_asm: false,
+            no_run: false,
+            optimize: 0,
+            strict: false,
+            entry: None,
+            defines: vec![],
+            trace_file: None,
+            coverage: false,
+            stats: false,
+            arithmetic: run::Arithmetic::Wrapping,
+        };
+        let result = run::run(args);
+        std::fs::remove_file(path).unwrap();
+        match result {
+            Ok(_) => panic!("ran {}", source),
+            Err(err) => error_line(&err),
+        }
+    }
+
+    #[test]
+    fn mainless_file_is_a_one_line_error() {
+        assert_eq!(
+            error("mainless", "(defun f { (return 1) })"),
+            "Error: main function not defined"
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_a_one_line_error() {
+        let line = error("div_zero", "(print (/ 7 0))");
+        assert!(line.starts_with("Error: Div by zero at pc="), "{}", line);
+        let line = error("mod_zero", "(defvar $zero 0) (print (% 7 $zero))");
+        assert!(line.starts_with("Error: Mod by zero at pc="), "{}", line);
     }
 }
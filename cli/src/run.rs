@@ -1,46 +1,534 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Read, Write},
+};
+
+use anyhow::{anyhow, Context, Result};
 use asm::assembler::Assembler;
-use risp::{codegen::CodeGen, parser::Parser};
-use shared::{lexer::Lexer, program::ProgramParser, token::Token};
+use clap::ValueEnum;
+use shared::{fileformat::FileFormat, lexer::Lexer, program::ProgramParser};
 use vm::vm::VM;
 
+// Same as the VM's, which doesn't know about clap
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Arithmetic {
+    Wrapping,
+    Checked,
+    Saturating,
+}
+
+impl From<Arithmetic> for vm::vm::Arithmetic {
+    fn from(arithmetic: Arithmetic) -> Self {
+        match arithmetic {
+            Arithmetic::Wrapping => Self::Wrapping,
+            Arithmetic::Checked => Self::Checked,
+            Arithmetic::Saturating => Self::Saturating,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct RunArgs {
     pub filepath: String,
     pub max_instructions: Option<usize>,
     pub dump: bool,
+    pub dump_json: bool,
+    pub show_asm: bool,
+    pub no_run: bool,
+    pub optimize: u8,
+    pub strict: bool,
+    pub entry: Option<String>,
+    pub defines: Vec<String>,
+    pub trace_file: Option<String>,
+    pub coverage: bool,
+    pub stats: bool,
+    pub arithmetic: Arithmetic,
+}
+
+// Passed instead of a file to read .risp source from stdin
+pub const STDIN: &str = "-";
+
+pub fn read_file(filepath: &str) -> Result<String> {
+    std::fs::read_to_string(filepath).with_context(|| format!("Couldn't read {}", filepath))
+}
+
+pub fn read_format(filepath: &str) -> Result<FileFormat> {
+    FileFormat::from_file(filepath.to_string())
+        .with_context(|| format!("Couldn't read {}", filepath))
+}
+
+// Returns the source and the filename to use in spans
+pub fn read_source(filepath: &str) -> Result<(String, String)> {
+    read_source_from(filepath, std::io::stdin())
 }
 
-pub fn run(args: RunArgs) {
+fn read_source_from(filepath: &str, mut stdin: impl Read) -> Result<(String, String)> {
+    if filepath == STDIN {
+        let mut source = String::new();
+        stdin.read_to_string(&mut source)?;
+        Ok((source, "<stdin>".to_string()))
+    } else {
+        Ok((read_file(filepath)?, filepath.to_string()))
+    }
+}
+
+pub fn is_risp(filepath: &str) -> bool {
+    filepath == STDIN || filepath.ends_with(".risp")
+}
+
+// Compiling still went through, so these go to stderr and don't stop anything
+pub fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+// Returns the program and its entry point from a .rasm, .risp or compiled file, or .risp from
+// stdin. `optimize`, `strict`, `entry` and `defines` only apply to .risp
+pub fn load(
+    filepath: &str,
+    optimize: u8,
+    strict: bool,
+    entry_function: Option<&str>,
+    defines: &[String],
+) -> Result<(Vec<usize>, usize)> {
     let program;
-    let mut entry = 0;
+    let entry;
 
-    if args.filepath.ends_with(".rasm") {
+    if filepath.ends_with(".rasm") {
         // Assembly
-        let lexer = Lexer::new_from_path(args.filepath.to_string());
-        let mut asm = Assembler::new(lexer).unwrap();
-        program = asm.assemble().unwrap();
-    } else if args.filepath.ends_with(".risp") {
+        let source = read_file(filepath)?;
+        let lexer = Lexer::new(&source, filepath.to_string());
+        let mut asm = Assembler::new(lexer)?;
+        program = asm.assemble()?;
+        entry = asm.entry();
+    } else if is_risp(filepath) {
         // Lisp
-        let lexer = Lexer::new_from_path(args.filepath.to_string());
-        let ast = Parser::parse(lexer).unwrap();
-        let bytecode = CodeGen::new().generate(ast).unwrap();
-        program = bytecode.0.clone();
-        entry = bytecode.1;
+        let (source, filename) = read_source(filepath)?;
+        let compiled = risp::compile_source(
+            &source,
+            &filename,
+            optimize,
+            strict,
+            entry_function,
+            defines,
+        )?;
+        print_warnings(&compiled.warnings);
+        program = compiled.program;
+        entry = compiled.entry;
     } else {
         // Bin
-        program = shared::fileformat::FileFormat::from_file(args.filepath)
-            .unwrap()
-            .program;
+        let format = read_format(filepath)?;
+        program = format.program;
+        entry = format.entry;
     }
 
-    let mut vm = VM::new(program, entry);
+    Ok((program, entry))
+}
 
-    if let Some(max) = args.max_instructions {
-        vm.run_max(max);
-    } else {
-        vm.run();
+// Every instruction with its offset, the ones that never ran marked with a `!`. Data embedded in
+// the program isn't counted
+fn coverage_report(program: &[usize], reached: &HashSet<usize>) -> Result<String> {
+    let program = ProgramParser::new(program.to_vec()).parse()?;
+    let instructions: Vec<_> = program.actions.iter().filter(|a| !a.data).collect();
+    let count = instructions
+        .iter()
+        .filter(|a| reached.contains(&a.offset))
+        .count();
+
+    let mut report = format!(
+        "; {} of {} instructions reached\n",
+        count,
+        instructions.len()
+    );
+    for action in instructions {
+        let mark = if reached.contains(&action.offset) {
+            ' '
+        } else {
+            '!'
+        };
+        report.push_str(&format!(
+            "{} {:#06x}: {}\n",
+            mark,
+            action.offset,
+            action.format()
+        ));
     }
+    Ok(report)
+}
+
+// How many instructions ran, then how many of each operation, most common first
+fn stats_report(vm: &VM) -> String {
+    let mut report = format!("; {} instructions executed\n", vm.instruction_count());
+    let mut histogram = vm.op_histogram().unwrap_or_default();
+    histogram.retain(|(_, count)| *count > 0);
+    histogram.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    for (operation, count) in histogram {
+        report.push_str(&format!(";   {:<12} {}\n", operation.to_asm(), count));
+    }
+    report
+}
 
+// Returns the exit code, what main returned or 0
+pub fn run(args: RunArgs) -> Result<i32> {
+    run_to(args, Box::new(std::io::stdout()))
+}
+
+// The listing from --show-asm and what the program prints go to `output`
+fn run_to(args: RunArgs, mut output: Box<dyn Write>) -> Result<i32> {
+    if args.entry.is_some() && !is_risp(&args.filepath) {
+        return Err(anyhow!(
+            "--entry only works with .risp, functions aren't named otherwise"
+        ));
+    }
+    let (program, entry) = load(
+        &args.filepath,
+        args.optimize,
+        args.strict,
+        args.entry.as_deref(),
+        &args.defines,
+    )?;
+
+    if args.show_asm || args.no_run {
+        // --show-asm prints the program before running it
+        let program = ProgramParser::new(program.clone()).parse()?;
+        writeln!(output, "{}", program.to_string())?;
+    }
+    if args.no_run {
+        return Ok(0);
+    }
+
+    let mut vm = VM::new(program, entry)
+        .with_output(output)
+        .with_arithmetic(args.arithmetic.into());
+    if args.coverage {
+        vm = vm.with_coverage();
+    }
+    if args.stats {
+        vm = vm.with_histogram();
+    }
+    if let Some(path) = &args.trace_file {
+        vm = vm.with_trace(Box::new(BufWriter::new(File::create(path)?)));
+    }
+
+    let result = match args.max_instructions {
+        Some(max) => vm.run_max(max),
+        None => vm.run(),
+    };
+
+    // Dump even if it failed, the state is most interesting then
     if args.dump {
-        vm.dump();
+        vm.dump()?;
+    }
+    if args.dump_json {
+        let json = vm.to_json();
+        writeln!(vm.output(), "{}", json)?;
+    }
+    if let Some(reached) = vm.coverage() {
+        let report = coverage_report(vm.program(), reached)?;
+        write!(vm.output(), "{}", report)?;
+    }
+    if args.stats {
+        let report = stats_report(&vm);
+        write!(vm.output(), "{}", report)?;
+    }
+    result.map(|_| vm.exit_code() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::testing::Output;
+
+    use super::*;
+
+    fn args(filepath: &str) -> RunArgs {
+        RunArgs {
+            filepath: filepath.to_string(),
+            max_instructions: None,
+            dump: false,
+            dump_json: false,
+            show_asm: false,
+            no_run: false,
+            optimize: 0,
+            strict: false,
+            entry: None,
+            defines: vec![],
+            trace_file: None,
+            coverage: false,
+            stats: false,
+            arithmetic: Arithmetic::Wrapping,
+        }
+    }
+
+    fn output(args: RunArgs) -> String {
+        let output = Output::default();
+        run_to(args, Box::new(output.clone())).unwrap();
+        output.text()
+    }
+
+    #[test]
+    fn only_program_output_without_show_asm() {
+        let path = std::env::temp_dir().join(format!("show_asm_{}.risp", std::process::id()));
+        std::fs::write(&path, "(print 42)").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert_eq!(output(args(path)), "42\n");
+        let text = output(RunArgs {
+            show_asm: true,
+            ..args(path)
+        });
+        assert!(
+            text.len() > "42\n".len() && text.ends_with("\n42\n"),
+            "{}",
+            text
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Files from before the header have no entry point, they start at the top
+    #[test]
+    fn legacy_files_run() {
+        let lexer = Lexer::new("push 42\ncall $print\nhalt", "test.rasm".to_string());
+        let program = Assembler::new(lexer).unwrap().assemble().unwrap();
+        let bytes = FileFormat::new(program)
+            .with_version(shared::fileformat::VERSION_LEGACY)
+            .encode()
+            .unwrap();
+        assert!(!bytes.starts_with(&shared::fileformat::MAGIC));
+
+        let path = std::env::temp_dir().join(format!("legacy_{}.bin", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(output(args(path)), "42\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Compiled in memory, so the tests using it can run at the same time
+    fn exit_code(source: &str) -> usize {
+        let compiled = risp::compile_source(source, "test.risp", 0, false, None, &[]).unwrap();
+        let mut vm =
+            VM::new(compiled.program, compiled.entry).with_output(Box::new(Output::default()));
+        vm.run().unwrap();
+        vm.exit_code()
+    }
+
+    #[test]
+    fn main_returns_the_exit_code() {
+        assert_eq!(exit_code("(defun main { (return 3) })"), 3);
+        assert_eq!(exit_code("(defun main { (exit 4) (return 3) })"), 4);
+        assert_eq!(exit_code("(print 1)"), 0);
+    }
+
+    #[test]
+    fn trace_file_gets_the_trace() {
+        let dir = std::env::temp_dir();
+        let source = dir.join(format!("trace_{}.rasm", std::process::id()));
+        let trace = dir.join(format!("trace_{}.log", std::process::id()));
+        std::fs::write(&source, "push 7\ncall $print\nhalt").unwrap();
+
+        let text = output(RunArgs {
+            trace_file: Some(trace.display().to_string()),
+            ..args(source.to_str().unwrap())
+        });
+        assert_eq!(text, "7\n");
+        let log = std::fs::read_to_string(&trace).unwrap();
+        assert_eq!(log.lines().count(), 3, "{}", log);
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(trace).unwrap();
+    }
+
+    #[test]
+    fn exit_with_a_code() {
+        assert_eq!(exit_code("(exit 2) (print 1)"), 2);
+        assert_eq!(
+            exit_code("(defun f { (exit 6) (return 0) }) (f) (exit 1)"),
+            6
+        );
+        assert_eq!(exit_code("(exit 0)"), 0);
+    }
+
+    #[test]
+    fn source_from_stdin() {
+        let stdin = "(print (* 6 7))\n(print $nope)";
+        let (source, filename) = read_source_from(STDIN, stdin.as_bytes()).unwrap();
+        assert_eq!(source, stdin);
+        assert_eq!(filename, "<stdin>");
+
+        let Err(error) = risp::compile_source(&source, &filename, 0, false, None, &[]) else {
+            panic!("compiled an unknown variable");
+        };
+        assert!(error.to_string().ends_with("at <stdin>:2:9"), "{}", error);
+
+        let (source, filename) = read_source_from(STDIN, "(print (* 6 7))".as_bytes()).unwrap();
+        let compiled = risp::compile_source(&source, &filename, 0, false, None, &[]).unwrap();
+        let output = Output::default();
+        VM::new(compiled.program, compiled.entry)
+            .with_output(Box::new(output.clone()))
+            .run()
+            .unwrap();
+        assert_eq!(output.text(), "42\n");
+    }
+
+    // The push 2 after the jump is never reached, everything else is
+    #[test]
+    fn coverage_marks_the_untaken_branch() {
+        let source = "push 1\njmp_if .skip\npush 2\n.skip:\npush 3\nhalt";
+        let lexer = Lexer::new(source, "test.rasm".to_string());
+        let program = Assembler::new(lexer).unwrap().assemble().unwrap();
+        let mut vm = VM::new(program, 0)
+            .with_output(Box::new(Output::default()))
+            .with_coverage();
+        vm.run().unwrap();
+
+        let report = coverage_report(vm.program(), vm.coverage().unwrap()).unwrap();
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some("; 4 of 5 instructions reached"));
+        let unreached: Vec<_> = lines.filter(|line| line.starts_with('!')).collect();
+        assert_eq!(unreached.len(), 1, "{}", report);
+        assert!(unreached[0].ends_with("push 2"), "{}", report);
+    }
+
+    #[test]
+    fn corrupt_bin_is_an_error() {
+        let path = std::env::temp_dir().join(format!("run_corrupt_{}.bin", std::process::id()));
+        let encoded = FileFormat::new(vec![usize::MAX]).encode().unwrap();
+        std::fs::write(&path, encoded).unwrap();
+        let path = path.to_str().unwrap();
+
+        let Err(error) = run_to(args(path), Box::new(Output::default())) else {
+            panic!("ran a corrupt program");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Invalid opcode 0xffffffffffffffff at pc=0"
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // The top level is left out when starting somewhere else
+    #[test]
+    fn entry_runs_another_function() {
+        let path = std::env::temp_dir().join(format!("entry_{}.risp", std::process::id()));
+        std::fs::write(
+            &path,
+            "(defun fib $n { (if (< $n 2) { (return $n) }) (return (+ (fib (- $n 1)) (fib (- $n 2)))) })
+            (defun fib_ten { (print (fib 10)) (return 7) })
+            (defun main { (print 1) (return 0) })",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let with_entry = |entry: &str| RunArgs {
+            entry: Some(entry.to_string()),
+            ..args(path)
+        };
+        assert_eq!(output(with_entry("fib_ten")), "55\n");
+        assert_eq!(output(args(path)), "1\n");
+        let code = run_to(with_entry("fib_ten"), Box::new(Output::default())).unwrap();
+        assert_eq!(code, 7);
+
+        for (entry, message) in [
+            (
+                "fib",
+                "entry function `fib` takes 1 arguments, it can't take any",
+            ),
+            ("nope", "entry function `nope` not defined"),
+        ] {
+            let Err(error) = run_to(with_entry(entry), Box::new(Output::default())) else {
+                panic!("ran {}", entry);
+            };
+            assert_eq!(error.to_string(), message);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn json_dump_goes_to_the_output() {
+        let path = std::env::temp_dir().join(format!("dump_json_{}.rasm", std::process::id()));
+        std::fs::write(&path, "push 7\ncall $print\nhalt").unwrap();
+
+        let text = output(RunArgs {
+            dump_json: true,
+            ..args(path.to_str().unwrap())
+        });
+        assert_eq!(
+            text,
+            "7\n{\"pc\":5,\"stack\":[7,0],\"registers\":[0,0,0,0,0,0,0,0,0,0],\"call_stack\":[],\"memory\":[]}\n"
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn coverage_goes_to_the_output() {
+        let path = std::env::temp_dir().join(format!("coverage_{}.rasm", std::process::id()));
+        std::fs::write(
+            &path,
+            "push 0\njmp_if .end\npush 7\ncall $print\n.end:\nhalt",
+        )
+        .unwrap();
+
+        let text = output(RunArgs {
+            coverage: true,
+            ..args(path.to_str().unwrap())
+        });
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("7"));
+        assert_eq!(lines.next(), Some("; 5 of 5 instructions reached"));
+        assert_eq!(lines.count(), 5, "{}", text);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Three times round the loop, then the print and halt
+    #[test]
+    fn stats_go_to_the_output() {
+        let path = std::env::temp_dir().join(format!("stats_{}.rasm", std::process::id()));
+        let source = "push 3\n.loop:\npush 1\nsub\ndup s(0)\njmp_if .loop\ncall $print\nhalt";
+        std::fs::write(&path, source).unwrap();
+
+        let text = output(RunArgs {
+            stats: true,
+            ..args(path.to_str().unwrap())
+        });
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("0"));
+        assert_eq!(lines.next(), Some("; 15 instructions executed"));
+        assert_eq!(lines.next(), Some(";   push         4"));
+        assert_eq!(lines.count(), 5, "{}", text);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Only the listing, the program's own print and the dump never happen
+    #[test]
+    fn no_run_only_prints_the_program() {
+        let path = std::env::temp_dir().join(format!("no_run_{}.risp", std::process::id()));
+        std::fs::write(&path, "(print 42) (exit 3)").unwrap();
+        let path = path.to_str().unwrap();
+
+        let no_run = || RunArgs {
+            no_run: true,
+            dump: true,
+            ..args(path)
+        };
+        let text = output(no_run());
+        assert!(text.contains("call $print"), "{}", text);
+        assert!(!text.lines().any(|line| line == "42"), "{}", text);
+        assert!(!text.contains("Stack:"), "{}", text);
+        // Running, the dump goes to the same output
+        let text = output(RunArgs {
+            dump: true,
+            ..args(path)
+        });
+        assert!(text.starts_with("42\nStack:\n"), "{}", text);
+        assert_eq!(run_to(no_run(), Box::new(Output::default())).unwrap(), 0);
+
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,123 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use shared::fileformat::FileFormat;
+
+use crate::{disassemble, run::read_file};
+
+pub struct SnapshotArgs {
+    pub dir: String,
+    pub bless: bool,
+}
+
+// Compiles every .risp in the directory, unoptimized, and compares the program with the .snap
+// file next to it so that changes to codegen don't go unnoticed. With bless the .snap files are
+// written instead, for when the change was meant
+pub fn snapshot(args: SnapshotArgs) -> Result<()> {
+    let mut sources: Vec<_> = fs::read_dir(&args.dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    sources.retain(|path| path.extension().is_some_and(|ext| ext == "risp"));
+    sources.sort();
+
+    let mut failed = 0;
+    for source in &sources {
+        let snap = source.with_extension("snap");
+        let rendered = render(source)?;
+
+        if args.bless {
+            fs::write(&snap, &rendered)?;
+            println!("blessed {}", snap.display());
+            continue;
+        }
+
+        match fs::read_to_string(&snap) {
+            Ok(expected) if expected == rendered => println!("ok {}", source.display()),
+            Ok(expected) => {
+                failed += 1;
+                println!("FAILED {}", source.display());
+                print_difference(&expected, &rendered);
+            }
+            Err(_) => {
+                failed += 1;
+                println!("FAILED {}, {} is missing", source.display(), snap.display());
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} snapshots differ, run with --bless if that's intended",
+            failed,
+            sources.len()
+        ));
+    }
+    Ok(())
+}
+
+fn render(source: &Path) -> Result<String> {
+    let filename = source.display().to_string();
+    let compiled = risp::compile_source(&read_file(&filename)?, &filename, 0, false, None, &[])?;
+    let format = FileFormat::new(compiled.program).with_entry(compiled.entry);
+    disassemble::render(format, false, false)
+}
+
+// Only the first line that differs, the rest usually differs because of it
+fn print_difference(expected: &str, got: &str) {
+    let mut expected_lines = expected.lines();
+    let mut got_lines = got.lines();
+    for line in 1.. {
+        match (expected_lines.next(), got_lines.next()) {
+            (None, None) => return,
+            (expected, got) if expected == got => {}
+            (expected, got) => {
+                println!("  line {}", line);
+                println!("  expected: {}", expected.unwrap_or("<end>"));
+                println!("       got: {}", got.unwrap_or("<end>"));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(dir: &Path, bless: bool) -> SnapshotArgs {
+        SnapshotArgs {
+            dir: dir.display().to_string(),
+            bless,
+        }
+    }
+
+    // The goldens in test_files, codegen changes fail here until they're blessed
+    #[test]
+    fn checked_in_snapshots_match() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test_files/snapshots");
+        snapshot(args(&dir, false)).unwrap();
+    }
+
+    #[test]
+    fn differences_fail_until_blessed() {
+        let dir = std::env::temp_dir().join(format!("snapshots_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("add.risp"), "(print (+ 1 2))").unwrap();
+
+        // Missing, then different
+        assert!(snapshot(args(&dir, false)).is_err());
+        fs::write(dir.join("add.snap"), "; entry: 0x0\n").unwrap();
+        let err = snapshot(args(&dir, false)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 of 1 snapshots differ, run with --bless if that's intended"
+        );
+
+        snapshot(args(&dir, true)).unwrap();
+        snapshot(args(&dir, false)).unwrap();
+        fs::write(dir.join("add.risp"), "(print (+ 1 3))").unwrap();
+        assert!(snapshot(args(&dir, false)).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
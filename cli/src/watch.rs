@@ -0,0 +1,33 @@
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::run::{self, RunArgs};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Runs the file every time its modification time changes. Errors are printed and the watcher
+// keeps going, so do panics, which the default hook prints
+pub fn watch(args: RunArgs) {
+    let mut last_modified: Option<SystemTime> = None;
+
+    loop {
+        let modified = fs::metadata(&args.filepath).and_then(|m| m.modified()).ok();
+
+        if modified.is_some() && modified != last_modified {
+            if last_modified.is_some() {
+                println!("---- {} changed ----", args.filepath);
+            }
+            last_modified = modified;
+
+            if let Ok(Err(err)) = panic::catch_unwind(AssertUnwindSafe(|| run::run(args.clone()))) {
+                eprintln!("Error: {:#}", err);
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -1,17 +1,21 @@
-use shared::token::TokenType;
+use shared::token::{TokenSpan, TokenType};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AST {
     Root(Block), // Only for proc definitions and import
     Block(Block),
 
     NumberLiteral(NumberLiteral),
+    StringLiteral(StringLiteral),
 
     VariableDefinition(VariableDefinition),
     VariableSet(VariableDefinition),
     Variable(Identifier),
+    ArrayLiteral(ArrayLiteral),
+    Index(Index),
 
     FunctionDefinition(FunctionDefinition),
+    MacroDefinition(MacroDefinition),
 
     Call(Call),
 
@@ -21,9 +25,12 @@ pub enum AST {
     If(If),
     FromTo(FromTo),
     While(While),
+    Switch(Switch),
+
+    Conditional(Conditional), // removed before codegen, see `conditional::strip`
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Block {
     pub statements: Vec<Box<AST>>,
 }
@@ -34,63 +41,121 @@ impl Block {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Identifier {
     pub name: String,
+    pub span: TokenSpan,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Call {
     pub id: Identifier,
     pub args: Vec<Box<AST>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NumberLiteral {
     pub value: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct StringLiteral {
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArrayLiteral {
+    pub elements: Vec<Box<AST>>,
+    pub span: TokenSpan, // the opening bracket
+}
+
+// ($array index)
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub id: Identifier,
+    pub index: Box<AST>,
+}
+
+#[derive(Debug, Clone)]
 pub struct VariableDefinition {
     pub id: Identifier,
     pub value: Box<AST>,
+    pub constant: bool, // defined with defconst, can't be set afterwards
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionDefinition {
     pub id: Identifier,
     pub variables: Vec<Identifier>,
     pub block: Block,
 }
 
-#[derive(Debug)]
+// Expanded away before codegen, see macros
+#[derive(Debug, Clone)]
+pub struct MacroDefinition {
+    pub id: Identifier,
+    pub variables: Vec<Identifier>,
+    pub template: Box<AST>, // an expression or a block
+}
+
+#[derive(Debug, Clone)]
 pub struct BinOp {
     pub lhs: Box<AST>,
     pub rhs: Box<AST>,
     pub op: TokenType, // TODO: Make this its own thing
+    pub span: TokenSpan,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Return {
     pub value: Box<AST>,
+    pub span: TokenSpan,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct If {
     pub cond: Box<AST>,
     pub then: Block,
     pub r#else: Option<Block>,
+    pub span: TokenSpan, // the if keyword
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FromTo {
+    pub id: Option<Identifier>, // name bound to the counter
     pub start: Box<AST>,
     pub finish: Box<AST>,
+    pub step: Option<Box<AST>>, // defaults to 1
     pub block: Block,
+    pub span: TokenSpan, // the from keyword
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct While {
     pub cond: Box<AST>,
     pub then: Block,
+    pub span: TokenSpan, // the while keyword
+}
+
+#[derive(Debug, Clone)]
+pub struct Switch {
+    pub value: Box<AST>,
+    pub cases: Vec<Case>,
+    pub default: Option<Block>, // runs when no case matches
+    pub span: TokenSpan,        // the switch keyword
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub value: usize,
+    pub block: Block,
+    pub span: TokenSpan, // the case keyword
+}
+
+// (#if SYMBOL { ... }), the block is kept when the symbol is defined
+#[derive(Debug, Clone)]
+pub struct Conditional {
+    pub symbol: Identifier,
+    pub block: Block,
+    pub span: TokenSpan, // the #
 }
@@ -1,18 +1,22 @@
-// FIXME: Arguments stack position are incorrect
-
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use shared::{
+    error::CompileError,
     instruction::{NativeFunctions, OpCode, Operation, Variant},
     program::Operand,
-    token::TokenType,
+    token::{TokenSpan, TokenType},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
 };
-use std::collections::HashMap;
 
 use crate::{
     ast::{
-        BinOp, Block, Call, FromTo, FunctionDefinition, If, Return, VariableDefinition, While, AST,
+        ArrayLiteral, BinOp, Block, Call, FromTo, FunctionDefinition, If, Index, NumberLiteral,
+        Return, Switch, VariableDefinition, While, AST,
     },
-    variable_stack::VariableStack,
+    conditional, macros, optimize, validate,
+    variable_stack::{ValueType, Var, VariableStack},
 };
 macro_rules! variants {
     () => {
@@ -38,19 +42,60 @@ macro_rules! op {
     };
 }
 
-#[derive(Debug)]
+// Hidden variables used by from-to loops, `@` can't be part of an identifier
+const FROM_TO_COUNTER: &str = "@counter";
+const FROM_TO_FINISH: &str = "@finish";
+const FROM_TO_STEP: &str = "@step";
+
+#[derive(Debug, Clone)]
 struct UnresolvedFunction {
     pub name: String,
     pub location: usize,
+    pub span: TokenSpan,
+}
+
+// Checked against the function's parameters once every function is known, natives right away
+#[derive(Debug, Clone)]
+struct CallSite {
+    pub name: String,
+    pub args: usize,
+    pub span: TokenSpan,
+}
+
+impl CallSite {
+    fn check_arguments(self, expected: usize) -> Result<()> {
+        if self.args != expected {
+            return Err(CompileError::ArgumentCount {
+                name: self.name,
+                expected,
+                got: self.args,
+                span: self.span,
+            }
+            .into());
+        }
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub struct CodeGen {
     program: Vec<usize>,
     variable_stack: VariableStack,
     functions: HashMap<String, usize>,
+    arities: HashMap<String, usize>, // number of parameters of every function
     stack_size: usize,
+    frame_base: usize, // stack size when the current function started, arguments not included
+    strings: HashMap<String, usize>, // address of the data for every string literal
+    addresses: Vec<usize>, // words holding code addresses, moved along by the optimizer
+
+    optimize: u8, // 0 leaves codegen's output as is, 1 folds constants and runs the peephole pass
+    strict: bool, // conditions have to be comparisons
+    entry: Option<String>, // function to start at instead of main
+    defines: HashSet<String>, // symbols that turn on #if blocks
 
     unresolved_function: Vec<UnresolvedFunction>,
+    calls: Vec<CallSite>,
+    warnings: Vec<String>, // for the caller to show, nothing is printed here
 }
 
 impl CodeGen {
@@ -59,11 +104,49 @@ impl CodeGen {
             program: vec![],
             variable_stack: VariableStack::new(),
             functions: HashMap::new(),
+            arities: HashMap::new(),
             stack_size: 0,
+            frame_base: 0,
+            strings: HashMap::new(),
+            addresses: vec![],
+            optimize: 0,
+            strict: false,
+            entry: None,
+            defines: HashSet::new(),
             unresolved_function: vec![],
+            calls: vec![],
+            warnings: vec![],
         }
     }
 
+    pub fn with_optimize(mut self, level: u8) -> Self {
+        self.optimize = level;
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // Starts at this function instead of main, it can't take any arguments. Whatever is at the
+    // top level is left out
+    pub fn with_entry(mut self, entry: Option<String>) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn with_defines(mut self, defines: HashSet<String>) -> Self {
+        self.defines = defines;
+        self
+    }
+
+    // The warnings since the last time they were taken, like unreachable code. Compiling still
+    // succeeds with them
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
     fn stack_push(&mut self, variant: Variant, value: usize) -> usize {
         self.program
             .push(OpCode::new(Operation::Push, [variant, Variant::None, Variant::None]).as_usize());
@@ -74,82 +157,311 @@ impl CodeGen {
         return self.stack_size - 1;
     }
 
+    // Operands holding string literals are addresses into the program
+    fn push_operand(&mut self, operand: &Operand) {
+        self.stack_push(operand.variant, operand.value);
+        if operand.string.is_some() {
+            self.addresses.push(self.program.len() - 1);
+        }
+    }
+
+    // Emits a word that is a code address, jump targets and the like
+    fn push_address(&mut self, addr: usize) {
+        self.addresses.push(self.program.len());
+        self.program.push(addr);
+    }
+
     fn stack_pop(&mut self) {
         self.program.push(op!(Pop));
         self.variable_stack.decrement_relative();
         self.stack_size -= 1;
     }
 
+    // Pops `count` values without tracking it, the caller has already accounted for them
+    fn emit_pops(&mut self, count: usize) {
+        match count {
+            0 => {}
+            1 => self.program.push(op!(Pop)),
+            _ => {
+                self.program.push(op!(PopN, Direct));
+                self.program.push(count);
+            }
+        }
+    }
+
     // Pop but without popping
     fn stack_lower(&mut self) {
         self.variable_stack.decrement_relative();
         self.stack_size -= 1;
     }
 
+    // Emits a conditional jump to be patched later and returns the address of its target. The
+    // jump pops the condition so it's no longer tracked on the stack
+    fn cond_jmp(&mut self, operation: Operation) -> usize {
+        self.program.push(
+            OpCode::new(operation, [Variant::Direct, Variant::None, Variant::None]).as_usize(),
+        );
+        self.push_address(0);
+        self.stack_lower();
+        self.program.len() - 1
+    }
+
     // push but without pushing
     fn stack_increce(&mut self) {
         self.variable_stack.increment_relative();
         self.stack_size += 1;
     }
 
-    pub fn generate(&mut self, ast: AST) -> Result<(Vec<usize>, usize)> {
+    pub fn generate(&mut self, mut ast: AST) -> Result<(Vec<usize>, usize)> {
+        conditional::strip(&mut ast, &self.defines);
+        macros::expand(&mut ast)?;
+        validate::validate(&ast, self.strict)?;
+        if self.optimize >= 1 {
+            optimize::fold(&mut ast);
+        }
         self.variable_stack.enter();
-        match ast {
-            AST::Root(block) => {
-                self.generate_block(&block)?;
+        let block = match ast {
+            AST::Root(block) => block,
+            other => return Err(anyhow!("Root must be root, is currently {:?}", other)),
+        };
+
+        // Top-level statements that aren't functions make up an implicit main, generated after
+        // all functions so that they can't see its variables
+        let (functions, statements): (Vec<&Box<AST>>, Vec<&Box<AST>>) = block
+            .statements
+            .iter()
+            .partition(|stmt| matches!(***stmt, AST::FunctionDefinition(_)));
+
+        self.variable_stack.enter();
+        for func in functions {
+            if let AST::FunctionDefinition(func) = &**func {
+                self.generate_function(func)?;
             }
+        }
+
+        let implicit_main = if statements.is_empty() {
+            None
+        } else {
+            Some(self.program.len())
+        };
+
+        self.frame_base = self.stack_size;
+        for stmt in statements {
+            self.generate_statement(stmt)?;
+        }
+        if implicit_main.is_some() {
+            self.program.push(op!(Halt));
+        }
+        self.variable_stack.leave()?;
+
+        self.resolve_calls()?;
+
+        let entry = match (&self.entry, implicit_main, self.functions.get("main")) {
+            (Some(name), _, _) => match (self.functions.get(name), self.arities.get(name)) {
+                (Some(entry), Some(0)) => *entry,
+                (Some(_), Some(args)) => {
+                    return Err(CompileError::EntryTakesArguments {
+                        name: name.clone(),
+                        args: *args,
+                    }
+                    .into())
+                }
+                _ => return Err(CompileError::UnknownEntry { name: name.clone() }.into()),
+            },
+            (None, Some(_), Some(_)) => return Err(CompileError::MainWithTopLevelStatements.into()),
+            (None, Some(entry), None) => entry,
+            (None, None, Some(entry)) => *entry,
+            (None, None, None) => return Err(CompileError::MissingMain.into()),
+        };
+        self.variable_stack.enter();
+
+        if self.optimize == 0 {
+            return Ok((self.program.clone(), entry));
+        }
+        Ok(optimize::optimize(
+            self.program.clone(),
+            entry,
+            &self.addresses,
+        ))
+    }
+
+    // For a REPL, where the program comes in chunks that build on the ones before. Each chunk is
+    // a root like the one `generate` takes and is appended to the program. Its top-level
+    // variables, and whatever its statements leave on the stack, are there for the next chunk,
+    // so the VM's stack has to be kept between them too. There's no main or entry, the returned
+    // range is the new code and the chunk runs from its start to its end, jumping over the
+    // functions in it. Running every range in order is the same as running the program from 0.
+    // Macros only apply to the chunk defining them and the peephole pass is skipped since it
+    // moves code around. A chunk that fails leaves the CodeGen as it was before it
+    pub fn generate_incremental(&mut self, ast: AST) -> Result<Range<usize>> {
+        let before = self.clone();
+        let range = self.generate_chunk(ast);
+        if range.is_err() {
+            *self = before;
+        }
+        range
+    }
+
+    // Everything generated so far, the ranges from generate_incremental index into it
+    pub fn program(&self) -> &[usize] {
+        &self.program
+    }
+
+    fn generate_chunk(&mut self, mut ast: AST) -> Result<Range<usize>> {
+        conditional::strip(&mut ast, &self.defines);
+        macros::expand(&mut ast)?;
+        validate::validate(&ast, self.strict)?;
+        if self.optimize >= 1 {
+            optimize::fold(&mut ast);
+        }
+        let block = match ast {
+            AST::Root(block) => block,
             other => return Err(anyhow!("Root must be root, is currently {:?}", other)),
+        };
+
+        // The scope of the top-level variables, it's never left
+        if self.variable_stack.is_empty() {
+            self.variable_stack.enter();
+        }
+
+        let start = self.program.len();
+        let (functions, statements): (Vec<&Box<AST>>, Vec<&Box<AST>>) = block
+            .statements
+            .iter()
+            .partition(|stmt| matches!(***stmt, AST::FunctionDefinition(_)));
+
+        if !functions.is_empty() {
+            self.program.push(op!(Jmp, Direct));
+            let over = self.program.len();
+            self.push_address(0);
+
+            // The top level continues where the last chunk left it once the functions are done
+            let (stack_size, frame_base) = (self.stack_size, self.frame_base);
+            for func in functions {
+                if let AST::FunctionDefinition(func) = &**func {
+                    self.generate_function(func)?;
+                }
+            }
+            self.program[over] = self.program.len();
+            self.stack_size = stack_size;
+            self.frame_base = frame_base;
         }
 
-        for func in &self.unresolved_function {
+        for stmt in statements {
+            self.generate_statement(stmt)?;
+        }
+
+        // Functions from later chunks can't be called yet, so everything has to resolve now
+        self.resolve_calls()?;
+        Ok(start..self.program.len())
+    }
+
+    // Patches in the address of every function called before its definition and checks that
+    // every call has as many arguments as the function has parameters
+    fn resolve_calls(&mut self) -> Result<()> {
+        for func in std::mem::take(&mut self.unresolved_function) {
             if let Some(addr) = self.functions.get(&func.name) {
                 self.program[func.location] = *addr;
             } else {
-                return Err(anyhow!("Unknown function {}", func.name));
+                return Err(CompileError::UnknownFunction {
+                    name: func.name,
+                    span: func.span,
+                }
+                .into());
             }
         }
 
-        let entry = self
-            .functions
-            .get("main")
-            .with_context(|| anyhow!("main function not defined"))?;
-        self.variable_stack.enter();
-
-        Ok((self.program.clone(), *entry))
+        for call in std::mem::take(&mut self.calls) {
+            let expected = self.arities[&call.name];
+            call.check_arguments(expected)?;
+        }
+        Ok(())
     }
 
     pub fn generate_call(&mut self, call: &Call) -> Result<()> {
-        // Push all args onto stack
+        let native = NativeFunctions::from_string(&call.id.name);
+
+        // exit's code is the only argument that can be left out
+        if let Some(func) = native {
+            if !(call.args.is_empty() && matches!(func, NativeFunctions::Exit)) {
+                CallSite {
+                    name: call.id.name.clone(),
+                    args: call.args.len(),
+                    span: call.id.span.clone(),
+                }
+                .check_arguments(func.arguments())?;
+            }
+        }
+
+        // Push all args onto stack, computed ones already are
         for arg in &call.args {
             let value = self.generate_statement(&(*arg))?;
-            let value = value
-                .with_context(|| anyhow!("Function call arguments must evaluate to a value"))?;
-            self.stack_push(value.variant, value.value);
+            let value = value.ok_or_else(|| CompileError::ExpectedValue {
+                what: "Function call arguments",
+                span: call.id.span.clone(),
+            })?;
+            self.push_if_not_last_on_stack(arg, value);
+        }
+        let mut args = call.args.len();
+
+        // exit takes its code from the stack, without one it exits with 0
+        if args == 0 && matches!(native, Some(NativeFunctions::Exit)) {
+            self.stack_push(Variant::Direct, 0);
+            args = 1;
         }
 
-        if let Some(func) = NativeFunctions::from_string(&call.id.name) {
+        if let Some(func) = native {
             self.program.push(op!(Call, Native));
             self.program.push(func as usize);
         } else {
             self.program.push(op!(Call, Direct));
+            self.calls.push(CallSite {
+                name: call.id.name.clone(),
+                args: call.args.len(),
+                span: call.id.span.clone(),
+            });
 
             if let Some(v) = self.functions.get(&call.id.name) {
-                self.program.push(*v);
+                self.push_address(*v);
             } else {
+                // Defined further down, the address is patched in once every function is
+                // known. The location is the word after the opcode, the one pushed next
                 self.unresolved_function.push(UnresolvedFunction {
                     name: call.id.name.clone(),
                     location: self.program.len(),
+                    span: call.id.span.clone(),
                 });
-                self.program.push(0);
+                self.push_address(0);
             }
         }
         // Silently push value from return
         self.stack_increce();
 
-        // Pop all args
-        for _ in &call.args {
-            self.program.push(op!(Swap));
-            self.stack_pop();
+        if !native.is_none_or(|func| func.returns_value()) {
+            // Nothing after it runs, so nothing is emitted to clean up. It's still tracked as a
+            // value in place of the args so that the code after it lines up
+            for _ in 0..args {
+                self.stack_lower();
+            }
+            return Ok(());
+        }
+
+        // Pop all args, the returned value takes the place of the first one
+        match args {
+            0 => {}
+            1 => {
+                self.program.push(op!(Swap));
+                self.stack_pop();
+            }
+            args => {
+                self.program.push(op!(Mov, Stack, Stack));
+                self.program.push(args);
+                self.program.push(0);
+                self.emit_pops(args);
+                for _ in 0..args {
+                    self.stack_lower();
+                }
+            }
         }
 
         Ok(())
@@ -158,8 +470,11 @@ impl CodeGen {
     pub fn has_call(&self, ast: &AST) -> bool {
         match ast {
             AST::NumberLiteral(_) => false,
+            AST::StringLiteral(_) => false,
+            AST::ArrayLiteral(array) => array.elements.iter().any(|e| self.has_call(e)),
+            AST::Index(index) => self.has_call(&index.index),
             AST::Call(_) => true,
-            AST::FunctionDefinition(_) => false,
+            AST::FunctionDefinition(_) | AST::MacroDefinition(_) | AST::Conditional(_) => false,
             AST::VariableDefinition(var) => self.has_call(&var.value),
             AST::VariableSet(var) => self.has_call(&var.value),
             AST::Variable(_) => false,
@@ -167,7 +482,15 @@ impl CodeGen {
             AST::Return(ret) => self.has_call(&ret.value),
             AST::If(ef) => self.has_call(&ef.cond),
             AST::While(wile) => self.has_call(&wile.cond),
-            other => todo!("Implement {:?}", other),
+            AST::Switch(switch) => self.has_call(&switch.value),
+            AST::FromTo(ft) => {
+                self.has_call(&ft.start)
+                    || self.has_call(&ft.finish)
+                    || ft.step.as_ref().is_some_and(|step| self.has_call(step))
+            }
+            AST::Root(block) | AST::Block(block) => {
+                block.statements.iter().any(|stmt| self.has_call(stmt))
+            }
         }
     }
 
@@ -178,18 +501,26 @@ impl CodeGen {
                 // self.stack_push(Variant::Direct, num.value);
                 return Ok(Some(Operand::new(num.value, Variant::Direct)));
             }
+            AST::StringLiteral(string) => {
+                let addr = self.generate_string(&string.value);
+                return Ok(Some(Operand::string(addr, string.value.clone())));
+            }
             AST::Call(call) => {
                 self.generate_call(call)?;
                 return Ok(Some(Operand::new(0, Variant::Stack)));
             }
-            AST::FunctionDefinition(func) => self.generate_function(func)?,
+            AST::FunctionDefinition(func) => {
+                // Functions are only generated from the root, anything else is nested
+                return Err(CompileError::NestedFunction {
+                    name: func.id.name.clone(),
+                    span: func.id.span.clone(),
+                }
+                .into());
+            }
             AST::VariableDefinition(var) => self.generate_variable_definition(var)?,
             AST::VariableSet(var) => self.generate_set_variable(var)?,
             AST::Variable(var) => {
-                let v = self
-                    .variable_stack
-                    .get(var.name.clone())
-                    .with_context(|| format!("Unknown variable {}", var.name))?;
+                let v = self.get_variable(&var.name, &var.span)?;
                 // FiXME: This should be pushed
                 return Ok(Some(Operand::new(v.location, v.variant)));
             }
@@ -197,73 +528,224 @@ impl CodeGen {
                 self.generate_binop(binop)?;
                 return Ok(Some(Operand::new(0, Variant::Stack)));
             }
+            AST::ArrayLiteral(array) => {
+                self.generate_array_literal(array)?;
+                return Ok(Some(Operand::new(0, Variant::Stack)));
+            }
+            AST::Index(index) => {
+                self.generate_index(index)?;
+                return Ok(Some(Operand::new(0, Variant::Stack)));
+            }
             AST::Return(ret) => self.generate_return(ret)?,
             AST::If(ef) => self.generate_if(ef)?,
             AST::While(wile) => self.generate_while(wile)?,
+            AST::Switch(switch) => self.generate_switch(switch)?,
             AST::FromTo(ft) => self.generate_from_to(ft)?,
-            other => todo!("Implement {:?}", other),
+            // Caught by validate, but codegen shouldn't rely on it to not panic
+            AST::Root(_) => {
+                return Err(CompileError::UnsupportedNode {
+                    what: "A nested root",
+                }
+                .into())
+            }
+            AST::MacroDefinition(_) => {
+                return Err(CompileError::UnsupportedNode {
+                    what: "A macro definition",
+                }
+                .into())
+            }
+            AST::Conditional(conditional) => {
+                return Err(CompileError::MisplacedConditional {
+                    span: conditional.span.clone(),
+                }
+                .into())
+            }
         }
 
         Ok(None)
     }
 
+    // Strings are stored inline as their length followed by one word per character, with a jump
+    // over them. Equal literals share the same data
+    fn generate_string(&mut self, value: &str) -> usize {
+        if let Some(addr) = self.strings.get(value) {
+            return *addr;
+        }
+
+        let chars: Vec<usize> = value.chars().map(|c| c as usize).collect();
+        let addr = self.program.len() + 2;
+        self.program.push(op!(Jmp, Direct));
+        self.push_address(addr + 1 + chars.len());
+        self.program.push(chars.len());
+        self.program.extend(chars);
+
+        self.strings.insert(value.to_string(), addr);
+        addr
+    }
+
+    // Only what's known at compile time, everything that isn't a string literal, a variable
+    // holding one or concat is treated as a number
+    fn value_type(&mut self, ast: &AST) -> ValueType {
+        match ast {
+            AST::StringLiteral(_) => ValueType::String,
+            AST::Call(call)
+                if matches!(
+                    NativeFunctions::from_string(&call.id.name),
+                    Some(NativeFunctions::Concat)
+                ) =>
+            {
+                ValueType::String
+            }
+            AST::Variable(var) => match self.variable_stack.get(var.name.clone()) {
+                Some(v) => v.value_type,
+                None => ValueType::Number,
+            },
+            _ => ValueType::Number,
+        }
+    }
+
     pub fn generate_block(&mut self, block: &Block) -> Result<()> {
+        // Locals and values left by statements are popped at the end, so that the stack is the
+        // same whether or not the block ran
+        let stack_size = self.stack_size;
         self.variable_stack.enter();
-        for stmt in &block.statements {
+        for (i, stmt) in block.statements.iter().enumerate() {
+            if let AST::Return(ret) = &**stmt {
+                if i + 1 < block.statements.len() {
+                    // Still generated, it just never runs
+                    self.warnings
+                        .push(format!("Unreachable code after return, at {}", ret.span));
+                }
+            }
             self.generate_statement(&(*stmt))?;
         }
-        self.variable_stack.leave()?;
+        self.emit_pops(self.stack_size.saturating_sub(stack_size));
+        self.stack_size = self.stack_size.min(stack_size);
+        self.variable_stack.leave()?; // untracks what was just popped
 
         Ok(())
     }
 
     pub fn generate_function(&mut self, definition: &FunctionDefinition) -> Result<()> {
-        // self.variable_stack.enter();
-        // TODO: Validate that the function isnt already defined
+        if self.functions.contains_key(&definition.id.name) {
+            return Err(CompileError::FunctionAlreadyDefined {
+                name: definition.id.name.clone(),
+                span: definition.id.span.clone(),
+            }
+            .into());
+        }
+
         self.functions
             .insert(definition.id.name.clone(), self.program.len());
+        self.arities
+            .insert(definition.id.name.clone(), definition.variables.len());
 
+        // Arguments are pushed in order, so the last one is on top
+        let args = definition.variables.len();
+        self.variable_stack.enter_frame(args);
         for (i, var) in definition.variables.iter().enumerate() {
-            self.variable_stack
-                .create(var.name.clone(), i, Variant::Stack)?;
+            if self.variable_stack.is_in_current_scope(&var.name) {
+                return Err(CompileError::VariableAlreadyDefined {
+                    name: var.name.clone(),
+                    span: var.span.clone(),
+                }
+                .into());
+            }
+            self.variable_stack.create(
+                var.name.clone(),
+                args - 1 - i,
+                Variant::Stack,
+                ValueType::Number,
+            )?;
         }
+        self.frame_base = self.stack_size;
 
         self.generate_block(&definition.block)?;
-        // self.variable_stack.leave()?;
+        if definition.id.name == "main" {
+            // Nothing to return to, so stop instead of running into the next function
+            self.program.push(op!(Halt));
+        } else if !matches!(
+            definition.block.statements.last().map(|stmt| &**stmt),
+            Some(AST::Return(_))
+        ) {
+            // Falling off the end returns 0 instead of running into the next function
+            self.generate_return(&Return {
+                value: Box::new(AST::NumberLiteral(NumberLiteral { value: 0 })),
+                span: definition.id.span.clone(),
+            })?;
+        }
+        self.variable_stack.leave_frame()?;
         Ok(())
     }
 
     pub fn generate_variable_definition(&mut self, definition: &VariableDefinition) -> Result<()> {
-        let value = self.generate_statement(&(*definition.value))?;
-        let value = value.with_context(|| anyhow!("Variable definition must be a value"))?;
+        if self.variable_stack.is_in_current_scope(&definition.id.name) {
+            return Err(CompileError::VariableAlreadyDefined {
+                name: definition.id.name.clone(),
+                span: definition.id.span.clone(),
+            }
+            .into());
+        }
 
-        self.stack_push(value.variant, value.value);
-        self.variable_stack.create(
-            definition.id.name.clone(),
-            // self.stack_size,
-            // Variant::StackAbsoulute,
-            0,
-            Variant::Stack,
-        )?;
+        let value_type = self.value_type(&definition.value);
+        let value = self.generate_statement(&(*definition.value))?;
+        let value = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "Variable definition",
+            span: definition.id.span.clone(),
+        })?;
+
+        self.push_operand(&value);
+        let name = definition.id.name.clone();
+        if definition.constant {
+            self.variable_stack
+                .create_constant(name, 0, Variant::Stack, value_type)?;
+        } else {
+            self.variable_stack
+                .create(name, 0, Variant::Stack, value_type)?;
+        }
         Ok(())
     }
 
     pub fn generate_set_variable(&mut self, definition: &VariableDefinition) -> Result<()> {
+        match self.variable_stack.get(definition.id.name.clone()) {
+            None => {
+                return Err(CompileError::SetUndefinedVariable {
+                    name: definition.id.name.clone(),
+                    span: definition.id.span.clone(),
+                }
+                .into())
+            }
+            Some(var) if !var.mutable => {
+                return Err(CompileError::SetConstant {
+                    name: definition.id.name.clone(),
+                    span: definition.id.span.clone(),
+                }
+                .into())
+            }
+            Some(_) => {}
+        }
+
         let value = self.generate_statement(&(*definition.value))?;
-        let value = value.with_context(|| anyhow!("Set Variable must be a value"))?;
+        let value = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "Set Variable",
+            span: definition.id.span.clone(),
+        })?;
 
         // This must be after since the stack might change durring statement generation of the
         // value
         let variable = self
             .variable_stack
             .get(definition.id.name.clone())
-            .with_context(|| anyhow!("Unknown variable {}", definition.id.name))?;
+            .ok_or_else(|| CompileError::UnknownVariable {
+                name: definition.id.name.clone(),
+                span: definition.id.span.clone(),
+            })?;
 
         self.program.push(
             OpCode::new(
                 Operation::Mov,
                 [Variant::Stack, value.variant, Variant::None],
-                // [Variant::StackAbsoulute, value.variant, Variant::None],
+                // [Variant::StackAbsolute, value.variant, Variant::None],
             )
             .as_usize(),
         );
@@ -273,9 +755,18 @@ impl CodeGen {
         //     self.program.push(self.stack_size - value.value - 1);
         // } else {
         self.program.push(value.value);
+        if value.string.is_some() {
+            self.addresses.push(self.program.len() - 1);
+        }
         // }
 
-        self.stack_pop(); // remove value from
+        // Only computed values were pushed, literals and variables are moved from where they are
+        let computed = !matches!(&*definition.value, AST::Variable(_))
+            && value.variant == Variant::Stack
+            && value.value == 0;
+        if computed {
+            self.stack_pop();
+        }
 
         Ok(())
     }
@@ -284,30 +775,57 @@ impl CodeGen {
         // FIXME: This is just a test
         match ast {
             AST::Variable(_) => {
-                self.stack_push(operand.variant, operand.value);
+                self.push_operand(&operand);
             }
             _ => {
                 if operand.variant == Variant::Stack && operand.value == 0 {
                     return;
                 }
-                self.stack_push(operand.variant, operand.value);
+                self.push_operand(&operand);
             }
         }
     }
 
     pub fn generate_binop(&mut self, binop: &BinOp) -> Result<()> {
+        // Strings can only be compared for equality with other strings
+        let strings = match (self.value_type(&binop.lhs), self.value_type(&binop.rhs)) {
+            (ValueType::Number, ValueType::Number) => false,
+            (ValueType::String, ValueType::String)
+                if matches!(binop.op, TokenType::Equal | TokenType::NotEqual) =>
+            {
+                true
+            }
+            _ => {
+                return Err(CompileError::InvalidStringOperation {
+                    op: binop.op,
+                    span: binop.span.clone(),
+                }
+                .into())
+            }
+        };
+
+        // The lhs has to be on the stack before the rhs is generated. Otherwise a computed rhs ends
+        // up below it, and stack offsets read for the rhs are off by the lhs push
         let value = self.generate_statement(&(*binop.lhs))?;
-        let lhs = value.with_context(|| anyhow!("LHS must evaluate to a value"))?;
+        let lhs = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "LHS",
+            span: binop.span.clone(),
+        })?;
+        self.push_if_not_last_on_stack(&binop.lhs, lhs);
 
         let value = self.generate_statement(&(*binop.rhs))?;
-        let rhs = value.with_context(|| anyhow!("RHS must evaluate to a value"))?;
-
-        // self.stack_push(lhs.variant, lhs.value);
-        // self.stack_push(rhs.variant, rhs.value);
-        self.push_if_not_last_on_stack(&binop.lhs, lhs);
+        let rhs = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "RHS",
+            span: binop.span.clone(),
+        })?;
         self.push_if_not_last_on_stack(&binop.rhs, rhs);
 
         match binop.op {
+            TokenType::Equal if strings => self.program.push(op!(CmpStrEq)),
+            TokenType::NotEqual if strings => {
+                self.program.push(op!(CmpStrEq));
+                self.program.push(op!(Not));
+            }
             TokenType::Plus => self.program.push(op!(Add)),
             TokenType::Dash => self.program.push(op!(Sub)),
             TokenType::Times => self.program.push(op!(Mult)),
@@ -316,7 +834,16 @@ impl CodeGen {
             TokenType::Equal => self.program.push(op!(CmpEq)),
             TokenType::LessThan => self.program.push(op!(CmpLt)),
             TokenType::GreaterThan => self.program.push(op!(CmpGt)),
-            other => return Err(anyhow!("{:?} isn't a valid binary operation", other)),
+            TokenType::NotEqual => self.program.push(op!(CmpNe)),
+            TokenType::LessThanEqual => self.program.push(op!(CmpLte)),
+            TokenType::GreaterThanEqual => self.program.push(op!(CmpGte)),
+            other => {
+                return Err(CompileError::InvalidBinOp {
+                    op: other,
+                    span: binop.span.clone(),
+                }
+                .into())
+            }
         }
 
         self.stack_lower(); // all binops removes one from the stack
@@ -329,114 +856,975 @@ impl CodeGen {
         Ok(())
     }
 
+    // Allocates the array and stores every element, leaving its address on the stack
+    pub fn generate_array_literal(&mut self, array: &ArrayLiteral) -> Result<()> {
+        self.stack_push(Variant::Direct, array.elements.len());
+        self.program.push(op!(Alloc)); // replaces the length with the address
+
+        for (i, element) in array.elements.iter().enumerate() {
+            self.stack_push(Variant::Stack, 0);
+            self.stack_push(Variant::Direct, i);
+
+            let value = self.generate_statement(element)?;
+            let value = value.ok_or_else(|| CompileError::ExpectedValue {
+                what: "Array elements",
+                span: array.span.clone(),
+            })?;
+            self.push_if_not_last_on_stack(element, value);
+
+            self.program.push(op!(Store));
+            for _ in 0..3 {
+                self.stack_lower();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn generate_index(&mut self, index: &Index) -> Result<()> {
+        self.push_variable(&index.id.name, &index.id.span)?;
+
+        let value = self.generate_statement(&index.index)?;
+        let value = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "Index",
+            span: index.id.span.clone(),
+        })?;
+        self.push_if_not_last_on_stack(&index.index, value);
+
+        self.program.push(op!(Load));
+        self.stack_lower(); // the address and index are replaced by the element
+        Ok(())
+    }
+
     pub fn generate_return(&mut self, ret: &Return) -> Result<()> {
         let value = self.generate_statement(&(*ret.value))?;
-        let value = value.with_context(|| anyhow!("return must evaluate to a value"))?;
+        let value = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "return",
+            span: ret.span.clone(),
+        })?;
         // self.stack_push(value.variant, value.value);
         self.push_if_not_last_on_stack(&ret.value, value);
+
+        // Locals would be left for the caller, so the value takes the place of the deepest one
+        // and everything above it is popped
+        let locals = self.stack_size.saturating_sub(self.frame_base + 1);
+        if locals > 0 {
+            self.program.push(op!(Mov, Stack, Stack));
+            self.program.push(locals);
+            self.program.push(0);
+            self.emit_pops(locals);
+        }
         self.program.push(op!(Ret));
+
+        // Only the path that returned loses its locals, code after the return still has them
+        self.stack_lower(); // the value went with the return
         Ok(())
     }
 
+    // Both branches are blocks, which scope their own variables and pop them at the end
     pub fn generate_if(&mut self, ef: &If) -> Result<()> {
-        // self.variable_stack.enter();
         let value = self.generate_statement(&(*ef.cond))?;
-        let cond = value.with_context(|| anyhow!("condition must evaluate to a value"))?;
+        let cond = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "condition",
+            span: ef.span.clone(),
+        })?;
         self.push_if_not_last_on_stack(&ef.cond, cond);
         // self.stack_push(cond.variant, cond.value);
 
-        self.program.push(op!(Not));
-        self.program.push(op!(JmpIf, Direct));
-        self.program.push(0);
-        self.stack_lower(); // jmp removed condition;
-        let jmp_to_else_addr = self.program.len() - 1;
+        let jmp_to_else_addr = self.cond_jmp(Operation::JmpIfNot);
 
         self.generate_block(&ef.then)?;
-        self.program.push(op!(Jmp, Direct));
-        self.program.push(10);
-        let jmp_to_end_addr = self.program.len() - 1;
 
-        self.program[jmp_to_else_addr] = self.program.len();
         if let Some(else_block) = &ef.r#else {
+            // then has to jump past the else block
+            self.program.push(op!(Jmp, Direct));
+            self.push_address(0);
+            let jmp_to_end_addr = self.program.len() - 1;
+
+            self.program[jmp_to_else_addr] = self.program.len();
             self.generate_block(else_block)?;
+            self.program[jmp_to_end_addr] = self.program.len();
+        } else {
+            self.program[jmp_to_else_addr] = self.program.len();
+        }
+        Ok(())
+    }
+
+    // The value stays on the stack while every case compares a copy of it, in order. The first
+    // one that matches runs and jumps to the end, nothing falls through to the next case
+    pub fn generate_switch(&mut self, switch: &Switch) -> Result<()> {
+        let mut values = HashSet::new();
+        for case in &switch.cases {
+            if !values.insert(case.value) {
+                return Err(CompileError::DuplicateCase {
+                    value: case.value,
+                    span: case.span.clone(),
+                }
+                .into());
+            }
+        }
+        if self.value_type(&switch.value) == ValueType::String {
+            return Err(CompileError::SwitchOnString {
+                span: switch.span.clone(),
+            }
+            .into());
+        }
+
+        let value = self.generate_statement(&switch.value)?;
+        let value = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "switch value",
+            span: switch.span.clone(),
+        })?;
+        self.push_if_not_last_on_stack(&switch.value, value);
+
+        let mut jmps_to_end = vec![];
+        for (i, case) in switch.cases.iter().enumerate() {
+            self.stack_push(Variant::Stack, 0);
+            self.stack_push(Variant::Direct, case.value);
+            self.program.push(op!(CmpEq));
+            self.stack_lower();
+            let jmp_to_next_addr = self.cond_jmp(Operation::JmpIfNot);
+
+            self.generate_block(&case.block)?;
+
+            // The last case only has the end after it
+            if i + 1 < switch.cases.len() || switch.default.is_some() {
+                self.program.push(op!(Jmp, Direct));
+                self.push_address(0);
+                jmps_to_end.push(self.program.len() - 1);
+            }
+            self.program[jmp_to_next_addr] = self.program.len();
         }
 
-        self.program[jmp_to_end_addr] = self.program.len() - 1;
-        // self.variable_stack.leave()?;
+        if let Some(block) = &switch.default {
+            self.generate_block(block)?;
+        }
+        for addr in jmps_to_end {
+            self.program[addr] = self.program.len();
+        }
+
+        self.stack_pop();
         Ok(())
     }
 
     pub fn generate_from_to(&mut self, ft: &FromTo) -> Result<()> {
-        // self.variable_stack.enter();
+        // The counter, finish and step live on the stack for the duration of the loop. They are
+        // tracked as variables with names that can't be written in source so that their
+        // positions follow along with everything that gets pushed in the block.
+        self.variable_stack.enter();
+
         let value = self.generate_statement(&(*ft.start))?;
-        let start = value.with_context(|| anyhow!("start must evaluate to a value"))?;
+        let start = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "start",
+            span: ft.span.clone(),
+        })?;
+        self.push_if_not_last_on_stack(&ft.start, start);
+        self.variable_stack.create(
+            FROM_TO_COUNTER.to_string(),
+            0,
+            Variant::Stack,
+            ValueType::Number,
+        )?;
+        if let Some(id) = &ft.id {
+            // Lives in the loop scope, so it goes away together with the counter
+            self.variable_stack
+                .create(id.name.clone(), 0, Variant::Stack, ValueType::Number)?;
+        }
 
         let value = self.generate_statement(&(*ft.finish))?;
-        let finish = value.with_context(|| anyhow!("finish must evaluate to a value"))?;
+        let finish = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "finish",
+            span: ft.span.clone(),
+        })?;
+        self.push_if_not_last_on_stack(&ft.finish, finish);
+        self.variable_stack.create(
+            FROM_TO_FINISH.to_string(),
+            0,
+            Variant::Stack,
+            ValueType::Number,
+        )?;
+
+        // A literal step decides the direction at compile time, anything else is checked when
+        // the loop runs
+        let mut descending = None;
+        match &ft.step {
+            Some(step) => {
+                if let AST::NumberLiteral(num) = &**step {
+                    if num.value == 0 {
+                        return Err(CompileError::ZeroStep {
+                            span: ft.span.clone(),
+                        }
+                        .into());
+                    }
+                    descending = Some((num.value as isize) < 0);
+                }
+
+                let value = self.generate_statement(step)?;
+                let step_value = value.ok_or_else(|| CompileError::ExpectedValue {
+                    what: "step",
+                    span: ft.span.clone(),
+                })?;
+                self.push_if_not_last_on_stack(step, step_value);
+            }
+            None => {
+                descending = Some(false);
+                self.stack_push(Variant::Direct, 1);
+            }
+        }
+        self.variable_stack.create(
+            FROM_TO_STEP.to_string(),
+            0,
+            Variant::Stack,
+            ValueType::Number,
+        )?;
 
-        // push start
-        let var = self.stack_push(start.variant, start.value); // current var
+        // A step that turns out to be 0 would never get anywhere, so it stops the program with
+        // an assertion failure before the loop starts
+        if descending.is_none() {
+            self.push_variable(FROM_TO_STEP, &ft.span)?;
+            self.stack_push(Variant::Direct, 0);
+            self.program.push(op!(CmpNe));
+            self.stack_lower();
+            self.program.push(op!(Call, Native));
+            self.program.push(NativeFunctions::Assert as usize);
+            // The condition and what assert returned
+            self.emit_pops(2);
+            self.stack_lower();
+        }
 
         let loop_start = self.program.len();
-        // push current and finish
-        self.stack_push(Variant::StackAbsoulute, var);
-        self.stack_push(finish.variant, finish.value);
-
-        //cmp
-        self.program.push(op!(CmpLt));
-        self.program.push(op!(Not));
-        self.program.push(op!(JmpIf, Direct));
-        self.program.push(0);
-        let end_addr = self.program.len() - 1;
+        match descending {
+            Some(descending) => self.generate_from_to_cmp(ft, descending)?,
+            None => {
+                self.push_variable(FROM_TO_STEP, &ft.span)?;
+                self.stack_push(Variant::Direct, 0);
+                self.program.push(op!(CmpLt));
+                self.stack_lower();
+                let jmp_to_descending_addr = self.cond_jmp(Operation::JmpIf);
+
+                self.generate_from_to_cmp(ft, false)?;
+                self.program.push(op!(Jmp, Direct));
+                self.push_address(0);
+                let jmp_to_check_addr = self.program.len() - 1;
+                self.stack_lower(); // only one of the comparisons ends up on the stack
+
+                self.program[jmp_to_descending_addr] = self.program.len();
+                self.generate_from_to_cmp(ft, true)?;
+                self.program[jmp_to_check_addr] = self.program.len();
+            }
+        }
+
+        let end_addr = self.cond_jmp(Operation::JmpIfNot);
 
         // Generate action
         self.generate_block(&ft.block)?;
 
-        // add
-        self.stack_push(Variant::StackAbsoulute, var);
-        self.stack_push(Variant::Direct, 1);
+        // counter += step
+        self.push_variable(FROM_TO_COUNTER, &ft.span)?;
+        self.push_variable(FROM_TO_STEP, &ft.span)?;
         self.program.push(op!(Add));
+        self.stack_lower();
 
-        self.program.push(op!(Mov, StackAbsoulute, Stack));
-        self.program.push(var);
+        let counter = self.get_variable(FROM_TO_COUNTER, &ft.span)?;
+        self.program.push(op!(Mov, Stack, Stack));
+        self.program.push(counter.location);
         self.program.push(0);
-        self.stack_pop(); // cmp
-        self.stack_pop(); // negated
+        self.stack_pop();
 
         // Jump back
         self.program.push(op!(Jmp, Direct));
-        self.program.push(loop_start);
+        self.push_address(loop_start);
 
         self.program[end_addr] = self.program.len();
-        self.stack_pop(); // current
-        self.stack_pop(); // start
 
-        // self.variable_stack.leave()?;
+        // Leaving the scope untracks the counter, finish and step, they only have to be popped
+        self.emit_pops(3);
+        self.stack_size -= 3;
+        self.variable_stack.leave()?;
+        Ok(())
+    }
+
+    // Compares the counter against finish, leaving whether the loop should continue on the stack
+    fn generate_from_to_cmp(&mut self, ft: &FromTo, descending: bool) -> Result<()> {
+        self.push_variable(FROM_TO_COUNTER, &ft.span)?;
+        self.push_variable(FROM_TO_FINISH, &ft.span)?;
+        if descending {
+            self.program.push(op!(CmpGt));
+        } else {
+            self.program.push(op!(CmpLt));
+        }
+        self.stack_lower();
         Ok(())
     }
 
+    // `span` is what the error points at if it isn't defined. The assembler uses `$name` for
+    // natives, so that's most likely what an undefined `$print` meant
+    fn get_variable(&mut self, name: &str, span: &TokenSpan) -> Result<Var> {
+        if let Some(var) = self.variable_stack.get(name.to_string()) {
+            return Ok(var);
+        }
+        if NativeFunctions::from_string(name).is_some() {
+            return Err(CompileError::NativeAsVariable {
+                name: name.to_string(),
+                span: span.clone(),
+            }
+            .into());
+        }
+        Err(CompileError::UnknownVariable {
+            name: name.to_string(),
+            span: span.clone(),
+        }
+        .into())
+    }
+
+    fn push_variable(&mut self, name: &str, span: &TokenSpan) -> Result<()> {
+        let var = self.get_variable(name, span)?;
+        self.stack_push(var.variant, var.location);
+        Ok(())
+    }
+
+    // Like `generate_if` the block takes care of its own variables, so every iteration starts
+    // with the same stack
     pub fn generate_while(&mut self, wile: &While) -> Result<()> {
-        // self.variable_stack.enter();
         let start_addr = self.program.len();
 
         let value = self.generate_statement(&(*wile.cond))?;
-        let cond = value.with_context(|| anyhow!("condition must evaluate to a value"))?;
+        let cond = value.ok_or_else(|| CompileError::ExpectedValue {
+            what: "condition",
+            span: wile.span.clone(),
+        })?;
         self.push_if_not_last_on_stack(&wile.cond, cond);
         // self.stack_push(cond.variant, cond.value);
 
-        self.program.push(op!(Not));
-        self.program.push(op!(JmpIf, Direct));
-        self.stack_lower(); // jmp removed condition;
-        self.program.push(0);
-        let jmp_to_end_addr = self.program.len() - 1;
+        let jmp_to_end_addr = self.cond_jmp(Operation::JmpIfNot);
 
         self.generate_block(&wile.then)?;
         self.program.push(op!(Jmp, Direct));
-        self.program.push(start_addr);
+        self.push_address(start_addr);
 
         self.program[jmp_to_end_addr] = self.program.len();
-
-        // self.variable_stack.leave()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::{
+        error::CompileError, instruction::Operation, lexer::Lexer, program::ProgramParser,
+    };
+
+    use super::*;
+    use crate::{
+        parser::Parser,
+        testing::{compile, compile_error, output},
+    };
+
+    fn operations(source: &str) -> Vec<Operation> {
+        let program = compile(source).unwrap().program;
+        let program = ProgramParser::new(program).parse().unwrap();
+        program.actions.iter().map(|a| a.operation).collect()
+    }
+
+    #[test]
+    fn top_level_statements_are_an_implicit_main() {
+        assert_eq!(output("(print 42)").unwrap(), "42\n");
+        let source = "(defun double $n { (return (* $n 2)) }) (print (double 21))";
+        assert_eq!(output(source).unwrap(), "42\n");
+    }
+
+    #[test]
+    fn main_and_top_level_statements_conflict() {
+        let source = "(print 1) (defun main { (print 2) })";
+        assert_eq!(
+            compile_error(source),
+            CompileError::MainWithTopLevelStatements
+        );
+    }
+
+    #[test]
+    fn nothing_to_run() {
+        let source = "(defun helper { (return 1) })";
+        assert_eq!(compile_error(source), CompileError::MissingMain);
+    }
+
+    #[test]
+    fn duplicate_functions_point_at_the_second() {
+        let source = "(defun foo { (return 1) })\n(defun foo { (return 2) })\n(print (foo))";
+        match compile_error(source) {
+            CompileError::FunctionAlreadyDefined { name, span } => {
+                assert_eq!(name, "foo");
+                assert_eq!(span.start_line, 2);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn comparisons_in_if() {
+        let source = "
+            (if (!= 1 2) { (print 1) })
+            (if (!= 2 2) { (print 0) })
+            (if (>= 2 2) { (print 2) })
+            (if (>= 1 2) { (print 0) })
+            (if (<= 2 2) { (print 3) })
+            (if (<= 3 2) { (print 0) })";
+        assert_eq!(output(source).unwrap(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn math_natives() {
+        let source = "
+            (print (pow 2 10))
+            (print (max 3 7))
+            (print (min 3 7))
+            (print (abs (- 0 5)))
+            (print (min (- 0 1) 1))";
+        assert_eq!(output(source).unwrap(), "1024\n7\n3\n5\n-1\n");
+    }
+
+    #[test]
+    fn if_with_and_without_else() {
+        let source = "
+            (if (= 1 1) { (print 1) } else { (print 2) })
+            (if (= 1 2) { (print 3) } else { (print 4) })
+            (if (= 1 1) { (print 5) })
+            (if (= 1 2) { (print 6) })
+            (print 7)";
+        assert_eq!(output(source).unwrap(), "1\n4\n5\n7\n");
+    }
+
+    #[test]
+    fn if_as_the_last_statement() {
+        let source = "
+            (defun check $n {
+              (if (> $n 0) { (print 1) } else { (print 2) })
+            })
+            (defun main {
+              (check 1)
+              (check 0)
+              (if (= 1 1) { (print 3) })
+            })";
+        assert_eq!(output(source).unwrap(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn unknown_variable_and_function() {
+        assert!(matches!(
+            compile_error("(print $nope)"),
+            CompileError::UnknownVariable { name, .. } if name == "nope"
+        ));
+        assert!(matches!(
+            compile_error("(print (nope 1))"),
+            CompileError::UnknownFunction { name, .. } if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn setvar_on_an_undefined_variable() {
+        let source = "(defvar $x 1) (setvar $y 2)";
+        match compile_error(source) {
+            CompileError::SetUndefinedVariable { name, .. } => assert_eq!(name, "y"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn defvar_redefining_in_the_same_block() {
+        let source = "(defvar $x 1) (defvar $x 2)";
+        match compile_error(source) {
+            CompileError::VariableAlreadyDefined { name, .. } => assert_eq!(name, "x"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_functions_are_rejected() {
+        let source =
+            "(defun outer { (defun inner { (return 1) }) (return (inner)) }) (print (outer))";
+        match compile_error(source) {
+            CompileError::NestedFunction { name, .. } => assert_eq!(name, "inner"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_to_with_a_step() {
+        let source = "(from $i 0 to 6 by 2 { (print $i) })";
+        assert_eq!(output(source).unwrap(), "0\n2\n4\n");
+        let source = "(from $i 3 to 0 by (- 0 1) { (print $i) })";
+        assert_eq!(output(source).unwrap(), "3\n2\n1\n");
+    }
+
+    #[test]
+    fn from_to_zero_step() {
+        let source = "(from $i 0 to 3 by 0 { (print $i) })";
+        assert!(matches!(
+            compile_error(source),
+            CompileError::ZeroStep { .. }
+        ));
+
+        // Only known once it runs
+        let source = "(defvar $s 0) (from $i 0 to 3 by $s { (print $i) })";
+        let error = output(source).unwrap_err().to_string();
+        assert!(error.starts_with("assertion failed at pc="), "{}", error);
+        let source = "(defvar $s 2) (from $i 0 to 3 by $s { (print $i) })";
+        assert_eq!(output(source).unwrap(), "0\n2\n");
+    }
+
+    #[test]
+    fn from_to_counter_is_a_variable() {
+        let source = "(from $i 0 to 5 { (print $i) })";
+        assert_eq!(output(source).unwrap(), "0\n1\n2\n3\n4\n");
+        // Gone again after the loop
+        let source = "(from $i 0 to 2 { (print $i) }) (print $i)";
+        assert!(matches!(
+            compile_error(source),
+            CompileError::UnknownVariable { .. }
+        ));
+    }
+
+    #[test]
+    fn string_equality() {
+        let source = r#"
+            (defvar $a "hi")
+            (if (= $a "hi") { (print 1) })
+            (if (= "hi" "ho") { (print 2) })
+            (if (= "hi" "hio") { (print 3) })
+            (if (!= "hi" "hio") { (print 4) })
+            (if (!= "hi" "hi") { (print 5) })"#;
+        assert_eq!(output(source).unwrap(), "1\n4\n");
+    }
+
+    #[test]
+    fn array_elements() {
+        let source = "(defvar $a [1 2 3]) (print ($a 0)) (print ($a 1)) (print ($a 2))";
+        assert_eq!(output(source).unwrap(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn array_index_out_of_bounds() {
+        let source = "(defvar $a [1 2 3]) (print ($a 3))";
+        let error = output(source).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Index 3 out of bounds for length 3"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn conditions_jump_without_not() {
+        let source = "
+            (defvar $i 0)
+            (while (< $i 3) { (setvar $i (+ $i 1)) })
+            (if (= $i 3) { (print $i) })
+            (from $j 0 to 2 { (print $j) })";
+        assert_eq!(output(source).unwrap(), "3\n0\n1\n");
+
+        let operations = operations(source);
+        assert!(!operations
+            .iter()
+            .any(|o| matches!(o, Operation::Not | Operation::JmpIf)));
+        let count = operations
+            .iter()
+            .filter(|o| matches!(o, Operation::JmpIfNot))
+            .count();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn if_leaves_the_stack_as_it_was() {
+        let depth = |statement: &str| {
+            let source = format!(
+                "(defvar $a (stack_depth)) {} (defvar $b (stack_depth)) (print (- $b $a))",
+                statement
+            );
+            output(&source).unwrap()
+        };
+        let untouched = depth("");
+        assert_eq!(depth("(if (= 1 2) { (defvar $x 1) })"), untouched);
+        assert_eq!(depth("(if (= 1 1) { (defvar $x 1) })"), untouched);
+        assert_eq!(
+            depth("(if (= 1 2) { (defvar $x 1) } else { (defvar $y 2) })"),
+            untouched
+        );
+    }
+
+    #[test]
+    fn native_arity_is_checked() {
+        let arity = |source: &str| match compile_error(source) {
+            CompileError::ArgumentCount {
+                name,
+                expected,
+                got,
+                ..
+            } => (name, expected, got),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            arity("(defvar $a 7) (print (pow 2))"),
+            ("pow".to_string(), 2, 1)
+        );
+        assert_eq!(arity("(print (max 3))"), ("max".to_string(), 2, 1));
+        assert_eq!(arity("(print 1 2)"), ("print".to_string(), 1, 2));
+        assert_eq!(arity("(print)"), ("print".to_string(), 1, 0));
+        assert_eq!(arity("(exit 1 2)"), ("exit".to_string(), 1, 2));
+        assert_eq!(output("(print (pow 2 3)) (exit)").unwrap(), "8\n");
+    }
+
+    #[test]
+    fn call_arity_is_checked() {
+        // Called before it's defined, so checked once everything is generated
+        let arity = |call: &str| {
+            let source = format!(
+                "(print {}) (defun add $a $b {{ (return (+ $a $b)) }})",
+                call
+            );
+            match compile_error(&source) {
+                CompileError::ArgumentCount {
+                    name,
+                    expected,
+                    got,
+                    ..
+                } => (name, expected, got),
+                other => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(arity("(add 1)"), ("add".to_string(), 2, 1));
+        assert_eq!(arity("(add 1 2 3)"), ("add".to_string(), 2, 3));
+
+        let source = "(print (add 1 2)) (defun add $a $b { (return (+ $a $b)) })";
+        assert_eq!(output(source).unwrap(), "3\n");
+    }
+
+    #[test]
+    fn early_return_unwinds_locals() {
+        let pick = "
+            (defun pick $n {
+                (defvar $a 10)
+                (defvar $b 20)
+                (if (< $n 0) {
+                    (defvar $c 30)
+                    (return (+ $a $c))
+                })
+                (return (+ $b $n))
+            })";
+        // The calls have to leave the stack the same as printing the numbers directly
+        let depth = |prints: &str| {
+            let source = format!(
+                "{} (defvar $before (stack_depth)) {} (defvar $after (stack_depth)) (print (- $after $before))",
+                pick, prints
+            );
+            output(&source).unwrap()
+        };
+        let expected = depth("(print 40) (print 21)");
+        assert_eq!(depth("(print (pick (- 0 1))) (print (pick 1))"), expected);
+        assert!(expected.starts_with("40\n21\n"));
+    }
+
+    #[test]
+    fn unreachable_code_after_return_warns() {
+        let source = "(defun f {\n  (return 1)\n  (print 2)\n})\n(print (f))";
+        let compiled = compile(source).unwrap();
+        assert_eq!(
+            compiled.warnings,
+            ["Unreachable code after return, at test.risp:2:4"]
+        );
+        assert!(compile("(defun f { (return 1) }) (print (f))")
+            .unwrap()
+            .warnings
+            .is_empty());
+    }
+
+    #[test]
+    fn char_literals_are_numbers() {
+        assert_eq!(
+            output("(print (+ 'a' 1)) (print '\\n')").unwrap(),
+            "98\n10\n"
+        );
+    }
+
+    #[test]
+    fn assert_native() {
+        let source = "
+            (defun fib $n { (if (< $n 2) { (return $n) }) (return (+ (fib (- $n 1)) (fib (- $n 2)))) })
+            (assert (= (fib 10) 55))
+            (print 1)";
+        assert_eq!(output(source).unwrap(), "1\n");
+
+        let error = output("(print 1) (assert (= 1 2)) (print 2)").unwrap_err();
+        assert!(
+            error.to_string().starts_with("assertion failed at pc="),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn block_variables_stay_in_their_block() {
+        for block in [
+            "(if (= 1 1) { (defvar $x 1) })",
+            "(if (= 1 2) { } else { (defvar $x 1) })",
+            "(while (= 1 2) { (defvar $x 1) })",
+            "(from $i 0 to 2 { (defvar $x 1) })",
+        ] {
+            let source = format!("{} (print $x)", block);
+            match compile_error(&source) {
+                CompileError::UnknownVariable { name, .. } => assert_eq!(name, "x"),
+                other => panic!("{}: {:?}", block, other),
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_leave_the_stack_balanced() {
+        let depth = |statements: &str| {
+            let source = format!(
+                "(defvar $n 0) (defvar $a (stack_depth)) {} (defvar $b (stack_depth)) (print (- $b $a))",
+                statements
+            );
+            output(&source).unwrap()
+        };
+        // However many times they run, the variables defined inside are gone after
+        let untouched = depth("");
+        for block in [
+            "(if (= 1 1) { (defvar $x 1) })",
+            "(if (= 1 2) { } else { (defvar $x 1) })",
+            "(while (= 1 2) { (defvar $x 1) })",
+            "(while (< $n 3) { (defvar $x 1) (setvar $n (+ $n 1)) })",
+            "(from $i 0 to 3 { (defvar $x 1) (defvar $y 2) })",
+        ] {
+            assert_eq!(depth(block), untouched, "{}", block);
+        }
+    }
+
+    #[test]
+    fn non_commutative_operands_stay_in_order() {
+        let source = "
+            (print (- 10 3))
+            (print (/ 20 4))
+            (print (% 17 5))
+            (defvar $a 10)
+            (defvar $b 3)
+            (print (- $a $b))
+            (print (/ $a $b))
+            (print (% $a $b))
+            (print (- (- $a 1) (+ $b 1)))
+            (print (/ (* $a 3) (- $a $b)))";
+        let expected = "7\n5\n2\n7\n3\n1\n5\n4\n";
+        assert_eq!(output(source).unwrap(), expected);
+        // Folded into constants the same way
+        let compiled = crate::compile_source(source, "test.risp", 1, false, None, &[]).unwrap();
+        assert_eq!(
+            crate::testing::run(compiled.program, compiled.entry).unwrap(),
+            expected
+        );
+    }
+
+    // Temporaries live on the stack, so there's no limit from the VM's 10 registers
+    #[test]
+    fn more_temporaries_than_registers() {
+        let source =
+            "(print (+ 1 (+ 2 (+ 3 (+ 4 (+ 5 (+ 6 (+ 7 (+ 8 (+ 9 (+ 10 (+ 11 12))))))))))))";
+        assert_eq!(output(source).unwrap(), "78\n");
+        // (* (+ $x 1) (* (+ $x 2) ... (+ $x 11))), every product waiting on the next
+        let mut product = "(+ $x 11)".to_string();
+        for i in (1..=10).rev() {
+            product = format!("(* (+ $x {}) {})", i, product);
+        }
+        let source = format!("(defvar $x 2) (print {})", product);
+        assert_eq!(output(&source).unwrap(), "3113510400\n");
+    }
+
+    // `$` is only for variables, natives are called by their bare name
+    #[test]
+    fn dollar_native_is_a_variable() {
+        match compile_error("(print $print)") {
+            CompileError::NativeAsVariable { name, .. } => assert_eq!(name, "print"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            compile_error("(print $nope)"),
+            CompileError::UnknownVariable { .. }
+        ));
+
+        // A variable can still have a native's name
+        let source = "(defun f $min { (defvar $max 3) (return (max $min $max)) }) (print (f 5))";
+        assert_eq!(output(source).unwrap(), "5\n");
+    }
+
+    #[test]
+    fn function_frames_are_isolated() {
+        let source = "
+            (defun inner $a $b {
+                (defvar $x (* $a 10))
+                (defvar $y (* $b 100))
+                (return (+ $x $y))
+            })
+            (defun outer $n {
+                (defvar $x 1)
+                (defvar $y 2)
+                (defvar $r (inner $n (+ $n 1)))
+                (print $x)
+                (print $y)
+                (print $n)
+                (return (+ $r $x))
+            })
+            (defvar $x 7)
+            (print (outer 3))
+            (print $x)";
+        assert_eq!(output(source).unwrap(), "1\n2\n3\n431\n7\n");
+    }
+
+    #[test]
+    fn constants_can_be_read_but_not_set() {
+        let source = "(defconst $PI 3) (defvar $r 2) (print (* $PI (* $r $r)))";
+        assert_eq!(output(source).unwrap(), "12\n");
+
+        let source = "(defconst $PI 3)\n(setvar $PI 4)";
+        match compile_error(source) {
+            CompileError::SetConstant { name, span } => {
+                assert_eq!(name, "PI");
+                assert_eq!(span.start_line, 2);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Also from a block inside the constant's scope
+        let source = "(defconst $N 1) (if (= 1 1) { (setvar $N 2) })";
+        assert!(matches!(
+            compile_error(source),
+            CompileError::SetConstant { .. }
+        ));
+    }
+
+    #[test]
+    fn errors_point_at_the_source() {
+        let source = "(defvar $x 1)\n(print (+ $x\n    $y))";
+        match compile_error(source) {
+            CompileError::UnknownVariable { name, span } => {
+                assert_eq!(name, "y");
+                assert_eq!((span.start_line, span.start_column), (3, 6));
+            }
+            other => panic!("{:?}", other),
+        }
+        let error = compile_error(source).to_string();
+        assert!(error.ends_with("at test.risp:3:6"), "{}", error);
+
+        match compile_error("(print 1)\n  (missing 2)") {
+            CompileError::UnknownFunction { span, .. } => {
+                assert_eq!((span.start_line, span.start_column), (2, 4));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn direct_and_mutual_recursion() {
+        let source = "
+            (defun fact $n { (if (< $n 2) { (return 1) }) (return (* $n (fact (- $n 1)))) })
+            (print (fact 10))";
+        assert_eq!(output(source).unwrap(), "3628800\n");
+
+        // even is called before odd is defined, both ways
+        let source = "
+            (defun even $n { (if (= $n 0) { (return 1) }) (return (odd (- $n 1))) })
+            (defun odd $n { (if (= $n 0) { (return 0) }) (return (even (- $n 1))) })
+            (print (even 10))
+            (print (odd 7))
+            (print (even 7))";
+        assert_eq!(output(source).unwrap(), "1\n1\n0\n");
+    }
+
+    // $n-1 reads a variable called n-1, the error says how to subtract instead
+    #[test]
+    fn dashed_names_are_one_variable() {
+        let source = "(defvar $is-even 1) (print $is-even)";
+        assert_eq!(output(source).unwrap(), "1\n");
+
+        let error = compile_error("(defvar $n 5) (print $n-1)");
+        assert!(matches!(error, CompileError::UnknownVariable { .. }));
+        assert!(error
+            .to_string()
+            .starts_with("Unknown variable n-1, `-` is part of names, subtracting is (- $n 1)"));
+        let error = compile_error("(defvar $a 5) (defvar $b 2) (print $a-b)");
+        assert!(error.to_string().contains("(- $a $b)"), "{}", error);
+    }
+
+    // Only the matching case runs, there is no falling through into the next one
+    #[test]
+    fn switch_dispatches_to_one_case() {
+        let source = "(defun pick $n {
+                (switch $n
+                    (case 0 { (print 10) })
+                    (case 1 { (print 11) })
+                    (case 'a' { (print 97) })
+                    (default { (print 0) }))
+                (return 0)
+            })
+            (pick 1) (pick 0) (pick 97) (pick 5) (switch 3 (case 1 { (print 1) }))";
+        assert_eq!(output(source).unwrap(), "11\n10\n97\n0\n");
+    }
+
+    #[test]
+    fn switch_errors() {
+        assert!(matches!(
+            compile_error("(switch 1 (case 1 { }) (case 1 { }))"),
+            CompileError::DuplicateCase { value: 1, .. }
+        ));
+        assert!(matches!(
+            compile_error("(switch \"a\" (case 1 { }))"),
+            CompileError::SwitchOnString { .. }
+        ));
+        for source in [
+            "(switch 1 (when 1 { }))",
+            "(switch 1 (default { }) (default { }))",
+        ] {
+            assert!(
+                matches!(
+                    compile_error(source),
+                    CompileError::InvalidSwitchCase { .. }
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn string_length_and_concatenation() {
+        let source = "(print (strlen \"hello\")) (print (strlen \"\"))
+            (print_str (concat \"ab\" \"cd\"))
+            (defvar $joined (concat (concat \"a\" \"b\") \"c\"))
+            (print (strlen $joined)) (print_str $joined) (print (= $joined \"abc\"))";
+        assert_eq!(output(source).unwrap(), "5\n0\nabcd\n3\nabc\n1\n");
+    }
+
+    fn chunk(codegen: &mut CodeGen, source: &str) -> Result<Range<usize>> {
+        let ast = Parser::parse(Lexer::new(source, "repl".to_string()))?;
+        codegen.generate_incremental(ast)
+    }
+
+    // The second chunk uses the function and variable from the first, a failed chunk in between
+    // changes nothing
+    #[test]
+    fn incremental_chunks_build_on_each_other() {
+        let mut codegen = CodeGen::new();
+        let first = chunk(
+            &mut codegen,
+            "(defun double $n { (return (* $n 2)) }) (defvar $x 20) (print $x)",
+        )
+        .unwrap();
+        assert_eq!(first.start, 0);
+
+        let program = codegen.program().to_vec();
+        assert!(chunk(&mut codegen, "(print $nope)").is_err());
+        assert_eq!(codegen.program(), program);
+
+        let second = chunk(&mut codegen, "(setvar $x (+ $x 1)) (print (double $x))").unwrap();
+        assert_eq!(second, first.end..codegen.program().len());
+
+        let output = crate::testing::run(codegen.program().to_vec(), 0).unwrap();
+        assert_eq!(output, "20\n42\n");
+    }
+}
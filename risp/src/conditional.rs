@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use crate::ast::{Block, AST};
+
+// Replaces every (#if SYMBOL { ... }) with the statements in its block when the symbol is in
+// `defines` and drops it otherwise, symbols that aren't defined are off. The statements end up in
+// the surrounding block instead of a scope of their own, so whatever they define, functions and
+// macros at the root too, can be used after it. An #if used as a value is left for validate
+pub fn strip(ast: &mut AST, defines: &HashSet<String>) {
+    match ast {
+        AST::Root(block) | AST::Block(block) => strip_block(block, defines),
+        AST::NumberLiteral(_)
+        | AST::StringLiteral(_)
+        | AST::Variable(_)
+        | AST::VariableDefinition(_)
+        | AST::VariableSet(_)
+        | AST::ArrayLiteral(_)
+        | AST::Index(_)
+        | AST::Call(_)
+        | AST::BinOp(_)
+        | AST::Return(_)
+        | AST::Conditional(_) => {}
+        AST::FunctionDefinition(func) => strip_block(&mut func.block, defines),
+        AST::MacroDefinition(definition) => strip(&mut definition.template, defines),
+        AST::If(ef) => {
+            strip_block(&mut ef.then, defines);
+            if let Some(block) = &mut ef.r#else {
+                strip_block(block, defines);
+            }
+        }
+        AST::FromTo(ft) => strip_block(&mut ft.block, defines),
+        AST::While(wile) => strip_block(&mut wile.then, defines),
+        AST::Switch(switch) => {
+            for case in &mut switch.cases {
+                strip_block(&mut case.block, defines);
+            }
+            if let Some(block) = &mut switch.default {
+                strip_block(block, defines);
+            }
+        }
+    }
+}
+
+fn strip_block(block: &mut Block, defines: &HashSet<String>) {
+    let mut statements = vec![];
+    for mut stmt in std::mem::take(&mut block.statements) {
+        if let AST::Conditional(conditional) = &mut *stmt {
+            if defines.contains(&conditional.symbol.name) {
+                strip_block(&mut conditional.block, defines);
+                statements.append(&mut conditional.block.statements);
+            }
+            continue;
+        }
+
+        strip(&mut stmt, defines);
+        statements.push(stmt);
+    }
+    block.statements = statements;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile_source;
+
+    fn program(source: &str, defines: &[&str]) -> Vec<usize> {
+        let defines: Vec<String> = defines.iter().map(|d| d.to_string()).collect();
+        compile_source(source, "test.risp", 0, false, None, &defines)
+            .unwrap()
+            .program
+    }
+
+    // Kept blocks compile as if their statements were written in place, dropped ones as if they
+    // weren't there
+    #[test]
+    fn same_file_with_and_without_debug() {
+        let source =
+            "(defvar $x 1) (#if DEBUG { (defvar $y 2) (#if VERBOSE { (print 9) }) }) (print $x)
+            (#if DEBUG { (print $y) })";
+        let release = "(defvar $x 1) (print $x)";
+        let debug = "(defvar $x 1) (defvar $y 2) (print $x) (print $y)";
+        let verbose = "(defvar $x 1) (defvar $y 2) (print 9) (print $x) (print $y)";
+
+        assert_eq!(program(source, &[]), program(release, &[]));
+        assert_eq!(program(source, &["DEBUG"]), program(debug, &[]));
+        assert_eq!(
+            program(source, &["DEBUG", "VERBOSE"]),
+            program(verbose, &[])
+        );
+        assert_eq!(program(source, &["VERBOSE"]), program(release, &[]));
+        assert_ne!(program(source, &[]), program(source, &["DEBUG"]));
+    }
+}
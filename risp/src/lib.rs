@@ -1,4 +1,196 @@
+use anyhow::Result;
+use shared::lexer::Lexer;
+use vm::vm::VM;
+
 pub mod ast;
 pub mod codegen;
+pub mod conditional;
+pub mod macros;
+pub mod optimize;
 pub mod parser;
+pub mod validate;
 pub mod variable_stack;
+
+pub struct Compiled {
+    pub program: Vec<usize>,
+    pub entry: usize,
+    pub warnings: Vec<String>, // left for the caller to show
+}
+
+// Compiles risp source into bytecode. `optimize` is the level passed to codegen, 0 for none.
+// `strict` only allows comparisons as conditions. `entry` is the function to start at, main if
+// None. `defines` are the symbols #if blocks check for
+pub fn compile_source(
+    source: &str,
+    filename: &str,
+    optimize: u8,
+    strict: bool,
+    entry: Option<&str>,
+    defines: &[String],
+) -> Result<Compiled> {
+    let lexer = Lexer::new(source, filename.to_string());
+    let ast = parser::Parser::parse(lexer)?;
+    let mut codegen = codegen::CodeGen::new()
+        .with_optimize(optimize)
+        .with_strict(strict)
+        .with_entry(entry.map(|name| name.to_string()))
+        .with_defines(defines.iter().cloned().collect());
+    let (program, entry) = codegen.generate(ast)?;
+    Ok(Compiled {
+        program,
+        entry,
+        warnings: codegen.take_warnings(),
+    })
+}
+
+// Compiles and runs risp source, returns what was left on the stack
+pub fn run_source(source: &str, filename: &str) -> Result<Vec<usize>> {
+    let compiled = compile_source(source, filename, 0, false, None, &[])?;
+    let mut vm = VM::new(compiled.program, compiled.entry);
+    vm.run()?;
+    Ok(vm.stack().to_vec())
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use anyhow::Result;
+    use shared::error::CompileError;
+    use vm::{testing::Output, vm::VM};
+
+    use crate::{compile_source, Compiled};
+
+    // Returns what the program printed, or the error it stopped with
+    pub fn run(program: Vec<usize>, entry: usize) -> Result<String> {
+        let output = Output::default();
+        VM::new(program, entry)
+            .with_output(Box::new(output.clone()))
+            .run()?;
+        Ok(output.text())
+    }
+
+    pub fn compile(source: &str) -> Result<Compiled> {
+        compile_source(source, "test.risp", 0, false, None, &[])
+    }
+
+    // Compiled without optimizing, like the cli does by default
+    pub fn output(source: &str) -> Result<String> {
+        let compiled = compile(source)?;
+        run(compiled.program, compiled.entry)
+    }
+
+    pub fn compile_error(source: &str) -> CompileError {
+        match compile(source) {
+            Ok(_) => panic!("compiled: {}", source),
+            Err(err) => err
+                .downcast_ref::<CompileError>()
+                .cloned()
+                .unwrap_or_else(|| panic!("not a CompileError: {:#}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::error::CompileError;
+
+    use super::*;
+
+    #[test]
+    fn run_source_returns_the_stack() {
+        let stack = run_source("(defun main { (return (+ 2 3)) })", "test.risp").unwrap();
+        assert_eq!(stack, [5]);
+    }
+
+    #[test]
+    fn optimizing_folds_constants() {
+        let source = "
+            (defvar $x (* (+ 2 3) (- 10 4)))
+            (print (+ $x (* 4 (/ 100 5))))
+            (if (< (* 2 3) 7) { (print (% 17 5)) })";
+        let unoptimized = compile_source(source, "test.risp", 0, false, None, &[]).unwrap();
+        let optimized = compile_source(source, "test.risp", 1, false, None, &[]).unwrap();
+        assert!(
+            optimized.program.len() < unoptimized.program.len(),
+            "{} words optimized, {} not",
+            optimized.program.len(),
+            unoptimized.program.len()
+        );
+
+        let output = testing::run(unoptimized.program, unoptimized.entry).unwrap();
+        assert_eq!(output, "110\n2\n");
+        assert_eq!(
+            testing::run(optimized.program, optimized.entry).unwrap(),
+            output
+        );
+    }
+
+    // Folded or run, division is signed and rounds towards zero
+    #[test]
+    fn division_of_negative_numbers() {
+        let source = "
+            (print (/ (- 0 6) 2))
+            (print (/ 7 (- 0 2)))
+            (print (% (- 0 7) 2))
+            (print (% 7 (- 0 2)))";
+        for optimize in [0, 1] {
+            let compiled = compile_source(source, "test.risp", optimize, false, None, &[]).unwrap();
+            let output = testing::run(compiled.program, compiled.entry).unwrap();
+            assert_eq!(output, "-3\n-3\n-1\n1\n", "-O{}", optimize);
+        }
+    }
+
+    #[test]
+    fn prints_leave_the_stack_balanced() {
+        let source = "(defun main { (print 1) (print 2) (print_hex 3) (return 7) })";
+        assert_eq!(run_source(source, "test.risp").unwrap(), [7]);
+
+        // However many times it prints, the call leaves the same stack behind
+        let depth = |times: usize| {
+            let source = format!(
+                "(defun noisy $n {{
+                    (from $i 0 to $n {{ (print $i) (print_hex $i) }})
+                    (return 0)
+                }})
+                (defun main {{
+                    (defvar $before (stack_depth))
+                    (noisy {})
+                    (defvar $after (stack_depth))
+                    (return (- $after $before))
+                }})",
+                times
+            );
+            run_source(&source, "test.risp").unwrap()
+        };
+        assert_eq!(depth(50), depth(0));
+    }
+
+    // Checked before folding, which would turn the comparison into a number
+    #[test]
+    fn strict_conditions_at_every_level() {
+        for optimize in [0, 1] {
+            let compile = |source| compile_source(source, "test.risp", optimize, true, None, &[]);
+            assert!(compile("(if (< 1 2) { (print 1) })").is_ok());
+            assert!(compile("(while (= 1 2) { (print 1) })").is_ok());
+
+            for source in ["(if (+ 1 2) { (print 1) })", "(while 1 { (print 1) })"] {
+                let Err(error) = compile(source) else {
+                    panic!("{} compiled at -O{}", source, optimize);
+                };
+                assert!(matches!(
+                    error.downcast_ref::<CompileError>(),
+                    Some(CompileError::NonBooleanCondition { .. })
+                ));
+            }
+        }
+        // Off by default
+        let compiled = compile_source(
+            "(if (+ 1 2) { (print 1) })",
+            "test.risp",
+            0,
+            false,
+            None,
+            &[],
+        );
+        assert!(compiled.is_ok());
+    }
+}
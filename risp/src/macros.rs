@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use shared::{error::CompileError, instruction::NativeFunctions, token::TokenSpan};
+
+use crate::ast::{Block, Identifier, MacroDefinition, AST};
+
+// How deep macros can expand inside each other before it's assumed they never stop
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+// Replaces every call to a macro with its template, where the parameters are replaced by the
+// arguments of the call as they were written. Nothing is evaluated first and names defined by the
+// template can clash with the caller's, like defmacro in other lisps. Macros are defined at the
+// root and can be used anywhere, before their definition too. The definitions are removed
+pub fn expand(ast: &mut AST) -> Result<()> {
+    let root = match ast {
+        AST::Root(root) => root,
+        _ => return Ok(()),
+    };
+
+    let mut macros: HashMap<String, MacroDefinition> = HashMap::new();
+    for stmt in std::mem::take(&mut root.statements) {
+        match *stmt {
+            AST::MacroDefinition(definition) => {
+                let name = &definition.id.name;
+                if macros.contains_key(name) || NativeFunctions::from_string(name).is_some() {
+                    return Err(already_defined(&definition.id));
+                }
+                macros.insert(name.clone(), definition);
+            }
+            other => root.statements.push(Box::new(other)),
+        }
+    }
+
+    for stmt in &root.statements {
+        if let AST::FunctionDefinition(func) = &**stmt {
+            if let Some(definition) = macros.get(&func.id.name) {
+                return Err(already_defined(&definition.id));
+            }
+        }
+    }
+
+    let expander = Expander { macros };
+    expander.expand_block(root, 0)
+}
+
+fn already_defined(id: &Identifier) -> anyhow::Error {
+    CompileError::MacroAlreadyDefined {
+        name: id.name.clone(),
+        span: id.span.clone(),
+    }
+    .into()
+}
+
+struct Expander {
+    macros: HashMap<String, MacroDefinition>,
+}
+
+// The call being expanded, for errors
+struct Expansion<'a> {
+    name: &'a str,
+    span: &'a TokenSpan,
+    args: HashMap<&'a str, &'a AST>,
+}
+
+impl Expander {
+    fn expand_block(&self, block: &mut Block, depth: usize) -> Result<()> {
+        for stmt in &mut block.statements {
+            self.expand_node(stmt, depth)?;
+        }
+        Ok(())
+    }
+
+    fn expand_node(&self, ast: &mut AST, depth: usize) -> Result<()> {
+        match ast {
+            AST::Root(block) | AST::Block(block) => self.expand_block(block, depth),
+            AST::NumberLiteral(_) | AST::StringLiteral(_) | AST::Variable(_) => Ok(()),
+            AST::VariableDefinition(var) | AST::VariableSet(var) => {
+                self.expand_node(&mut var.value, depth)
+            }
+            AST::ArrayLiteral(array) => array
+                .elements
+                .iter_mut()
+                .try_for_each(|e| self.expand_node(e, depth)),
+            AST::Index(index) => self.expand_node(&mut index.index, depth),
+            AST::FunctionDefinition(func) => self.expand_block(&mut func.block, depth),
+            // Only the ones at the root are macros, validate rejects the rest
+            AST::MacroDefinition(_) => Ok(()),
+            AST::Conditional(conditional) => self.expand_block(&mut conditional.block, depth),
+            AST::Call(call) => {
+                for arg in &mut call.args {
+                    self.expand_node(arg, depth)?;
+                }
+
+                let definition = match self.macros.get(&call.id.name) {
+                    Some(definition) => definition,
+                    None => return Ok(()),
+                };
+                if depth >= MAX_EXPANSION_DEPTH {
+                    return Err(CompileError::MacroTooDeep {
+                        name: call.id.name.clone(),
+                        limit: MAX_EXPANSION_DEPTH,
+                        span: call.id.span.clone(),
+                    }
+                    .into());
+                }
+                if call.args.len() != definition.variables.len() {
+                    return Err(CompileError::MacroArgumentCount {
+                        name: call.id.name.clone(),
+                        expected: definition.variables.len(),
+                        got: call.args.len(),
+                        span: call.id.span.clone(),
+                    }
+                    .into());
+                }
+
+                let expansion = Expansion {
+                    name: &call.id.name,
+                    span: &call.id.span,
+                    args: definition
+                        .variables
+                        .iter()
+                        .map(|v| v.name.as_str())
+                        .zip(call.args.iter().map(|a| &**a))
+                        .collect(),
+                };
+                let mut expanded = (*definition.template).clone();
+                substitute(&mut expanded, &expansion)?;
+
+                self.expand_node(&mut expanded, depth + 1)?;
+                *ast = expanded;
+                Ok(())
+            }
+            AST::BinOp(binop) => {
+                self.expand_node(&mut binop.lhs, depth)?;
+                self.expand_node(&mut binop.rhs, depth)
+            }
+            AST::Return(ret) => self.expand_node(&mut ret.value, depth),
+            AST::If(ef) => {
+                self.expand_node(&mut ef.cond, depth)?;
+                self.expand_block(&mut ef.then, depth)?;
+                match &mut ef.r#else {
+                    Some(block) => self.expand_block(block, depth),
+                    None => Ok(()),
+                }
+            }
+            AST::FromTo(ft) => {
+                self.expand_node(&mut ft.start, depth)?;
+                self.expand_node(&mut ft.finish, depth)?;
+                if let Some(step) = &mut ft.step {
+                    self.expand_node(step, depth)?;
+                }
+                self.expand_block(&mut ft.block, depth)
+            }
+            AST::While(wile) => {
+                self.expand_node(&mut wile.cond, depth)?;
+                self.expand_block(&mut wile.then, depth)
+            }
+            AST::Switch(switch) => {
+                self.expand_node(&mut switch.value, depth)?;
+                for case in &mut switch.cases {
+                    self.expand_block(&mut case.block, depth)?;
+                }
+                match &mut switch.default {
+                    Some(block) => self.expand_block(block, depth),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Parameters used as values are replaced by the argument. Where the template names a variable
+// to define, set, index or count with, the argument has to be a variable and its name is used
+fn substitute(ast: &mut AST, expansion: &Expansion) -> Result<()> {
+    match ast {
+        AST::Variable(id) => {
+            if let Some(arg) = expansion.args.get(id.name.as_str()) {
+                *ast = (*arg).clone();
+            }
+            Ok(())
+        }
+        AST::Root(block) | AST::Block(block) => substitute_block(block, expansion),
+        AST::NumberLiteral(_) | AST::StringLiteral(_) => Ok(()),
+        AST::VariableDefinition(var) | AST::VariableSet(var) => {
+            rename(&mut var.id, expansion)?;
+            substitute(&mut var.value, expansion)
+        }
+        AST::ArrayLiteral(array) => array
+            .elements
+            .iter_mut()
+            .try_for_each(|e| substitute(e, expansion)),
+        AST::Index(index) => {
+            rename(&mut index.id, expansion)?;
+            substitute(&mut index.index, expansion)
+        }
+        // Rejected by validate once they end up in a function
+        AST::FunctionDefinition(_) | AST::MacroDefinition(_) => Ok(()),
+        AST::Conditional(conditional) => substitute_block(&mut conditional.block, expansion),
+        AST::Call(call) => call
+            .args
+            .iter_mut()
+            .try_for_each(|a| substitute(a, expansion)),
+        AST::BinOp(binop) => {
+            substitute(&mut binop.lhs, expansion)?;
+            substitute(&mut binop.rhs, expansion)
+        }
+        AST::Return(ret) => substitute(&mut ret.value, expansion),
+        AST::If(ef) => {
+            substitute(&mut ef.cond, expansion)?;
+            substitute_block(&mut ef.then, expansion)?;
+            match &mut ef.r#else {
+                Some(block) => substitute_block(block, expansion),
+                None => Ok(()),
+            }
+        }
+        AST::FromTo(ft) => {
+            if let Some(id) = &mut ft.id {
+                rename(id, expansion)?;
+            }
+            substitute(&mut ft.start, expansion)?;
+            substitute(&mut ft.finish, expansion)?;
+            if let Some(step) = &mut ft.step {
+                substitute(step, expansion)?;
+            }
+            substitute_block(&mut ft.block, expansion)
+        }
+        AST::While(wile) => {
+            substitute(&mut wile.cond, expansion)?;
+            substitute_block(&mut wile.then, expansion)
+        }
+        AST::Switch(switch) => {
+            substitute(&mut switch.value, expansion)?;
+            for case in &mut switch.cases {
+                substitute_block(&mut case.block, expansion)?;
+            }
+            match &mut switch.default {
+                Some(block) => substitute_block(block, expansion),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+fn substitute_block(block: &mut Block, expansion: &Expansion) -> Result<()> {
+    for stmt in &mut block.statements {
+        substitute(stmt, expansion)?;
+    }
+    Ok(())
+}
+
+fn rename(id: &mut Identifier, expansion: &Expansion) -> Result<()> {
+    match expansion.args.get(id.name.as_str()) {
+        Some(AST::Variable(arg)) => *id = arg.clone(),
+        Some(_) => {
+            return Err(CompileError::MacroExpectedVariable {
+                name: expansion.name.to_string(),
+                param: id.name.clone(),
+                span: expansion.span.clone(),
+            }
+            .into())
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::lexer::Lexer;
+
+    use super::*;
+    use crate::{
+        parser::Parser,
+        testing::{compile, compile_error, output},
+    };
+
+    fn expanded(source: &str) -> Vec<AST> {
+        let mut ast = Parser::parse(Lexer::new(source, "test.risp".to_string())).unwrap();
+        expand(&mut ast).unwrap();
+        match ast {
+            AST::Root(root) => root.statements.into_iter().map(|stmt| *stmt).collect(),
+            other => panic!("not a root: {:?}", other),
+        }
+    }
+
+    // The arguments take the place of the parameters in the template, as they were written
+    #[test]
+    fn swap_expands_to_its_template() {
+        let swap = "(defmacro swap $a $b { (defvar $tmp $a) (setvar $a $b) (setvar $b $tmp) })
+            (defvar $x 1) (defvar $y 2) (swap $x $y)";
+        let statements = expanded(swap);
+        assert_eq!(statements.len(), 3);
+        let AST::Block(block) = &statements[2] else {
+            panic!("not expanded: {:?}", statements[2]);
+        };
+
+        let mut assignments = vec![];
+        for stmt in &block.statements {
+            let (kind, var) = match &**stmt {
+                AST::VariableDefinition(var) => ("defvar", var),
+                AST::VariableSet(var) => ("setvar", var),
+                other => panic!("not an assignment: {:?}", other),
+            };
+            let AST::Variable(value) = &*var.value else {
+                panic!("not a variable: {:?}", var.value);
+            };
+            assignments.push((kind, var.id.name.as_str(), value.name.as_str()));
+        }
+        assert_eq!(
+            assignments,
+            [
+                ("defvar", "tmp", "x"),
+                ("setvar", "x", "y"),
+                ("setvar", "y", "tmp")
+            ]
+        );
+        assert_eq!(
+            output(&format!("{} (print $x) (print $y)", swap)).unwrap(),
+            "2\n1\n"
+        );
+    }
+
+    #[test]
+    fn expressions_compile_like_the_template_by_hand() {
+        let square = "(defmacro square $x (* $x $x)) (print (square (+ 1 2)))";
+        let by_hand = "(print (* (+ 1 2) (+ 1 2)))";
+        assert_eq!(
+            compile(square).unwrap().program,
+            compile(by_hand).unwrap().program
+        );
+    }
+
+    #[test]
+    fn macros_in_macros_and_before_their_definition() {
+        let source = "(print (sum_of_squares 2 3))
+            (defmacro sum_of_squares $a $b (+ (square $a) (square $b)))
+            (defmacro square $x (* $x $x))";
+        assert_eq!(output(source).unwrap(), "13\n");
+    }
+
+    #[test]
+    fn expansion_errors() {
+        assert!(matches!(
+            compile_error("(defmacro forever $x (forever $x)) (forever 1)"),
+            CompileError::MacroTooDeep {
+                limit: MAX_EXPANSION_DEPTH,
+                ..
+            }
+        ));
+        assert!(matches!(
+            compile_error("(defmacro square $x (* $x $x)) (print (square 1 2))"),
+            CompileError::MacroArgumentCount {
+                expected: 1,
+                got: 2,
+                ..
+            }
+        ));
+        assert!(matches!(
+            compile_error("(defmacro m $x (* $x 2)) (defmacro m $x (* $x 3))"),
+            CompileError::MacroAlreadyDefined { .. }
+        ));
+        assert!(matches!(
+            compile_error("(defmacro inc $v (setvar $v (+ $v 1))) (inc 5)"),
+            CompileError::MacroExpectedVariable { .. }
+        ));
+    }
+}
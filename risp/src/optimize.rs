@@ -0,0 +1,297 @@
+use shared::instruction::Operation;
+use shared::program::ProgramParser;
+use shared::token::TokenType;
+
+use crate::ast::{Block, NumberLiteral, AST};
+
+// Replaces binops on two numbers with their result, computed the same way the VM would. Anything
+// the VM's arithmetic mode could change, overflow or division by zero, is left for it to run
+pub fn fold(ast: &mut AST) {
+    match ast {
+        AST::Root(block) | AST::Block(block) => fold_block(block),
+        AST::NumberLiteral(_) | AST::StringLiteral(_) | AST::Variable(_) => {}
+        AST::VariableDefinition(var) | AST::VariableSet(var) => fold(&mut var.value),
+        AST::ArrayLiteral(array) => array.elements.iter_mut().for_each(|e| fold(e)),
+        AST::Index(index) => fold(&mut index.index),
+        AST::FunctionDefinition(func) => fold_block(&mut func.block),
+        AST::MacroDefinition(_) | AST::Conditional(_) => {}
+        AST::Call(call) => call.args.iter_mut().for_each(|a| fold(a)),
+        AST::BinOp(binop) => {
+            fold(&mut binop.lhs);
+            fold(&mut binop.rhs);
+            if let (AST::NumberLiteral(lhs), AST::NumberLiteral(rhs)) = (&*binop.lhs, &*binop.rhs) {
+                if let Some(value) = fold_binop(binop.op, lhs.value, rhs.value) {
+                    *ast = AST::NumberLiteral(NumberLiteral { value });
+                }
+            }
+        }
+        AST::Return(ret) => fold(&mut ret.value),
+        AST::If(ef) => {
+            fold(&mut ef.cond);
+            fold_block(&mut ef.then);
+            if let Some(block) = &mut ef.r#else {
+                fold_block(block);
+            }
+        }
+        AST::FromTo(ft) => {
+            fold(&mut ft.start);
+            fold(&mut ft.finish);
+            if let Some(step) = &mut ft.step {
+                fold(step);
+            }
+            fold_block(&mut ft.block);
+        }
+        AST::While(wile) => {
+            fold(&mut wile.cond);
+            fold_block(&mut wile.then);
+        }
+        AST::Switch(switch) => {
+            fold(&mut switch.value);
+            for case in &mut switch.cases {
+                fold_block(&mut case.block);
+            }
+            if let Some(block) = &mut switch.default {
+                fold_block(block);
+            }
+        }
+    }
+}
+
+fn fold_block(block: &mut Block) {
+    block.statements.iter_mut().for_each(|s| fold(s));
+}
+
+// Comparisons and division are signed, values are two's complement
+fn fold_binop(op: TokenType, lhs: usize, rhs: usize) -> Option<usize> {
+    let (signed_lhs, signed_rhs) = (lhs as isize, rhs as isize);
+    match op {
+        TokenType::Plus => signed_lhs.checked_add(signed_rhs).map(|v| v as usize),
+        TokenType::Dash => signed_lhs.checked_sub(signed_rhs).map(|v| v as usize),
+        TokenType::Times => signed_lhs.checked_mul(signed_rhs).map(|v| v as usize),
+        TokenType::Slash => signed_lhs.checked_div(signed_rhs).map(|v| v as usize),
+        TokenType::Percent => signed_lhs.checked_rem(signed_rhs).map(|v| v as usize),
+        TokenType::Equal => Some((signed_lhs == signed_rhs) as usize),
+        TokenType::NotEqual => Some((signed_lhs != signed_rhs) as usize),
+        TokenType::LessThan => Some((signed_lhs < signed_rhs) as usize),
+        TokenType::GreaterThan => Some((signed_lhs > signed_rhs) as usize),
+        TokenType::LessThanEqual => Some((signed_lhs <= signed_rhs) as usize),
+        TokenType::GreaterThanEqual => Some((signed_lhs >= signed_rhs) as usize),
+        _ => None,
+    }
+}
+
+// Peephole pass over generated bytecode. Removing instructions moves everything after them, so
+// `addresses` has to list every word holding a code address (jump and call targets, string
+// literals) for them to be moved along. Returns the new program and entry point.
+pub fn optimize(program: Vec<usize>, entry: usize, addresses: &[usize]) -> (Vec<usize>, usize) {
+    let mut program = program;
+    let mut entry = entry;
+    let mut addresses = addresses.to_vec();
+
+    // Removing one pair can make a new one, like a push, push, pop, pop
+    loop {
+        let removed = find_removable(&program, &addresses);
+        if removed.is_empty() {
+            return (program, entry);
+        }
+        (program, entry, addresses) = remove(&program, entry, &addresses, &removed);
+    }
+}
+
+// Returns the ranges of words to remove, sorted and not overlapping
+fn find_removable(program: &[usize], addresses: &[usize]) -> Vec<(usize, usize)> {
+    let actions = match ProgramParser::new(program.to_vec()).parse() {
+        Ok(program) => program.actions,
+        Err(_) => return vec![],
+    };
+
+    let mut starts = Vec::with_capacity(actions.len());
+    let mut start = 0;
+    for action in &actions {
+        starts.push(start);
+        start += action.size();
+    }
+
+    // Nothing can be removed from under something jumping into the middle of a pattern
+    let targets: Vec<usize> = addresses.iter().map(|&i| program[i]).collect();
+
+    let mut removed = vec![];
+    let mut i = 0;
+    while i + 1 < actions.len() {
+        let (first, second) = (&actions[i], &actions[i + 1]);
+        let jumped_into = targets.contains(&starts[i + 1]);
+        // Landing on the first not skips the comparison that makes the value a boolean
+        let jumped_to = targets.contains(&starts[i]);
+
+        let pattern = !first.data
+            && !second.data
+            && match (first.operation, second.operation) {
+                // The value never gets used
+                (Operation::Push, Operation::Pop) => true,
+                // Only an identity for booleans, so the value has to come from a comparison
+                (Operation::Not, Operation::Not) => {
+                    i > 0
+                        && !jumped_to
+                        && !actions[i - 1].data
+                        && is_boolean(actions[i - 1].operation)
+                }
+                _ => false,
+            };
+
+        if pattern && !jumped_into {
+            removed.push((starts[i], starts[i + 1] + second.size()));
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    removed
+}
+
+fn is_boolean(operation: Operation) -> bool {
+    matches!(
+        operation,
+        Operation::CmpEq
+            | Operation::CmpNe
+            | Operation::CmpGt
+            | Operation::CmpLt
+            | Operation::CmpGte
+            | Operation::CmpLte
+            | Operation::CmpStrEq
+            | Operation::Not
+    )
+}
+
+fn remove(
+    program: &[usize],
+    entry: usize,
+    addresses: &[usize],
+    removed: &[(usize, usize)],
+) -> (Vec<usize>, usize, Vec<usize>) {
+    // An address ends up moved back by however many words were removed before it. Addresses
+    // pointing at a removed instruction end up at whatever followed it
+    let moved = |addr: usize| {
+        addr - removed
+            .iter()
+            .map(|&(start, end)| end.min(addr).saturating_sub(start))
+            .sum::<usize>()
+    };
+    let is_removed = |i: usize| {
+        removed
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&i))
+    };
+
+    let mut new_program = program.to_vec();
+    for &i in addresses {
+        new_program[i] = moved(program[i]);
+    }
+    let new_program = new_program
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !is_removed(*i))
+        .map(|(_, word)| word)
+        .collect();
+
+    let new_addresses = addresses
+        .iter()
+        .filter(|&&i| !is_removed(i))
+        .map(|&i| moved(i))
+        .collect();
+
+    (new_program, moved(entry), new_addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::instruction::{NativeFunctions, OpCode, Variant};
+
+    use super::*;
+    use crate::testing::run;
+
+    fn op(operation: Operation, variants: &[Variant]) -> usize {
+        let mut all = [Variant::None; 3];
+        all[..variants.len()].copy_from_slice(variants);
+        OpCode::new(operation, all).as_usize()
+    }
+
+    // Counts down from 3, with a push and pop before the loop that the backward jump has to be
+    // moved over
+    #[test]
+    fn backward_jump_over_removed_words() {
+        let push = op(Operation::Push, &[Variant::Direct]);
+        let pop = op(Operation::Pop, &[]);
+        let program = vec![
+            push,
+            3,
+            push, // 2, removed along with the pop
+            9,
+            pop,
+            op(Operation::Push, &[Variant::Stack]), // 5, the loop
+            0,
+            op(Operation::Call, &[Variant::Native]),
+            NativeFunctions::Print as usize,
+            pop,
+            pop,
+            push,
+            1,
+            op(Operation::Sub, &[]),
+            op(Operation::Push, &[Variant::Stack]),
+            0,
+            op(Operation::JmpIf, &[Variant::Direct]),
+            5,
+            op(Operation::Halt, &[]),
+        ];
+
+        let (optimized, entry) = optimize(program.clone(), 0, &[17]);
+        assert_eq!(optimized.len(), program.len() - 3);
+        assert_eq!(optimized[14], 2);
+        assert_eq!(entry, 0);
+
+        let expected = run(program, 0).unwrap();
+        assert_eq!(expected, "3\n2\n1\n");
+        assert_eq!(run(optimized, entry).unwrap(), expected);
+    }
+
+    #[test]
+    fn double_not_after_a_comparison() {
+        let program = vec![
+            op(Operation::Push, &[Variant::Direct]),
+            1,
+            op(Operation::Push, &[Variant::Direct]),
+            2,
+            op(Operation::CmpLt, &[]),
+            op(Operation::Not, &[]),
+            op(Operation::Not, &[]),
+            op(Operation::Call, &[Variant::Native]),
+            NativeFunctions::Print as usize,
+        ];
+        let (optimized, entry) = optimize(program.clone(), 0, &[]);
+        assert_eq!(optimized.len(), program.len() - 2);
+        assert_eq!(run(optimized, entry).unwrap(), run(program, 0).unwrap());
+    }
+
+    // Jumping straight to the first not brings 5 along, which the nots turn into 1
+    #[test]
+    fn double_not_that_is_jumped_to() {
+        let program = vec![
+            op(Operation::Push, &[Variant::Direct]),
+            5,
+            op(Operation::Jmp, &[Variant::Direct]),
+            9,
+            op(Operation::Push, &[Variant::Direct]),
+            1,
+            op(Operation::Push, &[Variant::Direct]),
+            2,
+            op(Operation::CmpLt, &[]),
+            op(Operation::Not, &[]), // 9
+            op(Operation::Not, &[]),
+            op(Operation::Call, &[Variant::Native]),
+            NativeFunctions::Print as usize,
+        ];
+        let (optimized, entry) = optimize(program.clone(), 0, &[3]);
+        assert_eq!(optimized, program);
+        assert_eq!(run(optimized, entry).unwrap(), "1\n");
+    }
+}
@@ -1,26 +1,21 @@
 use std::iter::Peekable;
 
 use crate::ast::{
-    Block, Call, FromTo, FunctionDefinition, Identifier, If, Return, VariableDefinition, While, AST,
+    Block, Call, Case, Conditional, FromTo, FunctionDefinition, Identifier, If, MacroDefinition,
+    Return, Switch, VariableDefinition, While, AST,
 };
+use shared::error::CompileError;
 use shared::lexer::Lexer;
 use shared::token::{Token, TokenType};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
+
+// Can't be used as names of functions or variables
+const RESERVED: [&str; 13] = [
+    "defun", "defvar", "defconst", "setvar", "return", "if", "else", "from", "to", "by", "while",
+    "switch", "defmacro",
+];
 
-macro_rules! error_at {
-    ($loc:expr, $msg:expr,  $($items:expr),*) => {{
-        let msg = format!($msg, $($items),*);
-        let msg = format!("{}, at {}:{}:{}", msg, $loc.file, $loc.start_line, $loc.start_column);
-        anyhow::anyhow!(msg)
-    }
-    };
-    ($loc:expr, $msg:expr) => {{
-        let msg = format!("{}, at {}:{}:{}", $msg, $loc.file, $loc.start_line, $loc.start_column);
-        anyhow::anyhow!(msg)
-    }
-    };
-}
 pub struct Parser {
     lexer: Peekable<Lexer>,
     current: Token,
@@ -28,7 +23,7 @@ pub struct Parser {
 
 impl Parser {
     pub fn parse(mut lexer: Lexer) -> Result<AST> {
-        let token = lexer.next().expect("Ran out of tokens");
+        let token = lexer.next().expect("Ran out of tokens")?;
 
         let mut parser = Parser {
             lexer: lexer.peekable(),
@@ -37,38 +32,72 @@ impl Parser {
 
         parser.parse_root()
     }
-    fn advance(&mut self) -> Token {
+    fn advance(&mut self) -> Result<Token> {
         let current = self.current.clone();
-        self.current = self.lexer.next().expect("Ran out of tokens");
-        current
+        self.current = self.lexer.next().expect("Ran out of tokens")?;
+        Ok(current)
     }
 
     fn peek(&mut self) -> Result<Token> {
         Ok(self
             .lexer
             .peek()
-            .with_context(|| error_at!(self.current.span, "Unexpected eof"))?
-            .clone())
+            .ok_or_else(|| CompileError::UnexpectedEof {
+                span: self.current.span.clone(),
+            })?
+            .clone()?)
     }
 
     fn eat(&mut self, expected: TokenType) -> Result<Token> {
         if self.current.r#type == expected {
             let old = self.current.clone();
-            self.advance();
+            self.advance()?;
             Ok(old)
         } else {
-            Err(error_at!(
-                self.current.span,
-                "Expected {:?} but got {:?} {:?}",
+            Err(CompileError::UnexpectedToken {
                 expected,
-                self.current.r#type,
-                self.current.value
-            ))
+                found: self.current.r#type,
+                value: self.current.value.clone(),
+                span: self.current.span.clone(),
+            }
+            .into())
+        }
+    }
+
+    // Eats the name of something being defined
+    fn eat_name(&mut self) -> Result<Token> {
+        let name = self.eat(TokenType::Identifier)?;
+        if RESERVED.contains(&name.value.as_str()) {
+            return Err(CompileError::ReservedKeyword {
+                name: name.value,
+                span: name.span,
+            }
+            .into());
+        }
+        Ok(name)
+    }
+
+    // `$name`. In risp `$` is only ever a variable, natives are called by their bare name
+    fn eat_variable(&mut self) -> Result<Token> {
+        self.eat(TokenType::Dollar)?;
+        self.eat_name()
+    }
+
+    fn expected_expression(&self) -> anyhow::Error {
+        CompileError::ExpectedExpression {
+            found: self.current.r#type,
+            value: self.current.value.clone(),
+            span: self.current.span.clone(),
         }
+        .into()
+    }
+
+    fn parse_number(token: &Token) -> Result<usize> {
+        Ok(token.number()?)
     }
 
     fn parse_binop(&mut self) -> Result<AST> {
-        let op = self.advance();
+        let op = self.advance()?;
         let lhs = self.parse_number_binop_variable_or_statement()?;
         let rhs = self.parse_number_binop_variable_or_statement()?;
 
@@ -76,27 +105,74 @@ impl Parser {
             lhs: Box::new(lhs),
             op: op.r#type,
             rhs: Box::new(rhs),
+            span: op.span,
         }))
     }
 
     fn parse_number_binop_variable_or_statement(&mut self) -> Result<AST> {
         match self.current.r#type {
-            TokenType::Number => {
+            TokenType::Number | TokenType::Char => {
+                let value = self.advance()?;
+
+                Ok(AST::NumberLiteral(crate::ast::NumberLiteral {
+                    value: Self::parse_number(&value)?,
+                }))
+            }
+            TokenType::Dash => {
+                // Negative literal, stored as two's complement
+                self.eat(TokenType::Dash)?;
                 let value = self.eat(TokenType::Number)?;
 
                 Ok(AST::NumberLiteral(crate::ast::NumberLiteral {
-                    value: value.value.parse::<usize>()?,
+                    value: Self::parse_number(&value)?.wrapping_neg(),
+                }))
+            }
+            TokenType::String => {
+                let value = self.eat(TokenType::String)?;
+
+                Ok(AST::StringLiteral(crate::ast::StringLiteral {
+                    value: value.value,
                 }))
             }
             TokenType::Dollar => {
-                self.eat(TokenType::Dollar)?;
-                let id = self.eat(TokenType::Identifier)?;
+                let id = self.eat_variable()?;
 
-                Ok(AST::Variable(Identifier { name: id.value }))
+                Ok(AST::Variable(Identifier {
+                    name: id.value,
+                    span: id.span,
+                }))
             }
+            TokenType::LBracket => self.parse_array_literal(),
             TokenType::LParen => self.parse_statement(),
-            _ => todo!("figure out what to do here"),
+            _ => Err(self.expected_expression()),
+        }
+    }
+
+    fn parse_array_literal(&mut self) -> Result<AST> {
+        let bracket = self.eat(TokenType::LBracket)?;
+        let mut elements = vec![];
+        while self.current.r#type != TokenType::RBracket {
+            elements.push(Box::new(self.parse_number_binop_variable_or_statement()?));
         }
+        self.eat(TokenType::RBracket)?;
+
+        Ok(AST::ArrayLiteral(crate::ast::ArrayLiteral {
+            elements,
+            span: bracket.span,
+        }))
+    }
+
+    fn parse_index(&mut self) -> Result<AST> {
+        let id = self.eat_variable()?;
+        let index = self.parse_number_binop_variable_or_statement()?;
+
+        Ok(AST::Index(crate::ast::Index {
+            id: Identifier {
+                name: id.value,
+                span: id.span,
+            },
+            index: Box::new(index),
+        }))
     }
 
     fn parse_function_call(&mut self) -> Result<AST> {
@@ -106,22 +182,26 @@ impl Parser {
             args.push(Box::new(self.parse_number_binop_variable_or_statement()?));
         }
         Ok(AST::Call(Call {
-            id: Identifier { name: name.value },
+            id: Identifier {
+                name: name.value,
+                span: name.span,
+            },
             args,
         }))
     }
 
     fn parse_return(&mut self) -> Result<AST> {
-        self.eat(TokenType::Identifier)?; // return
+        let keyword = self.eat(TokenType::Identifier)?; // return
         let value = self.parse_number_binop_variable_or_statement()?;
         // TODO: allow empty return
         Ok(AST::Return(Return {
             value: Box::new(value),
+            span: keyword.span,
         }))
     }
 
     fn parse_if(&mut self) -> Result<AST> {
-        self.eat(TokenType::Identifier)?; // if
+        let keyword = self.eat(TokenType::Identifier)?; // if
         let cond = self.parse_number_binop_variable_or_statement()?;
         let then = self.parse_block()?;
 
@@ -133,12 +213,14 @@ impl Parser {
                 cond: Box::new(cond),
                 then,
                 r#else: Some(elze),
+                span: keyword.span,
             }))
         } else {
             Ok(AST::If(If {
                 cond: Box::new(cond),
                 then,
                 r#else: None,
+                span: keyword.span,
             }))
         }
     }
@@ -154,58 +236,132 @@ impl Parser {
         let mut variables: Vec<Identifier> = vec![];
 
         self.eat(TokenType::Identifier)?; // defun
-        let name = self.eat(TokenType::Identifier)?; // ex main
+        let name = self.eat_name()?; // ex main
 
         // $arg1 $arg2
         while self.current.r#type == TokenType::Dollar {
-            self.eat(TokenType::Dollar)?;
-            let id = self.eat(TokenType::Identifier)?;
-            variables.push(Identifier { name: id.value })
+            let id = self.eat_variable()?;
+            variables.push(Identifier {
+                name: id.value,
+                span: id.span,
+            })
         }
 
         let block = self.parse_block()?;
 
         Ok(AST::FunctionDefinition(FunctionDefinition {
-            id: Identifier { name: name.value },
+            id: Identifier {
+                name: name.value,
+                span: name.span,
+            },
             variables,
             block,
         }))
     }
 
-    fn parse_variable_definition(&mut self) -> Result<AST> {
+    // (#if SYMBOL { ... }), the only directive
+    fn parse_directive(&mut self) -> Result<AST> {
+        let hash = self.eat(TokenType::Hash)?;
+        let directive = self.eat(TokenType::Identifier)?;
+        if directive.value != "if" {
+            return Err(CompileError::UnknownDirective {
+                name: directive.value,
+                span: directive.span,
+            }
+            .into());
+        }
+
+        let symbol = self.eat(TokenType::Identifier)?;
+        let block = self.parse_block()?;
+
+        Ok(AST::Conditional(Conditional {
+            symbol: Identifier {
+                name: symbol.value,
+                span: symbol.span,
+            },
+            block,
+            span: hash.span,
+        }))
+    }
+
+    // (defmacro name $arg1 $arg2 template), the template is an expression or a block
+    fn parse_macro_definition(&mut self) -> Result<AST> {
+        let mut variables: Vec<Identifier> = vec![];
+
+        self.eat(TokenType::Identifier)?; // defmacro
+        let name = self.eat_name()?;
+
+        while self.current.r#type == TokenType::Dollar {
+            let id = self.eat_variable()?;
+            variables.push(Identifier {
+                name: id.value,
+                span: id.span,
+            })
+        }
+
+        let template = if self.current.r#type == TokenType::LCurly {
+            AST::Block(self.parse_block()?)
+        } else {
+            self.parse_number_binop_variable_or_statement()?
+        };
+
+        Ok(AST::MacroDefinition(MacroDefinition {
+            id: Identifier {
+                name: name.value,
+                span: name.span,
+            },
+            variables,
+            template: Box::new(template),
+        }))
+    }
+
+    // defvar or defconst
+    fn parse_variable_definition(&mut self, constant: bool) -> Result<AST> {
         self.eat(TokenType::Identifier)?; // defvar
-        self.eat(TokenType::Dollar)?; // $
-        let id = self.eat(TokenType::Identifier)?; // ex num
+        let id = self.eat_variable()?; // ex $num
 
         let value = self.parse_number_binop_variable_or_statement()?;
         Ok(AST::VariableDefinition(VariableDefinition {
-            id: Identifier { name: id.value },
+            id: Identifier {
+                name: id.value,
+                span: id.span,
+            },
             value: Box::new(value),
+            constant,
         }))
     }
 
     fn parse_set_variable(&mut self) -> Result<AST> {
         self.eat(TokenType::Identifier)?; // defvar
-        self.eat(TokenType::Dollar)?; // $
-        let id = self.eat(TokenType::Identifier)?; // ex num
+        let id = self.eat_variable()?; // ex $num
 
         let value = self.parse_number_binop_variable_or_statement()?;
         Ok(AST::VariableSet(VariableDefinition {
-            id: Identifier { name: id.value },
+            id: Identifier {
+                name: id.value,
+                span: id.span,
+            },
             value: Box::new(value),
+            constant: false,
         }))
     }
 
     fn parse_keyword(&mut self) -> Result<AST> {
         match self.current.value.as_str() {
             "defun" => self.parse_function_definition(),
-            "defvar" => self.parse_variable_definition(),
+            "defmacro" => self.parse_macro_definition(),
+            "defvar" => self.parse_variable_definition(false),
+            "defconst" => self.parse_variable_definition(true),
             "setvar" => self.parse_set_variable(),
             "return" => self.parse_return(),
             "if" => self.parse_if(),
             "from" => self.parse_from_to(),
             "while" => self.parse_while_statement(),
-            "print" | "exit" => self.parse_function_call(), // Native Functions
+            "switch" => self.parse_switch(),
+            "print" | "print_hex" | "exit" | "pow" | "abs" | "min" | "max" | "stack_depth"
+            | "trace" | "assert" | "strlen" | "concat" | "print_str" => {
+                self.parse_function_call() // Native Functions
+            }
             _ => self.parse_function_call(),
         }
     }
@@ -221,12 +377,17 @@ impl Parser {
             | TokenType::Equal
             | TokenType::GreaterThan
             | TokenType::LessThan
+            | TokenType::NotEqual
+            | TokenType::GreaterThanEqual
+            | TokenType::LessThanEqual
             | TokenType::Percent => self.parse_binop()?,
             TokenType::Identifier => self.parse_keyword()?,
-            TokenType::Number => AST::NumberLiteral(crate::ast::NumberLiteral {
-                value: self.current.value.parse::<usize>()?,
+            TokenType::Hash => self.parse_directive()?,
+            TokenType::Dollar => self.parse_index()?,
+            TokenType::Number | TokenType::Char => AST::NumberLiteral(crate::ast::NumberLiteral {
+                value: Self::parse_number(&self.current)?,
             }),
-            other => todo!("Implement {:?}", other),
+            _ => return Err(self.expected_expression()),
         };
         self.eat(TokenType::RParen)?;
         Ok(statement)
@@ -256,27 +417,115 @@ impl Parser {
     }
 
     fn parse_from_to(&mut self) -> Result<AST> {
-        self.eat(TokenType::Identifier)?; // from
+        let keyword = self.eat(TokenType::Identifier)?; // from
+
+        let id = if self.current.r#type == TokenType::Dollar {
+            let id = self.eat_variable()?;
+            Some(Identifier {
+                name: id.value,
+                span: id.span,
+            })
+        } else {
+            None
+        };
+
         let start = self.parse_number_binop_variable_or_statement()?;
         self.eat(TokenType::Identifier)?; // to
         let finish = self.parse_number_binop_variable_or_statement()?;
+
+        let step = if self.current.r#type == TokenType::Identifier && self.current.value == "by" {
+            self.eat(TokenType::Identifier)?; // by
+            Some(Box::new(self.parse_number_binop_variable_or_statement()?))
+        } else {
+            None
+        };
+
         let block = self.parse_block()?;
 
         Ok(AST::FromTo(FromTo {
+            id,
             start: Box::new(start),
             finish: Box::new(finish),
+            step,
             block,
+            span: keyword.span,
         }))
     }
 
     fn parse_while_statement(&mut self) -> Result<AST> {
-        self.eat(TokenType::Identifier)?; // if
+        let keyword = self.eat(TokenType::Identifier)?; // while
         let cond = self.parse_number_binop_variable_or_statement()?;
         let then = self.parse_block()?;
 
         Ok(AST::While(While {
             cond: Box::new(cond),
             then,
+            span: keyword.span,
+        }))
+    }
+
+    // (switch value (case 1 {...}) (case 2 {...}) (default {...})), default is optional and
+    // has to be last
+    fn parse_switch(&mut self) -> Result<AST> {
+        let keyword = self.eat(TokenType::Identifier)?; // switch
+        let value = self.parse_number_binop_variable_or_statement()?;
+
+        let mut cases = vec![];
+        let mut default = None;
+        while self.current.r#type == TokenType::LParen {
+            self.eat(TokenType::LParen)?;
+            let kind = self.eat(TokenType::Identifier)?;
+            match kind.value.as_str() {
+                "case" if default.is_none() => {
+                    let value = Self::parse_number(&self.current)?;
+                    self.advance()?;
+                    cases.push(Case {
+                        value,
+                        block: self.parse_block()?,
+                        span: kind.span,
+                    });
+                }
+                "default" if default.is_none() => default = Some(self.parse_block()?),
+                _ => return Err(CompileError::InvalidSwitchCase { span: kind.span }.into()),
+            }
+            self.eat(TokenType::RParen)?;
+        }
+
+        Ok(AST::Switch(Switch {
+            value: Box::new(value),
+            cases,
+            default,
+            span: keyword.span,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> CompileError {
+        match Parser::parse(Lexer::new(source, "test.risp".to_string())) {
+            Ok(_) => panic!("parsed: {}", source),
+            Err(err) => err
+                .downcast_ref::<CompileError>()
+                .cloned()
+                .unwrap_or_else(|| panic!("not a CompileError: {:#}", err)),
+        }
+    }
+
+    #[test]
+    fn keywords_are_reserved() {
+        for (source, keyword) in [
+            ("(defun if { (return 1) })", "if"),
+            ("(defvar $return 1)", "return"),
+            ("(defvar $x 1) (setvar $while 2)", "while"),
+            ("(defun f $from { (return 1) })", "from"),
+        ] {
+            match parse_error(source) {
+                CompileError::ReservedKeyword { name, .. } => assert_eq!(name, keyword),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+}
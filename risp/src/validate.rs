@@ -0,0 +1,190 @@
+use anyhow::Result;
+use shared::{
+    error::CompileError,
+    token::{TokenSpan, TokenType},
+};
+
+use crate::ast::{Block, AST};
+
+// Checks that the tree only has what codegen knows how to generate, so that it fails before
+// anything is emitted instead of partway through. Functions are only allowed at the root. With
+// `strict` conditions have to be comparisons, checked before folding can turn one into a number
+pub fn validate(ast: &AST, strict: bool) -> Result<()> {
+    let root = match ast {
+        AST::Root(root) => root,
+        _ => return Err(CompileError::UnsupportedNode { what: "A program" }.into()),
+    };
+
+    for stmt in &root.statements {
+        match &**stmt {
+            AST::FunctionDefinition(func) => validate_block(&func.block, strict)?,
+            other => validate_node(other, strict)?,
+        }
+    }
+    Ok(())
+}
+
+fn validate_block(block: &Block, strict: bool) -> Result<()> {
+    for stmt in &block.statements {
+        validate_node(stmt, strict)?;
+    }
+    Ok(())
+}
+
+fn validate_node(ast: &AST, strict: bool) -> Result<()> {
+    match ast {
+        AST::Root(_) => Err(CompileError::UnsupportedNode {
+            what: "A nested root",
+        }
+        .into()),
+        AST::FunctionDefinition(func) => Err(CompileError::NestedFunction {
+            name: func.id.name.clone(),
+            span: func.id.span.clone(),
+        }
+        .into()),
+        // The ones at the root are gone after expansion
+        AST::MacroDefinition(definition) => Err(CompileError::NestedMacro {
+            name: definition.id.name.clone(),
+            span: definition.id.span.clone(),
+        }
+        .into()),
+
+        // The ones used as statements are gone after stripping
+        AST::Conditional(conditional) => Err(CompileError::MisplacedConditional {
+            span: conditional.span.clone(),
+        }
+        .into()),
+
+        AST::Block(block) => validate_block(block, strict),
+        AST::NumberLiteral(_) | AST::StringLiteral(_) | AST::Variable(_) => Ok(()),
+        AST::VariableDefinition(var) | AST::VariableSet(var) => validate_node(&var.value, strict),
+        AST::ArrayLiteral(array) => array
+            .elements
+            .iter()
+            .try_for_each(|e| validate_node(e, strict)),
+        AST::Index(index) => validate_node(&index.index, strict),
+        AST::Call(call) => call.args.iter().try_for_each(|a| validate_node(a, strict)),
+        AST::BinOp(binop) => {
+            validate_node(&binop.lhs, strict)?;
+            validate_node(&binop.rhs, strict)
+        }
+        AST::Return(ret) => validate_node(&ret.value, strict),
+        AST::If(ef) => {
+            if strict {
+                check_condition(&ef.cond, "if", &ef.span)?;
+            }
+            validate_node(&ef.cond, strict)?;
+            validate_block(&ef.then, strict)?;
+            match &ef.r#else {
+                Some(block) => validate_block(block, strict),
+                None => Ok(()),
+            }
+        }
+        AST::FromTo(ft) => {
+            validate_node(&ft.start, strict)?;
+            validate_node(&ft.finish, strict)?;
+            if let Some(step) = &ft.step {
+                validate_node(step, strict)?;
+            }
+            validate_block(&ft.block, strict)
+        }
+        AST::While(wile) => {
+            if strict {
+                check_condition(&wile.cond, "while", &wile.span)?;
+            }
+            validate_node(&wile.cond, strict)?;
+            validate_block(&wile.then, strict)
+        }
+        AST::Switch(switch) => {
+            validate_node(&switch.value, strict)?;
+            for case in &switch.cases {
+                validate_block(&case.block, strict)?;
+            }
+            match &switch.default {
+                Some(block) => validate_block(block, strict),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+// Conditions that are always a number, like `(if (+ 1 2) {...})`, are errors. There are no types,
+// so variables, calls and indexing could be holding a comparison and are let through
+fn check_condition(cond: &AST, what: &'static str, span: &TokenSpan) -> Result<()> {
+    let boolean = match cond {
+        AST::BinOp(binop) => matches!(
+            binop.op,
+            TokenType::Equal
+                | TokenType::NotEqual
+                | TokenType::LessThan
+                | TokenType::GreaterThan
+                | TokenType::LessThanEqual
+                | TokenType::GreaterThanEqual
+        ),
+        AST::Variable(_) | AST::Call(_) | AST::Index(_) => true,
+        _ => false,
+    };
+    if !boolean {
+        return Err(CompileError::NonBooleanCondition {
+            what,
+            span: span.clone(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::lexer::Lexer;
+
+    use super::*;
+    use crate::{ast::NumberLiteral, parser::Parser};
+
+    fn error(ast: &AST, strict: bool) -> CompileError {
+        let err = validate(ast, strict).unwrap_err();
+        err.downcast_ref::<CompileError>()
+            .cloned()
+            .unwrap_or_else(|| panic!("not a CompileError: {:#}", err))
+    }
+
+    fn parse(source: &str) -> AST {
+        Parser::parse(Lexer::new(source, "test.risp".to_string())).unwrap()
+    }
+
+    #[test]
+    fn unsupported_nodes_are_errors() {
+        let number = AST::NumberLiteral(NumberLiteral { value: 1 });
+        assert_eq!(
+            error(&number, false),
+            CompileError::UnsupportedNode { what: "A program" }
+        );
+
+        let nested = AST::Root(Block::new(vec![Box::new(AST::Root(Block::new(vec![])))]));
+        assert_eq!(
+            error(&nested, false),
+            CompileError::UnsupportedNode {
+                what: "A nested root"
+            }
+        );
+
+        assert!(validate(&parse("(print 1)"), false).is_ok());
+    }
+
+    #[test]
+    fn strict_conditions() {
+        let ast = parse("(if (+ 1 2) { (print 1) })");
+        assert!(validate(&ast, false).is_ok());
+        assert!(matches!(
+            error(&ast, true),
+            CompileError::NonBooleanCondition { what: "if", .. }
+        ));
+
+        let ast = parse("(defun f $x { (while 1 { (print $x) }) (return 0) })");
+        assert!(matches!(
+            error(&ast, true),
+            CompileError::NonBooleanCondition { what: "while", .. }
+        ));
+        assert!(validate(&parse("(defvar $x 1) (if $x { (print 1) })"), true).is_ok());
+    }
+}
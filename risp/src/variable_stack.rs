@@ -3,25 +3,34 @@ use std::collections::HashMap;
 use anyhow::{anyhow, Context, Result};
 use shared::instruction::Variant;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    Number,
+    String, // address of the string data in the program
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Var {
     pub location: usize,
     pub variant: Variant,
+    pub value_type: ValueType,
+    pub mutable: bool, // false for defconst
 }
 
-impl Var {
-    pub fn inc(&mut self) {
-        self.location += 1;
-    }
-    pub fn dec(&mut self) {
-        self.location -= 1;
-    }
+// Every function gets its own frame, so nothing generated in one can move the variables of another.
+// Stack variables are stored as their slot counted from the bottom of the frame and turned into
+// an s(n) offset when looked up, only the depth has to follow along with pushes and pops
+#[derive(Debug, Clone)]
+struct Frame {
+    layer: usize, // first scope of the frame, scopes below it aren't visible
+    depth: usize, // values on the stack since the frame started, arguments included
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VariableStack {
     stack: Vec<HashMap<String, Var>>,
     items: Vec<usize>, // FIXME: Better name
+    frames: Vec<Frame>,
 }
 
 impl VariableStack {
@@ -29,12 +38,40 @@ impl VariableStack {
         Self {
             stack: vec![],
             items: vec![],
+            // Top-level code isn't in a function but still needs somewhere to count from
+            frames: vec![Frame { layer: 0, depth: 0 }],
         }
     }
+    // No scope has been entered yet
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
     pub fn enter(&mut self) {
         self.stack.push(HashMap::new());
         self.items.push(0);
     }
+
+    // Starts a function whose `args` arguments are already on the stack
+    pub fn enter_frame(&mut self, args: usize) {
+        self.frames.push(Frame {
+            layer: self.stack.len(),
+            depth: args,
+        });
+        self.enter();
+    }
+
+    // Whatever the function left on the stack goes away with its frame
+    pub fn leave_frame(&mut self) -> Result<()> {
+        let frame = self.frames.pop().context("Frame stack underflowed")?;
+        self.stack.truncate(frame.layer);
+        self.items.truncate(frame.layer);
+        Ok(())
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
     pub fn leave(&mut self) -> Result<()> {
         self.stack
             .pop()
@@ -50,59 +87,93 @@ impl VariableStack {
     }
 
     pub fn increment_relative(&mut self) {
-        self.stack.iter_mut().for_each(|layer| {
-            layer.iter_mut().for_each(|l| {
-                if l.1.variant == Variant::Stack {
-                    l.1.inc()
-                }
-            });
-        });
+        self.frame().depth += 1;
         let len = self.items.len() - 1;
         self.items[len] += 1;
     }
 
     pub fn decrement_relative(&mut self) {
-        self.stack.iter_mut().for_each(|layer| {
-            layer.iter_mut().for_each(|l| {
-                if l.1.variant == Variant::Stack {
-                    l.1.dec()
-                }
-            });
-        });
+        self.frame().depth -= 1;
         let len = self.items.len() - 1;
         self.items[len] -= 1;
     }
 
+    // Stack variables come back with their current s(n) offset as the location
     pub fn get(&mut self, variable: String) -> Option<Var> {
-        for layer in &self.stack {
+        let frame = self.frames.last().unwrap();
+        // Innermost scope first so that shadowing works
+        for layer in self.stack[frame.layer..].iter().rev() {
             if let Some(value) = layer.get(&variable) {
-                return Some(*value);
+                let mut value = *value;
+                if value.variant == Variant::Stack {
+                    value.location = frame.depth - 1 - value.location;
+                }
+                return Some(value);
             }
         }
         None
     }
 
+    pub fn is_in_current_scope(&self, variable: &str) -> bool {
+        match self.stack.last() {
+            Some(layer) => layer.contains_key(variable),
+            None => false,
+        }
+    }
+
     // pub fn set(&mut self, variable: String, current_stack_count: usize) -> Result<()> {
     //     let len = self.stack.len() - 1;
     //     self.stack[len].insert(variable, current_stack_count);
     //     todo!("This is wrong, should look up in previous aswell");
     // }
 
+    // For stack variables `location` is the s(n) offset the value is at right now
     pub fn create(
         &mut self,
         name: String,
-        current_stack_count: usize,
+        location: usize,
         variant: Variant,
+        value_type: ValueType,
     ) -> Result<()> {
+        self.insert(name, location, variant, value_type, true)
+    }
+
+    // Same as `create` but can't be set afterwards
+    pub fn create_constant(
+        &mut self,
+        name: String,
+        location: usize,
+        variant: Variant,
+        value_type: ValueType,
+    ) -> Result<()> {
+        self.insert(name, location, variant, value_type, false)
+    }
+
+    fn insert(
+        &mut self,
+        name: String,
+        location: usize,
+        variant: Variant,
+        value_type: ValueType,
+        mutable: bool,
+    ) -> Result<()> {
+        let location = match variant {
+            Variant::Stack => self.frame().depth - 1 - location,
+            _ => location,
+        };
+
         let len = self.stack.len() - 1;
         if let Some(_) = self.stack[len].get(&name) {
-            Err(anyhow::anyhow!("Variable {:?} is already defined", name))
+            // Codegen checks first so that it can point at where it was defined again
+            Err(anyhow!("{:?} is already defined in this scope", name))
         } else {
             self.stack[len].insert(
                 name,
                 Var {
-                    location: current_stack_count,
+                    location,
                     variant,
+                    value_type,
+                    mutable,
                 },
             );
             Ok(())
@@ -0,0 +1,487 @@
+use std::fmt;
+
+use crate::token::{TokenSpan, TokenType};
+
+// Errors produced while turning source into bytecode. They are usually passed around wrapped in
+// an anyhow::Error, use `downcast_ref::<CompileError>()` to get the structured error back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    // Lexer
+    UnknownEscapeSequence {
+        escape: char,
+        span: TokenSpan,
+    },
+    UnterminatedString {
+        span: TokenSpan,
+    },
+    UnterminatedChar {
+        span: TokenSpan,
+    },
+    EmptyChar {
+        span: TokenSpan,
+    },
+    MultiCharLiteral {
+        span: TokenSpan,
+    },
+    UnexpectedCharacter {
+        found: char,
+        span: TokenSpan,
+    },
+
+    // Shared by the parser and assembler
+    UnexpectedToken {
+        expected: TokenType,
+        found: TokenType,
+        value: String,
+        span: TokenSpan,
+    },
+    UnexpectedEof {
+        span: TokenSpan,
+    },
+    InvalidNumber {
+        value: String,
+        span: TokenSpan,
+    },
+    NumberTooLarge {
+        value: String,
+        span: TokenSpan,
+    },
+
+    // Parser
+    ExpectedExpression {
+        found: TokenType,
+        value: String,
+        span: TokenSpan,
+    },
+    InvalidSwitchCase {
+        span: TokenSpan,
+    },
+    ReservedKeyword {
+        name: String,
+        span: TokenSpan,
+    },
+    NativeAsVariable {
+        name: String,
+        span: TokenSpan,
+    },
+
+    // Assembler
+    UnknownInstruction {
+        name: String,
+        span: TokenSpan,
+    },
+    MissingOperand {
+        instruction: String,
+        span: TokenSpan,
+    },
+    TooManyOperands {
+        instruction: String,
+        span: TokenSpan,
+    },
+    InvalidOperand {
+        found: TokenType,
+        span: TokenSpan,
+    },
+    DirectOperandOnly {
+        what: &'static str,
+        span: TokenSpan,
+    },
+    UnknownOperandKind {
+        name: String,
+        span: TokenSpan,
+    },
+    InvalidMovDestination {
+        span: TokenSpan,
+    },
+    InvalidMovSource {
+        span: TokenSpan,
+    },
+    InvalidDupOperand {
+        span: TokenSpan,
+    },
+    UnknownLabel {
+        label: String,
+        span: TokenSpan,
+    },
+    UnknownNativeFunction {
+        name: String,
+        span: TokenSpan,
+    },
+    IncludeFailed {
+        path: String,
+        reason: String,
+        span: TokenSpan,
+    },
+    CircularInclude {
+        path: String,
+        span: TokenSpan,
+    },
+    EmptyDefine {
+        name: String,
+        span: TokenSpan,
+    },
+
+    // Codegen
+    UnknownVariable {
+        name: String,
+        span: TokenSpan,
+    },
+    VariableAlreadyDefined {
+        name: String,
+        span: TokenSpan,
+    },
+    SetUndefinedVariable {
+        name: String,
+        span: TokenSpan,
+    },
+    SetConstant {
+        name: String,
+        span: TokenSpan,
+    },
+    UnknownFunction {
+        name: String,
+        span: TokenSpan,
+    },
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        got: usize,
+        span: TokenSpan,
+    },
+    FunctionAlreadyDefined {
+        name: String,
+        span: TokenSpan,
+    },
+    NestedFunction {
+        name: String,
+        span: TokenSpan,
+    },
+    NestedMacro {
+        name: String,
+        span: TokenSpan,
+    },
+    MacroAlreadyDefined {
+        name: String,
+        span: TokenSpan,
+    },
+    MacroArgumentCount {
+        name: String,
+        expected: usize,
+        got: usize,
+        span: TokenSpan,
+    },
+    MacroExpectedVariable {
+        name: String,
+        param: String,
+        span: TokenSpan,
+    },
+    MacroTooDeep {
+        name: String,
+        limit: usize,
+        span: TokenSpan,
+    },
+    UnsupportedNode {
+        what: &'static str,
+    },
+    MissingMain,
+    UnknownEntry {
+        name: String,
+    },
+    EntryTakesArguments {
+        name: String,
+        args: usize,
+    },
+    MainWithTopLevelStatements,
+    ExpectedValue {
+        what: &'static str,
+        span: TokenSpan,
+    },
+    InvalidBinOp {
+        op: TokenType,
+        span: TokenSpan,
+    },
+    ZeroStep {
+        span: TokenSpan,
+    },
+    DuplicateCase {
+        value: usize,
+        span: TokenSpan,
+    },
+    SwitchOnString {
+        span: TokenSpan,
+    },
+    UnknownDirective {
+        name: String,
+        span: TokenSpan,
+    },
+    MisplacedConditional {
+        span: TokenSpan,
+    },
+    NonBooleanCondition {
+        what: &'static str,
+        span: TokenSpan,
+    },
+    InvalidStringOperation {
+        op: TokenType,
+        span: TokenSpan,
+    },
+}
+
+impl CompileError {
+    pub fn span(&self) -> Option<&TokenSpan> {
+        match self {
+            CompileError::UnknownEscapeSequence { span, .. }
+            | CompileError::UnterminatedString { span }
+            | CompileError::UnterminatedChar { span }
+            | CompileError::EmptyChar { span }
+            | CompileError::MultiCharLiteral { span }
+            | CompileError::UnexpectedCharacter { span, .. }
+            | CompileError::UnexpectedToken { span, .. }
+            | CompileError::UnexpectedEof { span }
+            | CompileError::InvalidNumber { span, .. }
+            | CompileError::NumberTooLarge { span, .. }
+            | CompileError::ExpectedExpression { span, .. }
+            | CompileError::InvalidSwitchCase { span }
+            | CompileError::ReservedKeyword { span, .. }
+            | CompileError::NativeAsVariable { span, .. }
+            | CompileError::UnknownInstruction { span, .. }
+            | CompileError::MissingOperand { span, .. }
+            | CompileError::TooManyOperands { span, .. }
+            | CompileError::InvalidOperand { span, .. }
+            | CompileError::DirectOperandOnly { span, .. }
+            | CompileError::UnknownOperandKind { span, .. }
+            | CompileError::InvalidMovDestination { span }
+            | CompileError::InvalidMovSource { span }
+            | CompileError::InvalidDupOperand { span }
+            | CompileError::UnknownLabel { span, .. }
+            | CompileError::UnknownNativeFunction { span, .. }
+            | CompileError::IncludeFailed { span, .. }
+            | CompileError::CircularInclude { span, .. }
+            | CompileError::EmptyDefine { span, .. }
+            | CompileError::FunctionAlreadyDefined { span, .. }
+            | CompileError::NestedFunction { span, .. }
+            | CompileError::NestedMacro { span, .. }
+            | CompileError::MacroAlreadyDefined { span, .. }
+            | CompileError::MacroArgumentCount { span, .. }
+            | CompileError::MacroExpectedVariable { span, .. }
+            | CompileError::MacroTooDeep { span, .. }
+            | CompileError::UnknownVariable { span, .. }
+            | CompileError::VariableAlreadyDefined { span, .. }
+            | CompileError::SetUndefinedVariable { span, .. }
+            | CompileError::SetConstant { span, .. }
+            | CompileError::UnknownFunction { span, .. }
+            | CompileError::ArgumentCount { span, .. }
+            | CompileError::ExpectedValue { span, .. }
+            | CompileError::InvalidBinOp { span, .. }
+            | CompileError::ZeroStep { span }
+            | CompileError::DuplicateCase { span, .. }
+            | CompileError::SwitchOnString { span }
+            | CompileError::UnknownDirective { span, .. }
+            | CompileError::MisplacedConditional { span }
+            | CompileError::NonBooleanCondition { span, .. }
+            | CompileError::InvalidStringOperation { span, .. } => Some(span),
+            CompileError::UnsupportedNode { .. }
+            | CompileError::MissingMain
+            | CompileError::UnknownEntry { .. }
+            | CompileError::EntryTakesArguments { .. }
+            | CompileError::MainWithTopLevelStatements => None,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            CompileError::UnknownEscapeSequence { escape, .. } => {
+                format!("Unknown escape sequence \\{}", escape)
+            }
+            CompileError::UnterminatedString { .. } => "Unterminated string".to_string(),
+            CompileError::UnterminatedChar { .. } => "Unterminated char literal".to_string(),
+            CompileError::EmptyChar { .. } => "Empty char literal".to_string(),
+            CompileError::MultiCharLiteral { .. } => {
+                "Char literal has more than one character".to_string()
+            }
+            CompileError::UnexpectedCharacter { found, .. } => {
+                format!("Unexpected character {:?}", found)
+            }
+            CompileError::UnexpectedToken {
+                expected,
+                found,
+                value,
+                ..
+            } => format!("Expected {:?} but got {:?} {:?}", expected, found, value),
+            CompileError::UnexpectedEof { .. } => "Unexpected eof".to_string(),
+            CompileError::InvalidNumber { value, .. } => format!("{} is not a valid number", value),
+            CompileError::NumberTooLarge { value, .. } => {
+                format!("{} doesn't fit in a word", value)
+            }
+            CompileError::ExpectedExpression { found, value, .. } => {
+                format!("Expected an expression but got {:?} {:?}", found, value)
+            }
+            CompileError::InvalidSwitchCase { .. } => {
+                "switch only takes (case n {...}) followed by an optional (default {...})"
+                    .to_string()
+            }
+            CompileError::ReservedKeyword { name, .. } => {
+                format!("`{}` is a reserved keyword", name)
+            }
+            CompileError::NativeAsVariable { name, .. } => format!(
+                "`${}` isn't defined, native functions are called without `$` like `({} ...)`",
+                name, name
+            ),
+            CompileError::UnknownInstruction { name, .. } => {
+                format!("Unknown instruction {}", name)
+            }
+            CompileError::MissingOperand { instruction, .. } => {
+                format!("Missing operand for {}", instruction)
+            }
+            CompileError::TooManyOperands { instruction, .. } => {
+                format!("Too many operands for {}", instruction)
+            }
+            CompileError::InvalidOperand { found, .. } => {
+                format!("Operand cant start with {:?}", found)
+            }
+            CompileError::DirectOperandOnly { what, .. } => {
+                format!("{} must be plain numbers", what)
+            }
+            CompileError::UnknownOperandKind { name, .. } => {
+                format!("Unknown operand kind '{}'", name)
+            }
+            CompileError::InvalidMovDestination { .. } => {
+                "mov can only write to a register or the stack".to_string()
+            }
+            CompileError::InvalidMovSource { .. } => {
+                "mov can only read a register, the stack or a plain number".to_string()
+            }
+            CompileError::InvalidDupOperand { .. } => {
+                "dup can only copy a register, the stack or a plain number".to_string()
+            }
+            CompileError::UnknownLabel { label, .. } => format!("Couldn't find label '{}'", label),
+            CompileError::UnknownNativeFunction { name, .. } => {
+                format!("Unknown native function {}", name)
+            }
+            CompileError::IncludeFailed { path, reason, .. } => {
+                format!("Couldn't include {}: {}", path, reason)
+            }
+            CompileError::CircularInclude { path, .. } => {
+                format!("{} includes itself", path)
+            }
+            CompileError::EmptyDefine { name, .. } => format!("{} is defined as nothing", name),
+            // `$n-1` is the variable `n-1`, not n minus 1
+            CompileError::UnknownVariable { name, .. } => match name.rsplit_once('-') {
+                Some((lhs, rhs)) if !lhs.is_empty() && !rhs.is_empty() => {
+                    let rhs = if rhs.chars().all(|c| c.is_ascii_digit()) {
+                        rhs.to_string()
+                    } else {
+                        format!("${}", rhs)
+                    };
+                    format!(
+                        "Unknown variable {}, `-` is part of names, subtracting is (- ${} {})",
+                        name, lhs, rhs
+                    )
+                }
+                _ => format!("Unknown variable {}", name),
+            },
+            CompileError::VariableAlreadyDefined { name, .. } => format!(
+                "Variable {:?} is already defined in this scope, use setvar to change it",
+                name
+            ),
+            CompileError::SetUndefinedVariable { name, .. } => format!(
+                "Can't set undefined variable {:?}, define it with defvar first",
+                name
+            ),
+            CompileError::SetConstant { name, .. } => format!(
+                "Can't set {:?}, it was defined with defconst so it can't change",
+                name
+            ),
+            CompileError::UnknownFunction { name, .. } => format!("Unknown function {}", name),
+            CompileError::ArgumentCount {
+                name,
+                expected,
+                got,
+                ..
+            } => format!(
+                "function `{}` expects {} arguments, got {}",
+                name, expected, got
+            ),
+            CompileError::FunctionAlreadyDefined { name, .. } => {
+                format!("function `{}` already defined", name)
+            }
+            CompileError::NestedFunction { name, .. } => format!(
+                "functions can only be defined at top level, `{}` is nested",
+                name
+            ),
+            CompileError::NestedMacro { name, .. } => format!(
+                "macros can only be defined at top level, `{}` is nested",
+                name
+            ),
+            CompileError::MacroAlreadyDefined { name, .. } => {
+                format!("`{}` is already defined", name)
+            }
+            CompileError::MacroArgumentCount {
+                name,
+                expected,
+                got,
+                ..
+            } => format!(
+                "macro `{}` expects {} arguments, got {}",
+                name, expected, got
+            ),
+            CompileError::MacroExpectedVariable { name, param, .. } => format!(
+                "macro `{}` sets ${}, so it has to be given a variable",
+                name, param
+            ),
+            CompileError::MacroTooDeep { name, limit, .. } => format!(
+                "macro `{}` expands more than {} levels deep, it's probably recursive",
+                name, limit
+            ),
+            CompileError::UnsupportedNode { what } => format!("{} can't be generated here", what),
+            CompileError::MissingMain => "main function not defined".to_string(),
+            CompileError::UnknownEntry { name } => {
+                format!("entry function `{}` not defined", name)
+            }
+            CompileError::EntryTakesArguments { name, args } => format!(
+                "entry function `{}` takes {} arguments, it can't take any",
+                name, args
+            ),
+            CompileError::MainWithTopLevelStatements => {
+                "top-level statements can't be used together with a main function".to_string()
+            }
+            CompileError::ExpectedValue { what, .. } => {
+                format!("{} must evaluate to a value", what)
+            }
+            CompileError::InvalidBinOp { op, .. } => {
+                format!("{:?} isn't a valid binary operation", op)
+            }
+            CompileError::ZeroStep { .. } => "from-to step can't be 0".to_string(),
+            CompileError::DuplicateCase { value, .. } => {
+                format!("switch already has a case for {}", value)
+            }
+            CompileError::SwitchOnString { .. } => "switch only works on numbers".to_string(),
+            CompileError::UnknownDirective { name, .. } => {
+                format!("Unknown directive #{}, the only one is #if", name)
+            }
+            CompileError::MisplacedConditional { .. } => {
+                "#if can only be used as a statement, not as a value".to_string()
+            }
+            CompileError::NonBooleanCondition { what, .. } => {
+                format!("{} condition has to be a comparison", what)
+            }
+            CompileError::InvalidStringOperation { op, .. } => {
+                format!(
+                    "strings can only be compared to other strings with = and !=, got {:?}",
+                    op
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(f, "{}, at {}", self.message(), span),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -1,95 +1,487 @@
 use std::{
     fs::File,
-    io::{Read, Write},
+    io::{BufRead, BufReader, Read, Write},
 };
 
 use anyhow::{anyhow, Result};
 
+pub const MAGIC: [u8; 4] = *b"RISP";
+
+// Files written before the header existed have no magic and use the fixed encoding
+pub const VERSION_LEGACY: u8 = 0;
+// Every word stored as 8 big endian bytes
+pub const VERSION_FIXED: u8 = 1;
+// Every word stored as a LEB128 varint
+pub const VERSION_VARINT: u8 = 2;
+// What new files are written as, older versions are still read
+pub const VERSION_CURRENT: u8 = VERSION_VARINT;
+
+// Versioned files start with the magic and version byte, followed by the entry point and the
+// program, both encoded as words
 pub struct FileFormat {
     pub program: Vec<usize>,
+    pub entry: usize,
+    pub version: u8,
 }
 
 impl FileFormat {
     pub fn new(program: Vec<usize>) -> Self {
-        Self { program }
+        Self {
+            program,
+            entry: 0,
+            version: VERSION_CURRENT,
+        }
+    }
+
+    pub fn with_entry(mut self, entry: usize) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
     }
 
     pub fn write_to_file(&self, filepath: String) -> Result<()> {
         let mut f = File::create(filepath)?;
-        let data = self.encode();
+        let data = self.encode()?;
         f.write_all(data.as_slice())?;
         Ok(())
     }
 
     pub fn from_file(filepath: String) -> Result<Self> {
-        let mut f = File::open(filepath)?;
-        let mut data = Vec::new();
-        f.read_to_end(&mut data)?;
+        Self::from_reader(File::open(filepath)?)
+    }
+
+    // Same as `decode` but reads one word at a time instead of needing all the bytes up front.
+    // Every version ends up the same in memory, legacy files starting at 0. `version` is the one
+    // that was read, writing it back out keeps the old format
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let mut magic = vec![];
+        (&mut reader)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic != MAGIC {
+            // Legacy files are all program, so what was read is the start of the first word
+            let mut reader = magic.as_slice().chain(reader);
+            let mut program = vec![];
+            while let Some(word) = Self::read_word(&mut reader, VERSION_LEGACY)? {
+                program.push(word);
+            }
+            return Ok(Self::new(program).with_version(VERSION_LEGACY));
+        }
 
-        Self::decode(data)
+        let mut version = [0];
+        reader
+            .read_exact(&mut version)
+            .map_err(|_| anyhow!("File format header is missing a version"))?;
+        let version = version[0];
+
+        let entry = Self::read_word(&mut reader, version)?
+            .ok_or_else(|| anyhow!("File format header is missing an entry point"))?;
+        let mut program = vec![];
+        while let Some(word) = Self::read_word(&mut reader, version)? {
+            program.push(word);
+        }
+
+        Ok(Self::new(program).with_entry(entry).with_version(version))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
+    // None once the reader is empty
+    fn read_word(reader: &mut impl BufRead, version: u8) -> Result<Option<usize>> {
+        match version {
+            VERSION_LEGACY | VERSION_FIXED => {
+                let mut word = [0; 8];
+                let mut read = 0;
+                while read < word.len() {
+                    match reader.read(&mut word[read..])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+
+                match read {
+                    0 => Ok(None),
+                    8 => Ok(Some(Self::u8_array_to_usize(word))),
+                    n => Err(anyhow!("Program ends with {} bytes, not a whole word", n)),
+                }
+            }
+            VERSION_VARINT => Self::read_varint(reader),
+            other => Err(anyhow!("Unknown file format version {}", other)),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
         let mut res = vec![];
+        let mut header = vec![];
 
-        for data in &self.program {
-            let mut a = FileFormat::usize_to_u8_vec(*data);
+        if self.version != VERSION_LEGACY {
+            res.extend_from_slice(&MAGIC);
+            res.push(self.version);
+            header.push(self.entry);
+        } else if self.entry != 0 {
+            return Err(anyhow!("Legacy file format can't store an entry point"));
+        }
+
+        for data in header.iter().chain(&self.program) {
+            let mut a = match self.version {
+                VERSION_LEGACY | VERSION_FIXED => FileFormat::usize_to_u8_vec(*data),
+                VERSION_VARINT => FileFormat::usize_to_varint(*data),
+                other => return Err(anyhow!("Unknown file format version {}", other)),
+            };
             res.append(&mut a);
         }
 
-        res
+        // It would be read back as a versioned file
+        if self.version == VERSION_LEGACY && res.starts_with(&MAGIC) {
+            return Err(anyhow!(
+                "Legacy file format can't store a program starting with the magic"
+            ));
+        }
+
+        Ok(res)
     }
 
     pub fn decode(data: Vec<u8>) -> Result<Self> {
-        let mut program: Vec<usize> = Vec::new();
+        if !data.starts_with(&MAGIC) {
+            let program = Self::decode_fixed(&data)?;
+            return Ok(Self::new(program).with_version(VERSION_LEGACY));
+        }
 
-        let mut d = Vec::with_capacity(8);
+        let version = *data
+            .get(MAGIC.len())
+            .ok_or_else(|| anyhow!("File format header is missing a version"))?;
+        let body = &data[MAGIC.len() + 1..];
 
-        for byte in data {
-            let l = d.len();
+        let mut program = match version {
+            VERSION_FIXED => Self::decode_fixed(body)?,
+            VERSION_VARINT => Self::decode_varint(body)?,
+            other => return Err(anyhow!("Unknown file format version {}", other)),
+        };
 
-            if l == 8 {
-                program.push(Self::u8_vec_to_usize(&d)?);
-                d.clear();
-            }
+        if program.is_empty() {
+            return Err(anyhow!("File format header is missing an entry point"));
+        }
+        let entry = program.remove(0);
 
-            d.push(byte);
+        Ok(Self::new(program).with_entry(entry).with_version(version))
+    }
+
+    fn decode_fixed(data: &[u8]) -> Result<Vec<usize>> {
+        let chunks = data.chunks_exact(8);
+        if !chunks.remainder().is_empty() {
+            return Err(anyhow!(
+                "Program length {} is not a multiple of 8 bytes",
+                data.len()
+            ));
         }
 
-        let l = d.len();
+        Ok(chunks
+            .map(|chunk| Self::u8_array_to_usize(chunk.try_into().unwrap()))
+            .collect())
+    }
 
-        if l == 8 {
-            program.push(Self::u8_vec_to_usize(&d)?);
-            d.clear();
+    fn decode_varint(mut data: &[u8]) -> Result<Vec<usize>> {
+        let mut program: Vec<usize> = Vec::new();
+        while let Some(value) = Self::read_varint(&mut data)? {
+            program.push(value);
         }
 
-        Ok(Self::new(program))
+        Ok(program)
     }
 
     fn usize_to_u8_vec(data: usize) -> Vec<u8> {
         vec![
-            ((data >> 8 * 7) & 0b11111111) as u8,
-            ((data >> 8 * 6) & 0b11111111) as u8,
-            ((data >> 8 * 5) & 0b11111111) as u8,
-            ((data >> 8 * 4) & 0b11111111) as u8,
-            ((data >> 8 * 3) & 0b11111111) as u8,
-            ((data >> 8 * 2) & 0b11111111) as u8,
-            ((data >> 8 * 1) & 0b11111111) as u8,
-            ((data >> 8 * 0) & 0b11111111) as u8,
+            ((data >> (8 * 7)) & 0b11111111) as u8,
+            ((data >> (8 * 6)) & 0b11111111) as u8,
+            ((data >> (8 * 5)) & 0b11111111) as u8,
+            ((data >> (8 * 4)) & 0b11111111) as u8,
+            ((data >> (8 * 3)) & 0b11111111) as u8,
+            ((data >> (8 * 2)) & 0b11111111) as u8,
+            ((data >> 8) & 0b11111111) as u8,
+            (data & 0b11111111) as u8,
         ]
     }
 
-    fn u8_vec_to_usize(data: &Vec<u8>) -> Result<usize> {
+    fn u8_array_to_usize(data: [u8; 8]) -> usize {
+        u64::from_be_bytes(data) as usize
+    }
+
+    // 7 bits per byte, least significant group first, high bit set on all but the last byte
+    fn usize_to_varint(mut data: usize) -> Vec<u8> {
+        let mut res = vec![];
+
+        loop {
+            let byte = (data & 0b01111111) as u8;
+            data >>= 7;
+
+            if data == 0 {
+                res.push(byte);
+                return res;
+            }
+
+            res.push(byte | 0b10000000);
+        }
+    }
+
+    // None if the reader is empty before the first byte
+    fn read_varint(reader: &mut impl BufRead) -> Result<Option<usize>> {
         let mut res: usize = 0;
+        let mut read = 0;
+
+        for byte in reader.bytes() {
+            let byte = byte?;
+            let shift = 7 * read;
+            let bits = (byte & 0b01111111) as usize;
+            read += 1;
+
+            if shift >= usize::BITS as usize || (bits << shift) >> shift != bits {
+                return Err(anyhow!("Varint overflows usize"));
+            }
+
+            res |= bits << shift;
+
+            if byte & 0b10000000 == 0 {
+                return Ok(Some(res));
+            }
+        }
+
+        if read == 0 {
+            Ok(None)
+        } else {
+            Err(anyhow!("Varint ended unexpectedly"))
+        }
+    }
+}
 
-        for i in 0..8 {
-            if let Some(val) = data.get(i) {
-                res |= (*val as usize) << 8 * (7 - i);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test_files/snapshots/fib.risp compiled without optimizing, entry 0x1e
+    const FIB: [usize; 42] = [
+        4295229440,
+        0,
+        4295098368,
+        2,
+        60129542144,
+        115964248064,
+        10,
+        4295229440,
+        0,
+        81604378624,
+        4295229440,
+        0,
+        4295098368,
+        1,
+        30064771072,
+        77309542400,
+        0,
+        90194313216,
+        8589934592,
+        4295229440,
+        1,
+        4295098368,
+        2,
+        30064771072,
+        77309542400,
+        0,
+        90194313216,
+        8589934592,
+        25769803776,
+        81604378624,
+        4295098368,
+        10,
+        77309542400,
+        0,
+        90194313216,
+        8589934592,
+        77309804544,
+        0,
+        90194313216,
+        8589934592,
+        8589934592,
+        94489280512,
+    ];
+
+    #[test]
+    fn varint_round_trips_every_length() {
+        for bytes in 1..=9 {
+            let smallest = if bytes == 1 {
+                0
             } else {
-                return Err(anyhow!("Failed to convert Vec<u8> to usize"));
+                1 << (7 * (bytes - 1))
+            };
+            let largest = (1 << (7 * bytes)) - 1;
+            for value in [smallest, largest] {
+                let encoded = FileFormat::usize_to_varint(value);
+                assert_eq!(encoded.len(), bytes, "{:#x}", value);
+                let decoded = FileFormat::read_varint(&mut encoded.as_slice()).unwrap();
+                assert_eq!(decoded, Some(value));
             }
         }
 
-        Ok(res)
+        // 64 bits need 10 groups of 7
+        for value in [1 << 63, usize::MAX] {
+            let encoded = FileFormat::usize_to_varint(value);
+            assert_eq!(encoded.len(), 10);
+            let decoded = FileFormat::read_varint(&mut encoded.as_slice()).unwrap();
+            assert_eq!(decoded, Some(value));
+        }
+    }
+
+    #[test]
+    fn varint_rejects_overflow_and_truncation() {
+        let mut too_long = vec![0xff; 10];
+        too_long.push(0x01);
+        assert!(FileFormat::read_varint(&mut too_long.as_slice()).is_err());
+        assert!(FileFormat::read_varint(&mut [0x80].as_slice()).is_err());
+        assert_eq!(FileFormat::read_varint(&mut [].as_slice()).unwrap(), None);
+    }
+
+    // Opcodes take 5 bytes and most operands 1, instead of 8 each
+    #[test]
+    fn compiled_program_shrinks() {
+        let fixed = FileFormat::new(FIB.to_vec())
+            .with_entry(0x1e)
+            .with_version(VERSION_FIXED)
+            .encode()
+            .unwrap();
+        let varint = FileFormat::new(FIB.to_vec())
+            .with_entry(0x1e)
+            .encode()
+            .unwrap();
+        assert!(
+            varint.len() * 3 < fixed.len() * 2,
+            "{} bytes as varints, {} fixed",
+            varint.len(),
+            fixed.len()
+        );
+
+        let decoded = FileFormat::decode(varint).unwrap();
+        assert_eq!(decoded.program, FIB);
+        assert_eq!(decoded.entry, 0x1e);
+        assert_eq!(decoded.version, VERSION_VARINT);
+    }
+
+    #[test]
+    fn round_trips_through_decode_and_the_reader() {
+        let many: Vec<usize> = (0..1000).map(|i| i * 0x1234_5678).collect();
+        for version in [VERSION_LEGACY, VERSION_FIXED, VERSION_VARINT] {
+            let entry = if version == VERSION_LEGACY { 0 } else { 3 };
+            for program in [vec![], vec![42], many.clone()] {
+                let format = FileFormat::new(program.clone())
+                    .with_entry(entry)
+                    .with_version(version);
+                let encoded = format.encode().unwrap();
+
+                let decoded = FileFormat::decode(encoded.clone()).unwrap();
+                let read = FileFormat::from_reader(encoded.as_slice()).unwrap();
+                for format in [decoded, read] {
+                    assert_eq!(format.program, program);
+                    assert_eq!(format.entry, entry);
+                    assert_eq!(format.version, version);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_error() {
+        for version in [VERSION_LEGACY, VERSION_FIXED, VERSION_VARINT] {
+            let entry = if version == VERSION_LEGACY { 0 } else { 3 };
+            let mut encoded = FileFormat::new(vec![1, 2, 1 << 40])
+                .with_entry(entry)
+                .with_version(version)
+                .encode()
+                .unwrap();
+            encoded.pop();
+            assert!(FileFormat::decode(encoded.clone()).is_err());
+            assert!(FileFormat::from_reader(encoded.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn unsupported_versions_error() {
+        let mut data = MAGIC.to_vec();
+        data.extend([99, 0, 0]);
+        let Err(error) = FileFormat::decode(data.clone()) else {
+            panic!("decoded an unknown version");
+        };
+        assert_eq!(error.to_string(), "Unknown file format version 99");
+        assert!(FileFormat::from_reader(data.as_slice()).is_err());
+    }
+}
+
+// Round trips of generated programs, in every version
+#[cfg(test)]
+mod properties {
+    use quickcheck::{QuickCheck, TestResult};
+
+    use super::*;
+
+    const VERSIONS: [u8; 3] = [VERSION_LEGACY, VERSION_FIXED, VERSION_VARINT];
+
+    // Legacy files have no entry point and can't start with the magic, anything else has to come
+    // back the same from both decode and the reader
+    fn round_trips(program: Vec<usize>, entry: usize, version: u8) -> TestResult {
+        let version = VERSIONS[version as usize % VERSIONS.len()];
+        let entry = if version == VERSION_LEGACY { 0 } else { entry };
+        let format = FileFormat::new(program.clone())
+            .with_entry(entry)
+            .with_version(version);
+        let encoded = match format.encode() {
+            Ok(encoded) => encoded,
+            Err(_) => return TestResult::discard(),
+        };
+
+        let decoded = FileFormat::decode(encoded.clone()).unwrap();
+        let read = FileFormat::from_reader(encoded.as_slice()).unwrap();
+        TestResult::from_bool([decoded, read].iter().all(|format| {
+            format.program == program && format.entry == entry && format.version == version
+        }))
+    }
+
+    #[test]
+    fn generated_programs_round_trip() {
+        QuickCheck::new()
+            .tests(5000)
+            .quickcheck(round_trips as fn(Vec<usize>, usize, u8) -> TestResult);
+    }
+
+    #[test]
+    fn edge_programs_round_trip() {
+        let programs = [
+            vec![],
+            vec![0],
+            vec![1],
+            vec![usize::MAX],
+            vec![usize::MAX; 100],
+            vec![0, usize::MAX, 1 << 63, 127, 128],
+        ];
+        for program in programs {
+            for version in 0..VERSIONS.len() as u8 {
+                for entry in [0, usize::MAX] {
+                    let result = round_trips(program.clone(), entry, version);
+                    assert!(!result.is_failure(), "{:?} in version {}", program, version);
+                }
+            }
+        }
+    }
+
+    fn varint_round_trips(value: usize) -> bool {
+        let encoded = FileFormat::usize_to_varint(value);
+        let mut reader = encoded.as_slice();
+        FileFormat::read_varint(&mut reader).unwrap() == Some(value) && reader.is_empty()
+    }
+
+    #[test]
+    fn generated_varints_round_trip() {
+        QuickCheck::new()
+            .tests(10000)
+            .quickcheck(varint_round_trips as fn(usize) -> bool);
     }
 }
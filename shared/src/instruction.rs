@@ -1,4 +1,4 @@
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Operation {
     Nop = 0,
@@ -25,62 +25,117 @@ pub enum Operation {
     Ret = 19,
     Not = 20,
     Swap = 21,
+    Halt = 22,
+    CmpStrEq = 23, // compares the contents of two strings
+
+    // Heap memory, see the VM for the layout
+    Alloc = 24,
+    Load = 25,
+    Store = 26,
+
+    JmpIfNot = 27, // jumps if the popped condition is zero
+    Cmp = 28,      // pushes sign(lhs - rhs), that is -1, 0 or 1
+    PushN = 29,    // followed by a count and that many values, pushed in order
+    PopN = 30,     // pops as many values as its operand says
+
+    // Bitwise on the whole word, risp's and/or are logical and don't compile to these
+    And = 31,
+    Or = 32,
+    Xor = 33,
 }
 
 impl Operation {
+    // Every operation, in order of value. from_usize and from_asm go through this, to_asm is
+    // the only other place that lists them
+    pub const ALL: [Operation; 34] = [
+        Operation::Nop,
+        Operation::Push,
+        Operation::Pop,
+        Operation::Mov,
+        Operation::Jmp,
+        Operation::Dup,
+        Operation::Add,
+        Operation::Sub,
+        Operation::Mult,
+        Operation::Div,
+        Operation::JmpIf,
+        Operation::CmpEq,
+        Operation::CmpNe,
+        Operation::CmpGt,
+        Operation::CmpLt,
+        Operation::CmpGte,
+        Operation::CmpLte,
+        Operation::Mod,
+        Operation::Call,
+        Operation::Ret,
+        Operation::Not,
+        Operation::Swap,
+        Operation::Halt,
+        Operation::CmpStrEq,
+        Operation::Alloc,
+        Operation::Load,
+        Operation::Store,
+        Operation::JmpIfNot,
+        Operation::Cmp,
+        Operation::PushN,
+        Operation::PopN,
+        Operation::And,
+        Operation::Or,
+        Operation::Xor,
+    ];
+
+    pub fn iter() -> impl Iterator<Item = Operation> {
+        Self::ALL.into_iter()
+    }
+
+    #[inline]
     pub fn from_usize(value: usize) -> Option<Operation> {
-        match value {
-            0 => Some(Operation::Nop),
-            1 => Some(Operation::Push),
-            2 => Some(Operation::Pop),
-            3 => Some(Operation::Mov),
-            4 => Some(Operation::Jmp),
-            5 => Some(Operation::Dup),
-            6 => Some(Operation::Add),
-            7 => Some(Operation::Sub),
-            8 => Some(Operation::Mult),
-            9 => Some(Operation::Div),
-            10 => Some(Operation::JmpIf),
-            11 => Some(Operation::CmpEq),
-            12 => Some(Operation::CmpNe),
-            13 => Some(Operation::CmpGt),
-            14 => Some(Operation::CmpLt),
-            15 => Some(Operation::CmpGte),
-            16 => Some(Operation::CmpLte),
-            17 => Some(Operation::Mod),
-            18 => Some(Operation::Call),
-            19 => Some(Operation::Ret),
-            20 => Some(Operation::Not),
-            21 => Some(Operation::Swap),
-            _ => None,
-        }
+        Self::ALL.get(value).copied()
     }
 
     pub fn from_asm(value: &str) -> Option<Operation> {
-        match value {
-            "nop" => Some(Operation::Nop),
-            "push" => Some(Operation::Push),
-            "pop" => Some(Operation::Pop),
-            "mov" => Some(Operation::Mov),
-            "jmp" => Some(Operation::Jmp),
-            "dup" => Some(Operation::Dup),
-            "add" => Some(Operation::Add),
-            "sub" => Some(Operation::Sub),
-            "mult" => Some(Operation::Mult),
-            "div" => Some(Operation::Div),
-            "jmp_if" => Some(Operation::JmpIf),
-            "cmp_eq" => Some(Operation::CmpEq),
-            "cmp_ne" => Some(Operation::CmpNe),
-            "cmp_gt" => Some(Operation::CmpGt),
-            "cmp_lt" => Some(Operation::CmpLt),
-            "cmp_gte" => Some(Operation::CmpGte),
-            "cmp_lte" => Some(Operation::CmpLte),
-            "mod" => Some(Operation::Mod),
-            "call" => Some(Operation::Call),
-            "ret" => Some(Operation::Ret),
-            "not" => Some(Operation::Not),
-            "swap" => Some(Operation::Swap),
-            _ => None,
+        Self::iter().find(|op| op.to_asm() == value)
+    }
+
+    // Operands after the opcode, one word each. The assembler, the disassembler and the VM all
+    // go by this. swap's depth is optional, see `OpCode::operand_words`, and push_n's operand is
+    // the count of values that follow it
+    pub fn operand_count(&self) -> usize {
+        match self {
+            Operation::Mov => 2,
+            Operation::Push
+            | Operation::Jmp
+            | Operation::JmpIf
+            | Operation::JmpIfNot
+            | Operation::Dup
+            | Operation::Call
+            | Operation::Swap
+            | Operation::PushN
+            | Operation::PopN => 1,
+            Operation::Nop
+            | Operation::Pop
+            | Operation::Add
+            | Operation::Sub
+            | Operation::Mult
+            | Operation::Div
+            | Operation::Mod
+            | Operation::CmpEq
+            | Operation::CmpNe
+            | Operation::CmpGt
+            | Operation::CmpLt
+            | Operation::CmpGte
+            | Operation::CmpLte
+            | Operation::Cmp
+            | Operation::CmpStrEq
+            | Operation::Ret
+            | Operation::Not
+            | Operation::Halt
+            | Operation::Alloc
+            | Operation::Load
+            | Operation::Store
+            | Operation::And
+            | Operation::Or
+            | Operation::Xor => 0,
         }
     }
 
@@ -108,23 +163,45 @@ impl Operation {
             Operation::Ret => "ret",
             Operation::Not => "not",
             Operation::Swap => "swap",
+            Operation::Halt => "halt",
+            Operation::CmpStrEq => "cmp_str_eq",
+            Operation::Alloc => "alloc",
+            Operation::Load => "load",
+            Operation::Store => "store",
+            Operation::JmpIfNot => "jmp_if_not",
+            Operation::Cmp => "cmp",
+            Operation::PushN => "push_n",
+            Operation::PopN => "pop_n",
+            Operation::And => "and",
+            Operation::Or => "or",
+            Operation::Xor => "xor",
         }
     }
 }
 
+// from_usize relies on every operation being at the index of its value
+const _: () = {
+    let mut i = 0;
+    while i < Operation::ALL.len() {
+        assert!(Operation::ALL[i] as usize == i);
+        i += 1;
+    }
+};
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Variant {
     None = 0,
-    Register = 1,
-    Direct = 2,
+    Register = 1, // r(n), register n
+    Direct = 2,   // n, the value itself
     Indirect = 3,
-    Stack = 4,
-    StackAbsoulute = 5,
-    Native = 6,
+    Stack = 4,         // s(n), relative to the top of the stack, s(0) is the top
+    StackAbsolute = 5, // sa(n), absolute from the bottom of the stack, sa(0) is the bottom
+    Native = 6,        // $name, a native function
 }
 
 impl Variant {
+    #[inline]
     pub fn from_usize(value: usize) -> Option<Variant> {
         match value {
             0 => Some(Variant::None),
@@ -132,11 +209,27 @@ impl Variant {
             2 => Some(Variant::Direct),
             3 => Some(Variant::Indirect),
             4 => Some(Variant::Stack),
-            5 => Some(Variant::StackAbsoulute),
+            5 => Some(Variant::StackAbsolute),
             6 => Some(Variant::Native),
             _ => None,
         }
     }
+
+    // Whether an operand of this variant has a value to read, like what's pushed or moved
+    pub fn is_readable(&self) -> bool {
+        matches!(
+            self,
+            Variant::Register | Variant::Direct | Variant::Stack | Variant::StackAbsolute
+        )
+    }
+
+    // Whether an operand of this variant can be written to, like where mov puts the value
+    pub fn is_writable(&self) -> bool {
+        matches!(
+            self,
+            Variant::Register | Variant::Stack | Variant::StackAbsolute
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -153,14 +246,17 @@ impl OpCode {
         OpCode(code)
     }
 
+    #[inline]
     pub fn from_usize(code: usize) -> Self {
         OpCode(code)
     }
 
+    #[inline]
     pub fn operation(&self) -> Option<Operation> {
         Operation::from_usize(self.0 >> 8 * 4)
     }
 
+    #[inline]
     pub fn variants(&self) -> Option<[Variant; 3]> {
         Some([
             Variant::from_usize((self.0 >> 8 * 2) & 0b1111)?,
@@ -169,10 +265,22 @@ impl OpCode {
         ])
     }
 
+    #[inline]
     pub fn split(&self) -> Option<(Operation, [Variant; 3])> {
         Some((self.operation()?, self.variants()?))
     }
 
+    // Words after the opcode that belong to the instruction. `first` is the word right after
+    // it, only needed for push_n
+    pub fn operand_words(&self, first: Option<usize>) -> Option<usize> {
+        match self.operation()? {
+            Operation::PushN => first.map(|count| 1 + count),
+            // The depth is optional, without it the top two are swapped
+            Operation::Swap if !matches!(self.variants(), Some([Variant::Direct, ..])) => Some(0),
+            operation => Some(operation.operand_count()),
+        }
+    }
+
     pub fn as_usize(&self) -> usize {
         self.0
     }
@@ -183,6 +291,17 @@ impl OpCode {
 pub enum NativeFunctions {
     Print = 0,
     Exit = 1,
+    PrintHex = 2,
+    Pow = 3,        // (pow base exp), an error if exp is negative or past u32::MAX
+    Abs = 4,        // (abs value)
+    Min = 5,        // (min lhs rhs)
+    Max = 6,        // (max lhs rhs)
+    StackDepth = 7, // (stack_depth), the number of values on the stack
+    Trace = 8,      // (trace), prints the whole stack
+    Assert = 9,     // (assert cond), stops the program with an error if cond is 0
+    StrLen = 10,    // (strlen string), the number of characters
+    Concat = 11,    // (concat lhs rhs), a new string with rhs after lhs
+    PrintStr = 12,  // (print_str string)
 }
 
 impl NativeFunctions {
@@ -190,6 +309,17 @@ impl NativeFunctions {
         match name {
             "print" => Some(NativeFunctions::Print),
             "exit" => Some(NativeFunctions::Exit),
+            "print_hex" => Some(NativeFunctions::PrintHex),
+            "pow" => Some(NativeFunctions::Pow),
+            "abs" => Some(NativeFunctions::Abs),
+            "min" => Some(NativeFunctions::Min),
+            "max" => Some(NativeFunctions::Max),
+            "stack_depth" => Some(NativeFunctions::StackDepth),
+            "trace" => Some(NativeFunctions::Trace),
+            "assert" => Some(NativeFunctions::Assert),
+            "strlen" => Some(NativeFunctions::StrLen),
+            "concat" => Some(NativeFunctions::Concat),
+            "print_str" => Some(NativeFunctions::PrintStr),
             _ => None,
         }
     }
@@ -198,6 +328,17 @@ impl NativeFunctions {
         match num {
             0 => Some(NativeFunctions::Print),
             1 => Some(NativeFunctions::Exit),
+            2 => Some(NativeFunctions::PrintHex),
+            3 => Some(NativeFunctions::Pow),
+            4 => Some(NativeFunctions::Abs),
+            5 => Some(NativeFunctions::Min),
+            6 => Some(NativeFunctions::Max),
+            7 => Some(NativeFunctions::StackDepth),
+            8 => Some(NativeFunctions::Trace),
+            9 => Some(NativeFunctions::Assert),
+            10 => Some(NativeFunctions::StrLen),
+            11 => Some(NativeFunctions::Concat),
+            12 => Some(NativeFunctions::PrintStr),
             _ => None,
         }
     }
@@ -206,7 +347,62 @@ impl NativeFunctions {
         match self {
             NativeFunctions::Print => Some("print"),
             NativeFunctions::Exit => Some("exit"),
+            NativeFunctions::PrintHex => Some("print_hex"),
+            NativeFunctions::Pow => Some("pow"),
+            NativeFunctions::Abs => Some("abs"),
+            NativeFunctions::Min => Some("min"),
+            NativeFunctions::Max => Some("max"),
+            NativeFunctions::StackDepth => Some("stack_depth"),
+            NativeFunctions::Trace => Some("trace"),
+            NativeFunctions::Assert => Some("assert"),
+            NativeFunctions::StrLen => Some("strlen"),
+            NativeFunctions::Concat => Some("concat"),
+            NativeFunctions::PrintStr => Some("print_str"),
             _ => None,
         }
     }
+
+    // How many values it reads off the stack. exit can also be called without its code
+    pub fn arguments(&self) -> usize {
+        match self {
+            NativeFunctions::StackDepth | NativeFunctions::Trace => 0,
+            NativeFunctions::Print
+            | NativeFunctions::Exit
+            | NativeFunctions::PrintHex
+            | NativeFunctions::Abs
+            | NativeFunctions::Assert
+            | NativeFunctions::StrLen
+            | NativeFunctions::PrintStr => 1,
+            NativeFunctions::Pow
+            | NativeFunctions::Min
+            | NativeFunctions::Max
+            | NativeFunctions::Concat => 2,
+        }
+    }
+
+    // Whether it pushes a value when it's done. The ones with nothing useful to return, like
+    // print, push 0 so that every call can be used as a value. exit never comes back
+    pub fn returns_value(&self) -> bool {
+        !matches!(self, NativeFunctions::Exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_operation_round_trips() {
+        for (i, op) in Operation::iter().enumerate() {
+            assert_eq!(op as usize, i, "{:?} is out of order in ALL", op);
+            assert_eq!(Operation::from_usize(op as usize), Some(op));
+            assert_eq!(Operation::from_asm(op.to_asm()), Some(op));
+
+            let variants = [Variant::Register, Variant::Stack, Variant::None];
+            let code = OpCode::new(op, variants).as_usize();
+            assert_eq!(OpCode::from_usize(code).split(), Some((op, variants)));
+        }
+        assert_eq!(Operation::from_usize(Operation::ALL.len()), None);
+        assert_eq!(Operation::from_asm("nope"), None);
+    }
 }
@@ -1,5 +1,6 @@
+use crate::error::CompileError;
 use crate::token::{Token, TokenSpan, TokenType};
-use std::{char, fs};
+use std::char;
 
 #[derive(Debug)]
 pub struct Lexer {
@@ -14,41 +15,35 @@ pub struct Lexer {
     has_eof: bool,
 }
 impl Lexer {
-    pub fn new_from_path(filepath: String) -> Self {
-        let err = format!("Failed to open {filepath}");
-        let data = fs::read_to_string(filepath.clone())
-            .expect(&err)
-            .chars()
-            .into_iter()
-            .collect();
-
+    // `filepath` is only used for spans
+    pub fn new(source: &str, filepath: String) -> Self {
         Self {
             i: 0,
-            data,
+            data: source.chars().collect(),
             current_line: 1,
-            current_column: 0,
+            current_column: 1,
             filepath,
             has_eof: false,
         }
     }
 
+    // Columns are bumped after this, so the first column of the next line ends up as 1
     fn check_newline(&mut self) {
-        let c = self.data[self.i];
-        if c == '\n' {
+        if self.current_as_char() == Some('\n') {
             self.current_line += 1;
             self.current_column = 0;
         }
     }
 
+    // Stops at the newline so that it gets counted when skipping whitespace
     fn skip_comment(&mut self) {
-        while self.data[self.i] != '\n' {
+        while !self.is_at_end() && self.current_as_char() != Some('\n') {
             self.advance();
-            self.check_newline();
         }
     }
 
     fn get_char_token(&mut self) -> Option<Token> {
-        let c = self.data[self.i] as char;
+        let c = self.current_as_char()?;
         let span = TokenSpan::new(
             self.filepath.clone(),
             self.current_line,
@@ -62,11 +57,14 @@ impl Lexer {
             '=' => Some(Token::new(TokenType::Equal, span, c.to_string())),
             '{' => Some(Token::new(TokenType::LCurly, span, c.to_string())),
             '}' => Some(Token::new(TokenType::RCurly, span, c.to_string())),
+            '[' => Some(Token::new(TokenType::LBracket, span, c.to_string())),
+            ']' => Some(Token::new(TokenType::RBracket, span, c.to_string())),
             '(' => Some(Token::new(TokenType::LParen, span, c.to_string())),
             ')' => Some(Token::new(TokenType::RParen, span, c.to_string())),
             ',' => Some(Token::new(TokenType::Comma, span, c.to_string())),
             '.' => Some(Token::new(TokenType::Dot, span, c.to_string())),
             '$' => Some(Token::new(TokenType::Dollar, span, c.to_string())),
+            '#' => Some(Token::new(TokenType::Hash, span, c.to_string())),
             '+' => Some(Token::new(TokenType::Plus, span, c.to_string())),
             '-' => Some(Token::new(TokenType::Dash, span, c.to_string())),
             '*' => Some(Token::new(TokenType::Times, span, c.to_string())),
@@ -78,15 +76,50 @@ impl Lexer {
         }
     }
 
-    fn capture_string(&mut self) -> Token {
+    fn get_double_char_token(&mut self) -> Option<Token> {
+        let value: String = [self.current_as_char()?, self.peek_as_char()?]
+            .iter()
+            .collect();
+        let r#type = match value.as_str() {
+            "!=" => TokenType::NotEqual,
+            "<=" => TokenType::LessThanEqual,
+            ">=" => TokenType::GreaterThanEqual,
+            _ => return None,
+        };
+
+        let span = TokenSpan::new(
+            self.filepath.clone(),
+            self.current_line,
+            self.current_column,
+            self.current_line,
+            self.current_column + 2,
+        );
+
+        Some(Token::new(r#type, span, value))
+    }
+
+    // Strings may span lines, `\"` is an escaped quote
+    fn capture_string(&mut self) -> Result<Token, CompileError> {
         let mut string = String::new();
         let start_line = self.current_line;
         let start_col = self.current_column;
 
         loop {
             self.advance();
-            if self.current_as_char() == '"' {
-                let t = Token::new(
+            let unterminated = || CompileError::UnterminatedString {
+                span: TokenSpan::new(
+                    self.filepath.clone(),
+                    start_line,
+                    start_col,
+                    self.current_line,
+                    self.current_column,
+                ),
+            };
+            let c = self.current_as_char().ok_or_else(unterminated)?;
+
+            if c == '"' {
+                self.advance();
+                return Ok(Token::new(
                     TokenType::String,
                     TokenSpan::new(
                         self.filepath.clone(),
@@ -96,24 +129,84 @@ impl Lexer {
                         self.current_column,
                     ),
                     string,
-                );
-                self.advance();
-                return t;
+                ));
             }
-            if self.current_as_char() == '\\' {
-                let c = self.data[self.i + 1] as char;
-                match c {
-                    '\\' => string.push('\\'),
-                    'n' => string.push('\n'),
-                    't' => string.push('\t'),
-                    'r' => string.push('\r'),
-                    _ => panic!("Unknown escape sequence {}", c),
-                }
-                self.advance();
+            if c == '\\' {
+                // A backslash right before the end of the file never gets its closing quote
+                self.peek_as_char().ok_or_else(unterminated)?;
+                string.push(self.capture_escape()?);
                 continue;
             }
-            string.push(self.current_as_char());
+            string.push(c);
+        }
+    }
+
+    // Expects to be on the backslash, leaves the escaped character as the current one
+    fn capture_escape(&mut self) -> Result<char, CompileError> {
+        let span = TokenSpan::new(
+            self.filepath.clone(),
+            self.current_line,
+            self.current_column,
+            self.current_line,
+            self.current_column + 2,
+        );
+        self.advance();
+
+        match self.current_as_char() {
+            Some('\\') => Ok('\\'),
+            Some('"') => Ok('"'),
+            Some('\'') => Ok('\''),
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some(escape) => Err(CompileError::UnknownEscapeSequence { escape, span }),
+            None => Err(CompileError::UnterminatedChar { span }),
+        }
+    }
+
+    // The token value is the codepoint as a decimal number, so it can be used like one
+    fn capture_char(&mut self) -> Result<Token, CompileError> {
+        let start_line = self.current_line;
+        let start_col = self.current_column;
+        let span = |lexer: &Self| {
+            TokenSpan::new(
+                lexer.filepath.clone(),
+                start_line,
+                start_col,
+                lexer.current_line,
+                lexer.current_column,
+            )
+        };
+
+        self.advance();
+        let c = match self.current_as_char() {
+            Some('\'') => {
+                self.advance();
+                return Err(CompileError::EmptyChar { span: span(self) });
+            }
+            Some('\\') => self.capture_escape()?,
+            Some(c) if c != '\n' => c,
+            _ => return Err(CompileError::UnterminatedChar { span: span(self) }),
+        };
+        self.advance();
+
+        if self.current_as_char() == Some('\'') {
+            self.advance();
+            return Ok(Token::new(
+                TokenType::Char,
+                span(self),
+                (c as u32).to_string(),
+            ));
+        }
+
+        // Skip the rest of it so that `'ab'` is reported as one error instead of several
+        while let Some(c) = self.current_as_char().filter(|c| *c != '\n') {
+            self.advance();
+            if c == '\'' {
+                return Err(CompileError::MultiCharLiteral { span: span(self) });
+            }
         }
+        Err(CompileError::UnterminatedChar { span: span(self) })
     }
 
     fn capture_number(&mut self) -> Token {
@@ -121,9 +214,22 @@ impl Lexer {
         let start_line = self.current_line;
         let start_col = self.current_column;
 
-        // TODO: Add support for hex and binary numbers
-        while self.current_as_char().is_numeric() {
-            number.push(self.current_as_char());
+        // 0x and 0b take every letter and digit after them, which ones are valid is checked
+        // when the number is parsed
+        let prefixed = self.current_as_char() == Some('0')
+            && matches!(self.peek_as_char(), Some('x') | Some('b'));
+        if prefixed {
+            for _ in 0..2 {
+                number.push(self.current_as_char().unwrap());
+                self.advance();
+            }
+        }
+
+        while let Some(c) = self
+            .current_as_char()
+            .filter(|c| c.is_numeric() || (prefixed && c.is_alphanumeric()))
+        {
+            number.push(c);
             self.advance();
         }
 
@@ -140,16 +246,18 @@ impl Lexer {
         );
     }
 
+    // `-` can be part of identifiers like in other lisps, `is-even` is one name. In risp
+    // subtraction is always written as (- a b), so it never needs to split one
     fn capture_identifier(&mut self) -> Token {
         let mut string = String::new();
         let start_line = self.current_line;
         let start_col = self.current_column;
 
-        while self.current_as_char().is_alphanumeric()
-            || self.current_as_char() == '_'
-            || self.current_as_char() == '-'
+        while let Some(c) = self
+            .current_as_char()
+            .filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-')
         {
-            string.push(self.current_as_char());
+            string.push(c);
             self.advance();
         }
 
@@ -166,39 +274,47 @@ impl Lexer {
         );
     }
 
-    fn current_as_char(&mut self) -> char {
-        self.data[self.i] as char
+    fn is_at_end(&self) -> bool {
+        self.i >= self.data.len()
     }
 
-    fn peek_as_char(&mut self) -> char {
-        self.data[self.i + 1] as char
+    fn current_as_char(&self) -> Option<char> {
+        self.data.get(self.i).copied()
+    }
+
+    fn peek_as_char(&self) -> Option<char> {
+        self.data.get(self.i + 1).copied()
     }
 
     fn advance(&mut self) {
+        if self.is_at_end() {
+            return;
+        }
         self.check_newline();
         self.i += 1;
         self.current_column += 1;
     }
 
     fn skip_whitespace(&mut self) {
-        while self.current_as_char().is_whitespace() {
-            if self.i == self.data.len() - 1 {
-                return;
-            }
+        while self.current_as_char().is_some_and(|c| c.is_whitespace()) {
             self.advance();
         }
     }
-}
-
-impl Iterator for Lexer {
-    type Item = Token;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.data.len() {
+    // The next token, ending with an EoF token and None after that. Same as `next` but easier
+    // to use with `?`
+    pub fn try_next(&mut self) -> Result<Option<Token>, CompileError> {
+        while !self.is_at_end() {
             self.skip_whitespace();
 
-            if self.current_as_char() == ';' {
+            let c = match self.current_as_char() {
+                Some(c) => c,
+                None => break,
+            };
+
+            if c == ';' {
                 self.skip_comment();
+                continue;
             }
 
             // if self.current_as_char() == '/' && self.peek_as_char() == '/' {
@@ -206,32 +322,53 @@ impl Iterator for Lexer {
             //     continue;
             // }
 
+            if let Some(token) = self.get_double_char_token() {
+                self.advance();
+                self.advance();
+                return Ok(Some(token));
+            }
+
             if let Some(token) = self.get_char_token() {
                 self.advance();
-                return Some(token);
+                return Ok(Some(token));
             }
 
-            if self.current_as_char() == '"' {
-                return Some(self.capture_string());
+            if c == '"' {
+                return self.capture_string().map(Some);
             }
 
-            if self.current_as_char().is_numeric() {
-                return Some(self.capture_number());
+            if c == '\'' {
+                return self.capture_char().map(Some);
             }
 
-            if self.current_as_char().is_alphabetic() {
-                return Some(self.capture_identifier());
+            if c.is_numeric() {
+                return Ok(Some(self.capture_number()));
             }
 
-            // TODO: Handle unknown token
+            if c.is_alphabetic() {
+                return Ok(Some(self.capture_identifier()));
+            }
+
+            let error = CompileError::UnexpectedCharacter {
+                found: c,
+                span: TokenSpan::new(
+                    self.filepath.clone(),
+                    self.current_line,
+                    self.current_column,
+                    self.current_line,
+                    self.current_column + 1,
+                ),
+            };
+            // Skipped so that reading on carries on after it instead of failing forever
             self.advance();
+            return Err(error);
         }
 
         if self.has_eof {
-            None
+            Ok(None)
         } else {
             self.has_eof = true;
-            Some(Token::new(
+            Ok(Some(Token::new(
                 TokenType::EoF,
                 TokenSpan::new(
                     self.filepath.clone(),
@@ -241,7 +378,234 @@ impl Iterator for Lexer {
                     self.current_column,
                 ),
                 "EOF".to_string(),
-            ))
+            )))
+        }
+    }
+
+    // Every token up to and including EoF, or the first error
+    pub fn tokenize(self) -> Result<Vec<Token>, CompileError> {
+        self.collect()
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Result<Token, CompileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(source: &str) -> Vec<TokenType> {
+        Lexer::new(source, "test".to_string())
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.r#type)
+            .collect()
+    }
+
+    #[test]
+    fn comparisons_of_two_characters() {
+        assert_eq!(
+            types("!= >= <= < > ="),
+            [
+                TokenType::NotEqual,
+                TokenType::GreaterThanEqual,
+                TokenType::LessThanEqual,
+                TokenType::LessThan,
+                TokenType::GreaterThan,
+                TokenType::Equal,
+                TokenType::EoF,
+            ]
+        );
+        assert_eq!(
+            types("(<=1)"),
+            [
+                TokenType::LParen,
+                TokenType::LessThanEqual,
+                TokenType::Number,
+                TokenType::RParen,
+                TokenType::EoF,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_are_structured() {
+        let error = Lexer::new("\"a\\q\"", "test".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            CompileError::UnknownEscapeSequence { escape: 'q', .. }
+        ));
+
+        let error = Lexer::new("(\n  ~", "test".to_string())
+            .tokenize()
+            .unwrap_err();
+        match error {
+            CompileError::UnexpectedCharacter { found, span } => {
+                assert_eq!(found, '~');
+                assert_eq!((span.start_line, span.start_column), (2, 3));
+            }
+            other => panic!("{:?}", other),
         }
     }
+
+    #[test]
+    fn reading_on_after_an_error() {
+        let mut lexer = Lexer::new("1 ~ 2", "test".to_string());
+        assert_eq!(lexer.next().unwrap().unwrap().value, "1");
+        assert!(matches!(
+            lexer.next(),
+            Some(Err(CompileError::UnexpectedCharacter { found: '~', .. }))
+        ));
+        assert_eq!(lexer.next().unwrap().unwrap().value, "2");
+        assert_eq!(lexer.next().unwrap().unwrap().r#type, TokenType::EoF);
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        for source in ["(x)  ", "(x)\t\t", "(x)\n\n", "(x) \t\n", " ", "\n"] {
+            let expected: &[TokenType] = if source.trim().is_empty() {
+                &[TokenType::EoF]
+            } else {
+                &[
+                    TokenType::LParen,
+                    TokenType::Identifier,
+                    TokenType::RParen,
+                    TokenType::EoF,
+                ]
+            };
+            assert_eq!(types(source), expected, "{:?}", source);
+        }
+    }
+
+    fn spans(source: &str) -> Vec<(usize, usize, usize, usize)> {
+        Lexer::new(source, "test".to_string())
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| {
+                let span = token.span;
+                (
+                    span.start_line,
+                    span.start_column,
+                    span.end_line(),
+                    span.end_column(),
+                )
+            })
+            .collect()
+    }
+
+    // Columns start at 1 and the end is one past the last character
+    #[test]
+    fn token_ends() {
+        assert_eq!(
+            spans("(print \"a b\")\n  hello"),
+            [
+                (1, 1, 1, 2),
+                (1, 2, 1, 7),
+                (1, 8, 1, 13),
+                (1, 13, 1, 14),
+                (2, 3, 2, 8),
+                (2, 8, 2, 8),
+            ]
+        );
+    }
+
+    fn values(source: &str) -> Vec<String> {
+        Lexer::new(source, "test".to_string())
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.value)
+            .collect()
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(values(r#""say \"hi\"""#)[0], "say \"hi\"");
+        assert_eq!(values(r#""a\n\t\\""#)[0], "a\n\t\\");
+
+        let error = Lexer::new("(print\n  \"ab\\z\")", "test".to_string())
+            .tokenize()
+            .unwrap_err();
+        match error {
+            CompileError::UnknownEscapeSequence { escape, span } => {
+                assert_eq!(escape, 'z');
+                assert_eq!((span.start_line, span.start_column), (2, 6));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        for source in ["\"\\", "\"abc\\", "\"abc"] {
+            let error = Lexer::new(source, "test".to_string())
+                .tokenize()
+                .unwrap_err();
+            assert!(
+                matches!(error, CompileError::UnterminatedString { .. }),
+                "{:?}",
+                source
+            );
+        }
+    }
+
+    fn lex_error(source: &str) -> CompileError {
+        Lexer::new(source, "test".to_string())
+            .tokenize()
+            .unwrap_err()
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_eq!(types("'a'"), [TokenType::Char, TokenType::EoF]);
+        assert_eq!(values("'a'")[0], "97");
+        assert_eq!(values(r"'\n'")[0], "10");
+        assert_eq!(values(r"'\''")[0], "39");
+        assert_eq!(values(r"'\\'")[0], "92");
+
+        assert!(matches!(lex_error("''"), CompileError::EmptyChar { .. }));
+        assert!(matches!(
+            lex_error("'ab'"),
+            CompileError::MultiCharLiteral { .. }
+        ));
+        assert!(matches!(
+            lex_error("'a"),
+            CompileError::UnterminatedChar { .. }
+        ));
+    }
+
+    #[test]
+    fn errors_come_back_as_err() {
+        let error = Lexer::new("(print \"abc", "test".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(matches!(error, CompileError::UnterminatedString { .. }));
+
+        // The iterator hands out the tokens before the error, then the error
+        let mut lexer = Lexer::new("(x ~", "test".to_string());
+        assert_eq!(lexer.next().unwrap().unwrap().r#type, TokenType::LParen);
+        assert_eq!(lexer.next().unwrap().unwrap().r#type, TokenType::Identifier);
+        assert!(lexer.next().unwrap().is_err());
+
+        let mut lexer = Lexer::new("x", "test".to_string());
+        assert!(lexer.try_next().unwrap().is_some());
+        assert_eq!(lexer.try_next().unwrap().unwrap().r#type, TokenType::EoF);
+        assert!(lexer.try_next().unwrap().is_none());
+    }
+
+    // `-` only starts subtraction on its own, inside a name it's part of it
+    #[test]
+    fn dashes_in_identifiers() {
+        assert_eq!(values("a-b"), ["a-b", "EOF"]);
+        assert_eq!(values("$n-1"), ["$", "n-1", "EOF"]);
+        assert_eq!(values("(- $a 1)"), ["(", "-", "$", "a", "1", ")", "EOF"]);
+    }
 }
@@ -1,3 +1,4 @@
+pub mod error;
 pub mod fileformat;
 pub mod instruction;
 pub mod lexer;
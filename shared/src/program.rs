@@ -1,31 +1,69 @@
+use std::collections::HashSet;
+
 use crate::instruction::{NativeFunctions, OpCode, Operation, Variant};
 use anyhow::{anyhow, Context, Result};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Operand {
     pub value: usize,
     pub variant: Variant,
+    pub string: Option<String>, // set for string data, rendered instead of the value
 }
 
 impl Operand {
     pub fn new(value: usize, variant: Variant) -> Self {
-        Self { value, variant }
+        Self {
+            value,
+            variant,
+            string: None,
+        }
+    }
+
+    // Decodes an operand as it's stored in the program, the variant from the opcode and the word
+    // after it. None is what instructions without an operand have, so it can't be one
+    pub fn from_variant_value(variant: Variant, value: usize) -> Result<Self> {
+        match variant {
+            Variant::None => Err(anyhow!("Operand {} has no variant", value)),
+            variant => Ok(Self::new(value, variant)),
+        }
+    }
+
+    // `addr` is where the string data starts
+    pub fn string(addr: usize, value: String) -> Self {
+        Self {
+            value: addr,
+            variant: Variant::Direct,
+            string: Some(value),
+        }
     }
 
     pub fn format(&self) -> String {
+        if let Some(string) = &self.string {
+            return format!("{:?}", string);
+        }
+
         match self.variant {
             Variant::Stack => format!("s({})", self.value),
-            Variant::StackAbsoulute => format!("sa({})", self.value),
+            Variant::StackAbsolute => format!("sa({})", self.value),
             Variant::Register => format!("r({})", self.value),
             Variant::Direct => format!("{}", self.value),
-            Variant::Native => format!(
-                "${}",
-                NativeFunctions::from_usize(self.value)
-                    .unwrap()
-                    .to_string()
-                    .unwrap()
-            ), // TODO: Look up native function name from number
-            Variant::None | Variant::Indirect => "".to_string(),
+            Variant::Native => {
+                match NativeFunctions::from_usize(self.value).and_then(|func| func.to_string()) {
+                    Some(name) => format!("${}", name),
+                    None => format!("$?({})", self.value), // not a native this VM has
+                }
+            }
+            // Nothing emits or runs it yet and the assembler has no syntax for it, but it's
+            // shown instead of leaving a blank
+            Variant::Indirect => format!("indirect({})", self.value),
+            Variant::None => "".to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match &self.string {
+            Some(string) => json_string(string),
+            None => self.value.to_string(),
         }
     }
 }
@@ -34,6 +72,9 @@ impl Operand {
 pub struct Action {
     pub operation: Operation,
     pub operands: Vec<Operand>,
+    pub data: bool, // data embedded in the program, operation is Nop and isn't executed
+    pub offset: usize, // where in the program the action starts
+    pub words: Vec<usize>, // the raw words it was parsed from
 }
 
 impl Action {
@@ -41,10 +82,27 @@ impl Action {
         Self {
             operation,
             operands,
+            data: false,
+            offset: 0,
+            words: vec![],
+        }
+    }
+
+    pub fn string(addr: usize, value: String) -> Self {
+        Self {
+            operation: Operation::Nop,
+            operands: vec![Operand::string(addr, value)],
+            data: true,
+            offset: 0,
+            words: vec![],
         }
     }
 
     pub fn format(&self) -> String {
+        if self.data {
+            return format!(".string {}", self.operands[0].format());
+        }
+
         format!(
             "{} {}",
             self.operation.to_asm(),
@@ -55,6 +113,50 @@ impl Action {
                 .join(", ")
         )
     }
+
+    // Number of words the action takes up in the program
+    pub fn size(&self) -> usize {
+        match &self.operands[..] {
+            [operand] if self.data => 1 + operand.string.as_ref().map_or(0, |s| s.chars().count()),
+            // The count isn't an operand
+            operands if matches!(self.operation, Operation::PushN) => 2 + operands.len(),
+            operands => 1 + operands.len(),
+        }
+    }
+
+    // Address this jumps or calls to, if it's a fixed one
+    pub fn target(&self) -> Option<usize> {
+        match (self.operation, self.operands.first()) {
+            (
+                Operation::Jmp | Operation::JmpIf | Operation::JmpIfNot | Operation::Call,
+                Some(operand),
+            ) if !self.data && operand.variant == Variant::Direct => Some(operand.value),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let variants = self
+            .operands
+            .iter()
+            .map(|o| json_string(&format!("{:?}", o.variant)))
+            .collect::<Vec<String>>()
+            .join(",");
+        let operands = self
+            .operands
+            .iter()
+            .map(|o| o.to_json())
+            .collect::<Vec<String>>()
+            .join(",");
+
+        format!(
+            "{{\"operation\":{},\"variants\":[{}],\"operands\":[{}],\"data\":{}}}",
+            json_string(self.operation.to_asm()),
+            variants,
+            operands,
+            self.data
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -75,6 +177,156 @@ impl Program {
 
         assembly
     }
+
+    // Errors on the entry point or the first fixed jump or call target that isn't the start of an
+    // instruction. Landing on an operand or on data runs it as an opcode. Going to the very end is
+    // fine, the program stops there
+    pub fn verify_targets(&self, entry: usize) -> Result<()> {
+        let starts: HashSet<usize> = self
+            .actions
+            .iter()
+            .filter(|a| !a.data)
+            .map(|a| a.offset)
+            .collect();
+        let end = self.actions.last().map_or(0, |a| a.offset + a.words.len());
+        let valid = |target: usize| starts.contains(&target) || target == end;
+
+        if !valid(entry) {
+            return Err(anyhow!(
+                "Entry point {:#x} is not the start of an instruction",
+                entry
+            ));
+        }
+        for action in &self.actions {
+            match action.target() {
+                Some(target) if !valid(target) => {
+                    return Err(anyhow!(
+                        "{} at {:#x} goes to {:#x}, which is not the start of an instruction",
+                        action.operation.to_asm(),
+                        action.offset,
+                        target
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Same as `to_string` but with labels for jump targets and the entry point, so that it can be
+    // assembled back into the same program
+    pub fn to_asm(&self, entry: usize) -> Result<String> {
+        let mut starts = Vec::with_capacity(self.actions.len());
+        let mut addr = 0;
+        for action in &self.actions {
+            starts.push(addr);
+            addr += action.size();
+        }
+
+        let mut labels: Vec<usize> = self
+            .actions
+            .iter()
+            .filter_map(|a| a.target())
+            .filter(|t| starts.contains(t))
+            .collect();
+        let label = |addr: usize| format!(".addr_{}", addr);
+
+        let mut assembly = "".to_string();
+        if entry != 0 {
+            if !starts.contains(&entry) {
+                return Err(anyhow!(
+                    "Entry point {:#x} is not the start of an instruction",
+                    entry
+                ));
+            }
+            labels.push(entry);
+            assembly.push_str(format!(".entry {}\n", label(entry)).as_str());
+        }
+
+        for (action, start) in self.actions.iter().zip(starts) {
+            if labels.contains(&start) {
+                assembly.push_str(format!("{}:\n", label(start)).as_str());
+            }
+
+            match action.target().filter(|t| labels.contains(t)) {
+                Some(target) => assembly.push_str(
+                    format!("  {} {}\n", action.operation.to_asm(), label(target)).as_str(),
+                ),
+                None => assembly.push_str(format!("  {}\n", action.format()).as_str()),
+            }
+        }
+
+        Ok(assembly)
+    }
+
+    // One action per line with its offset and the raw words it came from, for debugging the
+    // encoding. Can't be assembled back
+    pub fn to_annotated(&self) -> String {
+        let words: Vec<String> = self
+            .actions
+            .iter()
+            .map(|a| {
+                a.words
+                    .iter()
+                    .map(|w| format!("{:#x}", w))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect();
+        // Strings can be any length, so only instructions decide where the mnemonics line up
+        let width = self
+            .actions
+            .iter()
+            .zip(&words)
+            .filter(|(a, _)| !a.data)
+            .map(|(_, w)| w.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut annotated = "".to_string();
+        for (action, words) in self.actions.iter().zip(&words) {
+            annotated.push_str(
+                format!(
+                    "{:#06x}: {:<width$}  {}\n",
+                    action.offset,
+                    words,
+                    action.format(),
+                    width = width
+                )
+                .as_str(),
+            );
+        }
+
+        annotated
+    }
+
+    // One object per action, string data has the string itself as its operand
+    pub fn to_json(&self) -> String {
+        let actions = self
+            .actions
+            .iter()
+            .map(|a| a.to_json())
+            .collect::<Vec<String>>()
+            .join(",");
+        format!("[{}]", actions)
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = "\"".to_string();
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            '\r' => json.push_str("\\r"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
 }
 
 #[derive(Clone, Debug)]
@@ -96,47 +348,68 @@ impl ProgramParser {
     pub fn parse(&mut self) -> Result<Program> {
         while self.pc < self.bytes.len() {
             let action = self.step()?;
+            let skips_data = matches!(action.operation, Operation::Jmp)
+                && action.operands[0].variant == Variant::Direct;
+            let target = action.operands.first().map(|o| o.value);
             self.actions.push(action);
+
+            if let Some(string) = target
+                .filter(|_| skips_data)
+                .and_then(|t| self.string_at(t))
+            {
+                self.actions.push(string);
+            }
         }
 
         Ok(Program::new(self.actions.clone()))
     }
 
     pub fn step(&mut self) -> Result<Action> {
+        let start = self.pc;
         let opcode = OpCode::from_usize(match self.advance() {
-            None => return Err(anyhow!("djawjdakwd")),
+            None => return Err(anyhow!("Program ends before an instruction at {}", start)),
             Some(value) => value,
         });
 
-        match opcode.operation() {
-            Some(Operation::Nop) => self.collect_zero(&opcode),
-            Some(Operation::Push) => self.collect_one(&opcode),
-            Some(Operation::Pop) => self.collect_zero(&opcode),
-            Some(Operation::Add) => self.collect_zero(&opcode),
-            Some(Operation::Mult) => self.collect_zero(&opcode),
-            Some(Operation::Sub) => self.collect_zero(&opcode),
-            Some(Operation::Div) => self.collect_zero(&opcode),
-            Some(Operation::Mov) => self.collect_two(&opcode),
-            Some(Operation::Jmp) => self.collect_one(&opcode),
-            Some(Operation::JmpIf) => self.collect_one(&opcode),
-
-            Some(Operation::CmpEq) => self.collect_zero(&opcode),
-            Some(Operation::CmpNe) => self.collect_zero(&opcode),
-            Some(Operation::CmpGt) => self.collect_zero(&opcode),
-            Some(Operation::CmpLt) => self.collect_zero(&opcode),
-            Some(Operation::CmpGte) => self.collect_zero(&opcode),
-            Some(Operation::CmpLte) => self.collect_zero(&opcode),
-            Some(Operation::Dup) => self.collect_one(&opcode),
-            Some(Operation::Call) => self.collect_one(&opcode),
-            Some(Operation::Ret) => self.collect_zero(&opcode),
-            Some(Operation::Not) => self.collect_zero(&opcode),
-            Some(Operation::Mod) => self.collect_zero(&opcode),
-            Some(Operation::Swap) => self.collect_zero(&opcode),
-            Some(other) => {
-                todo!("Opcode {:?} not implemented", other)
+        let mut action = match opcode.operation() {
+            Some(Operation::PushN) => self.collect_many(&opcode),
+            Some(_) => {
+                let count = opcode.operand_words(None).unwrap();
+                self.collect(&opcode, count)
             }
-            None => panic!("Invalid opcode {:?}", opcode),
+            None => Err(anyhow!(
+                "Invalid opcode {:#x} at {}",
+                opcode.as_usize(),
+                start
+            )),
+        }?;
+
+        action.offset = start;
+        action.words = self.bytes[start..self.pc.min(self.bytes.len())].to_vec();
+        Ok(action)
+    }
+
+    // Strings are emitted as their length followed by one word per character, right after a jmp
+    // over them. If the words up to `target` look like that they are read as a string. A length
+    // of 0 is left alone, it's the same word as a nop and both assemble back the same way
+    fn string_at(&mut self, target: usize) -> Option<Action> {
+        let addr = self.pc;
+        let len = *self.bytes.get(addr)?;
+        if len == 0 || addr.checked_add(len)?.checked_add(1)? != target || target > self.bytes.len()
+        {
+            return None;
         }
+
+        let string = self.bytes[addr + 1..target]
+            .iter()
+            .map(|c| u32::try_from(*c).ok().and_then(char::from_u32))
+            .collect::<Option<String>>()?;
+
+        self.pc = target;
+        let mut action = Action::string(addr, string);
+        action.offset = addr;
+        action.words = self.bytes[addr..target].to_vec();
+        Some(action)
     }
 
     fn advance(&mut self) -> Option<usize> {
@@ -147,31 +420,198 @@ impl ProgramParser {
         }
     }
 
-    fn collect_zero(&mut self, op: &OpCode) -> Result<Action> {
-        Ok(Action::new(op.operation().unwrap(), vec![]))
+    fn collect(&mut self, op: &OpCode, count: usize) -> Result<Action> {
+        let mut operands = vec![];
+        if count > 0 {
+            let variants = op
+                .variants()
+                .with_context(|| format!("Invalid variants in opcode {:#x}", op.as_usize()))?;
+            for variant in &variants[..count] {
+                operands.push(self.collect_operand(*variant)?);
+            }
+        }
+
+        Ok(Action::new(op.operation().unwrap(), operands))
     }
 
-    fn collect_one(&mut self, op: &OpCode) -> Result<Action> {
-        let variants = op
-            .variants()
-            .with_context(|| format!("Failed to collect variants"))?;
+    // A count followed by that many direct values, the count itself isn't kept as an operand
+    fn collect_many(&mut self, op: &OpCode) -> Result<Action> {
+        let count = self
+            .advance()
+            .with_context(|| format!("Missing count for {:?}", op.operation()))?;
+        let mut operands = vec![];
+        for _ in 0..count {
+            let value = self.advance().with_context(|| {
+                format!("{:?} ends before its {} values", op.operation(), count)
+            })?;
+            operands.push(Operand::new(value, Variant::Direct));
+        }
+
+        Ok(Action::new(op.operation().unwrap(), operands))
+    }
+
+    fn collect_operand(&mut self, variant: Variant) -> Result<Operand> {
+        let value = self.advance().context("Program ends before an operand")?;
+        Operand::from_variant_value(variant, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(operation: Operation, variants: &[Variant]) -> usize {
+        let mut all = [Variant::None; 3];
+        all[..variants.len()].copy_from_slice(variants);
+        OpCode::new(operation, all).as_usize()
+    }
+
+    #[test]
+    fn string_data_is_shown_as_a_string() {
+        // jmp over "hi" at 2, then push its address and halt
+        let words = vec![
+            op(Operation::Jmp, &[Variant::Direct]),
+            5,
+            2,
+            'h' as usize,
+            'i' as usize,
+            op(Operation::Push, &[Variant::Direct]),
+            2,
+            op(Operation::Halt, &[]),
+        ];
+        let program = ProgramParser::new(words).parse().unwrap();
+        let lines: Vec<_> = program.to_string().lines().map(str::to_string).collect();
+        assert_eq!(lines.len(), 4, "{:?}", lines);
+        assert!(lines[1].ends_with(".string \"hi\""), "{:?}", lines);
+        assert_eq!(program.actions[1].size(), 3);
+        assert!(program.to_json().contains("\"hi\""));
+    }
+
+    #[test]
+    fn json_structure() {
+        let words = vec![
+            op(Operation::Push, &[Variant::Direct]),
+            5,
+            op(Operation::Mov, &[Variant::Register, Variant::Stack]),
+            1,
+            0,
+            op(Operation::Halt, &[]),
+        ];
+        let program = ProgramParser::new(words).parse().unwrap();
+        assert_eq!(
+            program.to_json(),
+            concat!(
+                "[",
+                r#"{"operation":"push","variants":["Direct"],"operands":[5],"data":false},"#,
+                r#"{"operation":"mov","variants":["Register","Stack"],"operands":[1,0],"data":false},"#,
+                r#"{"operation":"halt","variants":[],"operands":[],"data":false}"#,
+                "]"
+            )
+        );
+    }
+
+    #[test]
+    fn push_n_lists_its_values() {
+        let push_n = op(Operation::PushN, &[]);
+        let words = vec![push_n, 3, 1, 2, 3, push_n, 1, 7, op(Operation::Halt, &[])];
+        let program = ProgramParser::new(words).parse().unwrap();
+        assert_eq!(
+            program.to_string(),
+            "  push_n 1, 2, 3\n  push_n 7\n  halt \n"
+        );
+        assert_eq!(program.actions[0].size(), 5);
+    }
+
+    #[test]
+    fn annotated_shows_offsets_and_words() {
+        let words = vec![
+            op(Operation::Push, &[Variant::Direct]),
+            0x2a,
+            op(Operation::Halt, &[]),
+        ];
+        let push = format!("{:#x} 0x2a", words[0]);
+        let halt = format!("{:#x}", words[2]);
+        let program = ProgramParser::new(words).parse().unwrap();
+        assert_eq!(
+            program.to_annotated(),
+            format!(
+                "0x0000: {}  push 42\n0x0002: {:<width$}  halt \n",
+                push,
+                halt,
+                width = push.len()
+            )
+        );
+    }
+
+    #[test]
+    fn stack_operands_use_assembler_syntax() {
+        let mov = op(Operation::Mov, &[Variant::StackAbsolute, Variant::Stack]);
+        let push = op(Operation::Push, &[Variant::Register]);
+        let program = ProgramParser::new(vec![mov, 0, 0, push, 3])
+            .parse()
+            .unwrap();
+        assert_eq!(program.to_string(), "  mov sa(0), s(0)\n  push r(3)\n");
+    }
+
+    // push 5 at 0, jmp at 2 and halt at 4
+    fn jump_to(target: usize) -> Result<()> {
+        let program = vec![
+            op(Operation::Push, &[Variant::Direct]),
+            5,
+            op(Operation::Jmp, &[Variant::Direct]),
+            target,
+            op(Operation::Halt, &[]),
+        ];
+        ProgramParser::new(program).parse()?.verify_targets(0)
+    }
+
+    #[test]
+    fn jumps_into_operands_are_caught() {
+        for target in [0, 2, 4, 5] {
+            assert!(jump_to(target).is_ok(), "{}", target);
+        }
+        let error = jump_to(1).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "jmp at 0x2 goes to 0x1, which is not the start of an instruction"
+        );
+        assert!(jump_to(3).is_err());
+        assert!(jump_to(6).is_err());
+
+        let program = ProgramParser::new(vec![op(Operation::Push, &[Variant::Direct]), 5])
+            .parse()
+            .unwrap();
+        assert!(program.verify_targets(0).is_ok());
+        assert_eq!(
+            program.verify_targets(1).unwrap_err().to_string(),
+            "Entry point 0x1 is not the start of an instruction"
+        );
+    }
 
-        Ok(Action::new(
-            op.operation().unwrap(),
-            vec![Operand::new(self.advance().unwrap(), variants[0])],
-        ))
+    #[test]
+    fn garbage_words_are_errors() {
+        let error = |words: Vec<usize>| ProgramParser::new(words).parse().unwrap_err().to_string();
+        let halt = op(Operation::Halt, &[]);
+        assert_eq!(
+            error(vec![halt, usize::MAX]),
+            "Invalid opcode 0xffffffffffffffff at 1"
+        );
+        // An operation with a variant that doesn't exist
+        let push = op(Operation::Push, &[]) | (15 << 16);
+        assert_eq!(
+            error(vec![push, 1]),
+            format!("Invalid variants in opcode {:#x}", push)
+        );
+        assert_eq!(
+            error(vec![op(Operation::Push, &[Variant::Direct])]),
+            "Program ends before an operand"
+        );
     }
-    fn collect_two(&mut self, op: &OpCode) -> Result<Action> {
-        let variants = op
-            .variants()
-            .with_context(|| format!("Failed to collect variants"))?;
 
-        Ok(Action::new(
-            op.operation().unwrap(),
-            vec![
-                Operand::new(self.advance().unwrap(), variants[0]),
-                Operand::new(self.advance().unwrap(), variants[1]),
-            ],
-        ))
+    #[test]
+    fn operands_need_a_variant() {
+        assert!(Operand::from_variant_value(Variant::None, 3).is_err());
+        let operand = Operand::from_variant_value(Variant::Register, 3).unwrap();
+        assert_eq!((operand.variant, operand.value), (Variant::Register, 3));
     }
 }
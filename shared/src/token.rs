@@ -1,4 +1,9 @@
-#[derive(Debug, Clone)]
+use std::{fmt, num::IntErrorKind};
+
+use crate::error::CompileError;
+
+// Lines and columns start at 1, the end is exclusive and points just past the last character
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TokenSpan {
     pub file: String,
     pub start_line: usize,
@@ -23,6 +28,20 @@ impl TokenSpan {
             end_column,
         }
     }
+
+    pub fn end_line(&self) -> usize {
+        self.end_line
+    }
+
+    pub fn end_column(&self) -> usize {
+        self.end_column
+    }
+}
+
+impl fmt::Display for TokenSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.start_line, self.start_column)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -35,7 +54,10 @@ pub enum TokenType {
     RParen,
     LCurly,
     RCurly,
+    LBracket,
+    RBracket,
     Dollar,
+    Hash,
     Plus,
     Dash,
     Times,
@@ -44,7 +66,11 @@ pub enum TokenType {
     Percent,
     LessThan,
     GreaterThan,
+    NotEqual,
+    LessThanEqual,
+    GreaterThanEqual,
     Number,
+    Char,
     String,
     EoF,
 }
@@ -64,4 +90,24 @@ impl Token {
             value,
         }
     }
+
+    // The value of a Number or Char token. Numbers are decimal unless they start with 0x or 0b
+    pub fn number(&self) -> Result<usize, CompileError> {
+        let (digits, radix) = match self.value.get(..2) {
+            Some("0x") => (&self.value[2..], 16),
+            Some("0b") => (&self.value[2..], 2),
+            _ => (self.value.as_str(), 10),
+        };
+
+        usize::from_str_radix(digits, radix).map_err(|err| match err.kind() {
+            IntErrorKind::PosOverflow => CompileError::NumberTooLarge {
+                value: self.value.clone(),
+                span: self.span.clone(),
+            },
+            _ => CompileError::InvalidNumber {
+                value: self.value.clone(),
+                span: self.span.clone(),
+            },
+        })
+    }
 }
@@ -0,0 +1,18 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use vm::vm::VM;
+
+fn sum(c: &mut Criterion) {
+    let source = include_str!("sum.risp");
+    let compiled = risp::compile_source(source, "sum.risp", 1, false, None, &[]).unwrap();
+
+    c.bench_function("sum 1m", |b| {
+        b.iter(|| {
+            VM::new(compiled.program.clone(), compiled.entry)
+                .run()
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, sum);
+criterion_main!(benches);
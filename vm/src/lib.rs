@@ -1,5 +1,9 @@
 pub mod vm;
 
+// Helpers for tests here and in the crates built on the VM
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 // macro_rules! variants {
 //     () => {
 //         [Variant::None, Variant::None, Variant::None]
@@ -1,13 +1,45 @@
+use std::{collections::HashSet, io::Write};
+
 use anyhow::{anyhow, Result};
-use shared::instruction::{NativeFunctions, OpCode, Operation, Variant};
+use shared::{
+    instruction::{NativeFunctions, OpCode, Operation, Variant},
+    program::{Operand, ProgramParser},
+};
+
+// Generous, it's only there to stop runaway programs before they run out of memory
+pub const DEFAULT_MAX_STACK: usize = 1 << 24;
+// Same for alloc, in words with each allocation's length included
+pub const DEFAULT_MAX_MEMORY: usize = 1 << 24;
+
+// What add, sub, mult and div do with results that don't fit in a word. Values are two's
+// complement, so overflow is as signed numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Arithmetic {
+    #[default]
+    Wrapping,
+    Checked,    // overflowing is an error
+    Saturating, // clamps to isize::MIN and isize::MAX
+}
 
 pub struct VM {
     program: Vec<usize>,
 
     pc: usize,
+    instruction_pc: usize, // where the instruction currently running starts
     stack: Vec<usize>,
+    max_stack: usize,
+    max_memory: usize,
     call_stack: Vec<usize>,
     register: [usize; 10],
+    memory: Vec<usize>,
+    strings: Vec<usize>, // made while running, like by concat, see `string_at`
+    exit_code: usize,    // what main returned, 0 if it never did
+    arithmetic: Arithmetic,
+    trace: Option<Box<dyn Write>>, // gets a line for every instruction before it runs
+    coverage: Option<HashSet<usize>>, // where every instruction that ran starts
+    output: Box<dyn Write>,        // what the natives and dump write to, flushed every time
+    executed: usize,               // instructions started, including one that failed
+    histogram: Option<[usize; Operation::ALL.len()]>, // `executed` per operation, by value
 }
 
 impl VM {
@@ -15,12 +47,88 @@ impl VM {
         Self {
             program,
             pc: entry,
+            instruction_pc: entry,
             stack: vec![],
+            max_stack: DEFAULT_MAX_STACK,
+            max_memory: DEFAULT_MAX_MEMORY,
             call_stack: vec![],
-            register: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            register: [0; 10],
+            memory: vec![],
+            strings: vec![],
+            exit_code: 0,
+            arithmetic: Arithmetic::default(),
+            trace: None,
+            coverage: None,
+            output: Box::new(std::io::stdout()),
+            executed: 0,
+            histogram: None,
         }
     }
 
+    pub fn with_max_stack(mut self, max_stack: usize) -> Self {
+        self.max_stack = max_stack;
+        self
+    }
+
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+
+    pub fn with_arithmetic(mut self, arithmetic: Arithmetic) -> Self {
+        self.arithmetic = arithmetic;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Box<dyn Write>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    // Stdout by default
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    // For anything written after the program, so that it ends up in the same place
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(HashSet::new());
+        self
+    }
+
+    // Offsets of the instructions that have run, None unless enabled with `with_coverage`
+    pub fn coverage(&self) -> Option<&HashSet<usize>> {
+        self.coverage.as_ref()
+    }
+
+    pub fn with_histogram(mut self) -> Self {
+        self.histogram = Some([0; Operation::ALL.len()]);
+        self
+    }
+
+    // Instructions executed so far, halt included
+    pub fn instruction_count(&self) -> usize {
+        self.executed
+    }
+
+    // How many times each operation has executed, in order of value. None unless enabled with
+    // `with_histogram`. The counts add up to `instruction_count`
+    pub fn op_histogram(&self) -> Option<Vec<(Operation, usize)>> {
+        self.histogram
+            .map(|histogram| Operation::iter().zip(histogram).collect())
+    }
+
+    // Set when the program stops by returning from its entry point, like from main, to the
+    // value it returned, or by calling exit to its argument. Halting leaves it at 0
+    pub fn exit_code(&self) -> usize {
+        self.exit_code
+    }
+
     fn advance(&mut self) -> Option<usize> {
         self.pc += 1;
         match self.program.get(self.pc - 1) {
@@ -29,53 +137,124 @@ impl VM {
         }
     }
 
-    pub fn step(&mut self) -> bool {
+    // Returns whether the program should keep running
+    pub fn step(&mut self) -> Result<bool> {
+        self.instruction_pc = self.pc;
         let opcode = OpCode::from_usize(match self.advance() {
-            None => return false,
+            None => return Ok(false),
             Some(value) => value,
         });
 
-        match opcode.operation() {
-            Some(Operation::Nop) => {}
-            Some(Operation::Push) => self.op_push(&opcode),
-            Some(Operation::Pop) => self.op_pop(),
-            Some(Operation::Add) => self.op_add(),
-            Some(Operation::Mult) => self.op_mult(),
-            Some(Operation::Sub) => self.op_sub(),
-            Some(Operation::Div) => self.op_div(),
-            Some(Operation::Mod) => self.op_mod(),
-            Some(Operation::Mov) => self.op_mov(&opcode),
-            Some(Operation::Dup) => self.op_dup(&opcode),
-            Some(Operation::Jmp) => self.op_jmp(&opcode, Operation::Jmp),
-            Some(Operation::JmpIf) => self.op_jmp(&opcode, Operation::JmpIf),
-            Some(Operation::CmpEq) => self.op_cmp(&opcode, Operation::CmpEq),
-            Some(Operation::CmpNe) => self.op_cmp(&opcode, Operation::CmpNe),
-            Some(Operation::CmpGt) => self.op_cmp(&opcode, Operation::CmpGt),
-            Some(Operation::CmpLt) => self.op_cmp(&opcode, Operation::CmpLt),
-            Some(Operation::CmpGte) => self.op_cmp(&opcode, Operation::CmpGte),
-            Some(Operation::CmpLte) => self.op_cmp(&opcode, Operation::CmpLte),
-            Some(Operation::Call) => return self.op_call(&opcode),
-            Some(Operation::Ret) => self.op_ret(),
-            Some(Operation::Not) => self.op_not(),
-            Some(Operation::Swap) => self.op_swap(),
-            Some(other) => {
-                todo!("Opcode {:?} not implemented", other)
+        // Decoded once here, handlers get the variants they need passed in
+        let (operation, variants) = match opcode.split() {
+            Some(split) => split,
+            None => {
+                return Err(anyhow!(
+                    "Invalid opcode {:#x} at pc={}",
+                    opcode.as_usize(),
+                    self.instruction_pc
+                ))
             }
-            None => panic!("Invalid opcode {:?}", opcode),
+        };
+
+        if self.trace.is_some() {
+            self.write_trace(operation)?;
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.insert(self.instruction_pc);
+        }
+        self.executed += 1;
+        if let Some(histogram) = &mut self.histogram {
+            histogram[operation as usize] += 1;
         }
 
-        true
+        match operation {
+            Operation::Nop => {}
+            Operation::Push => {
+                self.check_stack_space(1)?;
+                self.op_push(variants[0])?
+            }
+            Operation::Pop => self.op_pop(),
+            Operation::Add => self.op_add()?,
+            Operation::Mult => self.op_mult()?,
+            Operation::Sub => self.op_sub()?,
+            Operation::Div => self.op_div()?,
+            Operation::Mod => self.op_mod()?,
+            Operation::And | Operation::Or | Operation::Xor => self.op_bitwise(operation)?,
+            Operation::Mov => self.op_mov(variants[0], variants[1])?,
+            Operation::Dup => {
+                self.check_stack_space(1)?;
+                self.op_dup(variants[0])?
+            }
+            Operation::Jmp | Operation::JmpIf | Operation::JmpIfNot => {
+                self.op_jmp(variants[0], operation)?
+            }
+            Operation::CmpEq
+            | Operation::CmpNe
+            | Operation::CmpGt
+            | Operation::CmpLt
+            | Operation::CmpGte
+            | Operation::CmpLte
+            | Operation::Cmp => self.op_cmp(operation)?,
+            Operation::CmpStrEq => self.op_cmp_str_eq()?,
+            Operation::Call => {
+                // Natives push their return value
+                if variants[0] == Variant::Native {
+                    self.check_stack_space(1)?;
+                }
+                return self.op_call(variants[0]);
+            }
+            Operation::Ret => return Ok(self.op_ret()),
+            Operation::Not => self.op_not()?,
+            Operation::Swap => self.op_swap(variants[0])?,
+            Operation::Halt => return Ok(false),
+            Operation::Alloc => self.op_alloc()?,
+            Operation::Load => self.op_load()?,
+            Operation::Store => self.op_store()?,
+            Operation::PushN => self.op_push_n()?,
+            Operation::PopN => self.op_pop_n()?,
+        }
+
+        Ok(true)
     }
 
-    pub fn run(&mut self) {
+    // Only the words of the current instruction are decoded, push_n being the only one whose
+    // length depends on its operands
+    #[cold]
+    fn write_trace(&mut self, operation: Operation) -> Result<()> {
+        let pc = self.instruction_pc;
+        let len = match operation {
+            Operation::PushN => 2 + self.program.get(pc + 1).copied().unwrap_or(0),
+            _ => 3,
+        };
+        let words = &self.program[pc..self.program.len().min(pc.saturating_add(len))];
+        let action = match ProgramParser::new(words.to_vec()).step() {
+            Ok(action) => action.format(),
+            Err(_) => "?".to_string(),
+        };
+
+        if let Some(trace) = &mut self.trace {
+            writeln!(
+                trace,
+                "{:#x}: {} ; depth {}",
+                pc,
+                action.trim_end(),
+                self.stack.len()
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
         loop {
-            if !self.step() {
+            if !self.step()? {
                 break;
             }
         }
+        Ok(())
     }
 
-    pub fn run_max(&mut self, max: usize) {
+    pub fn run_max(&mut self, max: usize) -> Result<()> {
         let mut steps = 0;
         loop {
             steps += 1;
@@ -83,136 +262,360 @@ impl VM {
                 break;
             }
 
-            if !self.step() {
+            if !self.step()? {
                 break;
             }
         }
+        Ok(())
     }
 
-    fn value_from_variant(&self, variant: Variant, value: usize) -> Result<usize> {
-        match variant {
-            Variant::Direct => Ok(value),
-            Variant::Register => Ok(self.register[value]),
-            Variant::Stack => Ok(self.stack[self.stack.len() - (value + 1)]),
-            Variant::StackAbsoulute => Ok(self.stack[value]),
+    // Pops N values for `operation`, the first pushed value first
+    fn pop_operands<const N: usize>(&mut self, operation: Operation) -> Result<[usize; N]> {
+        let len = self.stack.len();
+        if len < N {
+            return Err(self.underflow(operation, N));
+        }
+
+        let mut values = [0; N];
+        for value in values.iter_mut().rev() {
+            *value = self.stack.pop().unwrap();
+        }
+        Ok(values)
+    }
+
+    // Called before anything that grows the stack, with how many values it adds
+    #[inline]
+    fn check_stack_space(&self, needed: usize) -> Result<()> {
+        if needed > self.max_stack - self.stack.len() {
+            return Err(anyhow!(
+                "stack overflow (limit {}) at pc={}",
+                self.max_stack,
+                self.instruction_pc
+            ));
+        }
+        Ok(())
+    }
+
+    // Kept out of line so that the error formatting doesn't slow down the handlers
+    #[cold]
+    fn underflow(&self, operation: Operation, needed: usize) -> anyhow::Error {
+        anyhow!(
+            "{:?} needs {} stack values, found {} at pc={}",
+            operation,
+            needed,
+            self.stack.len(),
+            self.instruction_pc
+        )
+    }
+
+    // Reads the next word as an operand of the current instruction
+    fn next_operand(&mut self, variant: Variant) -> Result<Operand> {
+        let value = self
+            .advance()
+            .ok_or_else(|| anyhow!("Missing operand at pc={}", self.instruction_pc))?;
+        Operand::from_variant_value(variant, value)
+    }
+
+    // Every variant that's `is_readable` has a value here
+    fn read_operand(&self, operand: &Operand) -> Result<usize> {
+        match operand.variant {
+            Variant::Direct => Ok(operand.value),
+            Variant::Register => Ok(self.register[self.register_index(operand.value)?]),
+            Variant::Stack | Variant::StackAbsolute => {
+                Ok(self.stack[self.stack_index(operand.variant, operand.value)?])
+            }
             other => Err(anyhow!("Can't get value from variant {:?}", other)),
         }
     }
 
-    pub fn dump(&self) {
-        self.dump_stack();
-        println!("");
-        self.dump_registers();
-        println!("");
-        self.dump_call_stack();
+    fn register_index(&self, value: usize) -> Result<usize> {
+        if value < self.register.len() {
+            Ok(value)
+        } else {
+            Err(anyhow!(
+                "Register r({}) doesn't exist, there are {} at pc={}",
+                value,
+                self.register.len(),
+                self.instruction_pc
+            ))
+        }
+    }
+
+    // s(n) counts from the top and sa(n) from the bottom, both as the stack is when the
+    // instruction starts. Operands come from the program, so nothing has been popped by then
+    fn stack_index(&self, variant: Variant, value: usize) -> Result<usize> {
+        let len = self.stack.len();
+        let index = match variant {
+            Variant::Stack => value.checked_add(1).and_then(|v| len.checked_sub(v)),
+            Variant::StackAbsolute => Some(value).filter(|&v| v < len),
+            other => return Err(anyhow!("{:?} isn't a stack variant", other)),
+        };
+
+        index.ok_or_else(|| {
+            anyhow!(
+                "{:?}({}) is outside the stack of {} values at pc={}",
+                variant,
+                value,
+                len,
+                self.instruction_pc
+            )
+        })
     }
 
-    pub fn dump_stack(&self) {
-        println!("Stack:");
-        if self.stack.len() == 0 {
-            println!("  Empty");
+    pub fn stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn program(&self) -> &[usize] {
+        &self.program
+    }
+
+    // Written to the output, after whatever the program printed
+    pub fn dump(&mut self) -> Result<()> {
+        let dump = [
+            self.format_stack(),
+            self.format_registers(),
+            self.format_call_stack(),
+            self.format_memory(),
+        ]
+        .join("\n");
+        write!(self.output, "{}", dump)?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    // Same state as `dump`, for tools that want to read it
+    pub fn to_json(&self) -> String {
+        let list = |values: &[usize]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+
+        format!(
+            "{{\"pc\":{},\"stack\":[{}],\"registers\":[{}],\"call_stack\":[{}],\"memory\":[{}]}}",
+            self.pc,
+            list(&self.stack),
+            list(&self.register),
+            list(&self.call_stack),
+            list(&self.memory)
+        )
+    }
+
+    // Shared by dump and the trace native
+    pub fn format_stack(&self) -> String {
+        let mut res = String::from("Stack:\n");
+        if self.stack.is_empty() {
+            res.push_str("  Empty\n");
         }
         for (i, value) in self.stack.iter().enumerate() {
-            println!("  {}: {}", i, value);
+            res.push_str(&format!("  {}: {}\n", i, value));
         }
+        res
     }
 
-    pub fn dump_registers(&self) {
-        println!("Registers:");
+    pub fn format_registers(&self) -> String {
+        let mut res = String::from("Registers:\n");
         for (i, value) in self.register.iter().enumerate() {
-            println!("  r{}: {}", i, value);
+            res.push_str(&format!("  r{}: {}\n", i, value));
         }
+        res
     }
 
-    pub fn dump_call_stack(&self) {
-        println!("Call Stack:");
-        if self.call_stack.len() == 0 {
-            println!("  Empty");
+    pub fn format_call_stack(&self) -> String {
+        let mut res = String::from("Call Stack:\n");
+        if self.call_stack.is_empty() {
+            res.push_str("  Empty\n");
         }
         for (i, value) in self.call_stack.iter().enumerate() {
-            println!("  {}: {}", i, value);
+            res.push_str(&format!("  {}: {}\n", i, value));
+        }
+        res
+    }
+
+    pub fn format_memory(&self) -> String {
+        let mut res = String::from("Memory:\n");
+        if self.memory.is_empty() {
+            res.push_str("  Empty\n");
+        }
+        for (i, value) in self.memory.iter().enumerate() {
+            res.push_str(&format!("  {}: {}\n", i, value));
         }
+        res
     }
 
-    fn op_add(&mut self) {
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
-        self.stack.push(lhs + rhs);
+    // Applies whichever of the three versions of an operation the arithmetic mode asks for
+    #[inline]
+    fn arithmetic(
+        &self,
+        operation: Operation,
+        [lhs, rhs]: [usize; 2],
+        wrapping: fn(usize, usize) -> usize,
+        checked: fn(isize, isize) -> Option<isize>,
+        saturating: fn(isize, isize) -> isize,
+    ) -> Result<usize> {
+        match self.arithmetic {
+            Arithmetic::Wrapping => Ok(wrapping(lhs, rhs)),
+            Arithmetic::Checked => match checked(lhs as isize, rhs as isize) {
+                Some(value) => Ok(value as usize),
+                None => Err(anyhow!(
+                    "{:?} overflowed at pc={}",
+                    operation,
+                    self.instruction_pc
+                )),
+            },
+            Arithmetic::Saturating => Ok(saturating(lhs as isize, rhs as isize) as usize),
+        }
+    }
+
+    fn op_add(&mut self) -> Result<()> {
+        let operands = self.pop_operands(Operation::Add)?;
+        let value = self.arithmetic(
+            Operation::Add,
+            operands,
+            usize::wrapping_add,
+            isize::checked_add,
+            isize::saturating_add,
+        )?;
+        self.stack.push(value);
+        Ok(())
+    }
+    fn op_mult(&mut self) -> Result<()> {
+        let operands = self.pop_operands(Operation::Mult)?;
+        let value = self.arithmetic(
+            Operation::Mult,
+            operands,
+            usize::wrapping_mul,
+            isize::checked_mul,
+            isize::saturating_mul,
+        )?;
+        self.stack.push(value);
+        Ok(())
     }
-    fn op_mult(&mut self) {
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
-        self.stack.push(lhs * rhs);
+    fn op_sub(&mut self) -> Result<()> {
+        let operands = self.pop_operands(Operation::Sub)?;
+        let value = self.arithmetic(
+            Operation::Sub,
+            operands,
+            usize::wrapping_sub,
+            isize::checked_sub,
+            isize::saturating_sub,
+        )?;
+        self.stack.push(value);
+        Ok(())
     }
-    fn op_sub(&mut self) {
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
-        self.stack.push(lhs - rhs);
+    // Rounds towards zero. isize::MIN / -1 is the only one that overflows
+    fn op_div(&mut self) -> Result<()> {
+        let operands = self.pop_operands(Operation::Div)?;
+        self.check_divisor(Operation::Div, operands)?;
+        let value = self.arithmetic(
+            Operation::Div,
+            operands,
+            |lhs, rhs| (lhs as isize).wrapping_div(rhs as isize) as usize,
+            isize::checked_div,
+            isize::saturating_div,
+        )?;
+        self.stack.push(value);
+        Ok(())
     }
-    fn op_div(&mut self) {
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
-        self.stack.push(lhs / rhs);
+    // Division by zero is an error whatever the arithmetic mode
+    fn check_divisor(&self, operation: Operation, [_, rhs]: [usize; 2]) -> Result<()> {
+        if rhs == 0 {
+            return Err(anyhow!(
+                "{:?} by zero at pc={}",
+                operation,
+                self.instruction_pc
+            ));
+        }
+        Ok(())
     }
-    fn op_mod(&mut self) {
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
-        self.stack.push(lhs % rhs);
+    // Has the sign of lhs. isize::MIN % -1 is 0 in every mode, nothing is out of range
+    fn op_mod(&mut self) -> Result<()> {
+        let operands = self.pop_operands(Operation::Mod)?;
+        self.check_divisor(Operation::Mod, operands)?;
+        let value = self.arithmetic(
+            Operation::Mod,
+            operands,
+            |lhs, rhs| (lhs as isize).wrapping_rem(rhs as isize) as usize,
+            |lhs, rhs| Some(lhs.wrapping_rem(rhs)),
+            isize::wrapping_rem,
+        )?;
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn op_push(&mut self, op: &OpCode) {
-        let variant = op.variants().unwrap()[0];
-        match variant {
-            Variant::Direct => {
-                let value = self.advance().unwrap();
-                self.stack.push(value)
-            }
-            Variant::Register => {
-                let value = self.advance().unwrap();
-                self.stack.push(self.register[value as usize])
-            }
-            Variant::Stack => {
-                let value = self.advance().unwrap();
-                let len = self.stack.len();
-                self.stack.push(self.stack[len - (value + 1)]);
-            }
-            Variant::StackAbsoulute => {
-                let value = self.advance().unwrap();
-                self.stack.push(self.stack[value as usize])
-            }
-            other => panic!("Invalid push variant ({:?})", other),
+    fn op_bitwise(&mut self, operation: Operation) -> Result<()> {
+        let [lhs, rhs] = self.pop_operands(operation)?;
+        let value = match operation {
+            Operation::And => lhs & rhs,
+            Operation::Or => lhs | rhs,
+            Operation::Xor => lhs ^ rhs,
+            other => panic!("{:?} isn't a bitwise operation", other),
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn op_push(&mut self, variant: Variant) -> Result<()> {
+        let operand = self.next_operand(variant)?;
+        let value = self.read_operand(&operand)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn op_push_n(&mut self) -> Result<()> {
+        let count = self.next_operand(Variant::Direct)?.value;
+        self.check_stack_space(count)?;
+        for _ in 0..count {
+            let value = self.next_operand(Variant::Direct)?.value;
+            self.stack.push(value);
         }
+        Ok(())
     }
 
-    fn op_mov(&mut self, op: &OpCode) {
-        let where_variant = op.variants().unwrap()[0];
-        let where_value = self.advance().unwrap();
+    fn op_pop_n(&mut self) -> Result<()> {
+        let count = self.next_operand(Variant::Direct)?.value;
+        if count > self.stack.len() {
+            return Err(self.underflow(Operation::PopN, count));
+        }
+        self.stack.truncate(self.stack.len() - count);
+        Ok(())
+    }
 
-        let what_variant = op.variants().unwrap()[1];
-        let what_value = self.advance().unwrap();
+    // The source and destination are both read before anything changes, so `mov s(1), s(0)`
+    // copies the top over the value below it
+    fn op_mov(&mut self, where_variant: Variant, what_variant: Variant) -> Result<()> {
+        let r#where = self.next_operand(where_variant)?;
+        let what = self.next_operand(what_variant)?;
 
-        let what = self.value_from_variant(what_variant, what_value).unwrap();
+        let what = self.read_operand(&what)?;
 
-        match where_variant {
+        // Every variant that's `is_writable`
+        match r#where.variant {
             Variant::Register => {
-                self.register[where_value] = what;
-            }
-            Variant::Stack => {
-                let len = self.stack.len();
-                self.stack[len - (where_value + 1)] = what;
+                let index = self.register_index(r#where.value)?;
+                self.register[index] = what;
             }
-            Variant::StackAbsoulute => {
-                self.stack[where_value] = what;
+            Variant::Stack | Variant::StackAbsolute => {
+                let index = self.stack_index(r#where.variant, r#where.value)?;
+                self.stack[index] = what;
             }
-            other => panic!("Invalid mov variant ({:?})", other),
+            other => return Err(anyhow!("Invalid mov destination {:?}", other)),
         }
+        Ok(())
     }
 
     fn op_pop(&mut self) {
         self.stack.pop();
     }
 
-    fn op_cmp(&mut self, op: &OpCode, operation: Operation) {
+    fn op_cmp(&mut self, operation: Operation) -> Result<()> {
         // let v = self.advance().unwrap();
         // let lhs = self
         //     .value_from_variant(op.variants().unwrap()[0], v)
@@ -223,8 +626,9 @@ impl VM {
         //     .value_from_variant(op.variants().unwrap()[1], v)
         //     .unwrap();
 
-        let rhs = self.stack.pop().unwrap();
-        let lhs = self.stack.pop().unwrap();
+        // Values are two's complement, so ordering is signed
+        let [lhs, rhs] = self.pop_operands(operation)?;
+        let (lhs, rhs) = (lhs as isize, rhs as isize);
 
         match operation {
             Operation::CmpEq => {
@@ -245,80 +649,866 @@ impl VM {
             Operation::CmpLte => {
                 self.stack.push((lhs <= rhs) as usize);
             }
+            Operation::Cmp => {
+                self.stack.push(lhs.cmp(&rhs) as isize as usize);
+            }
             other => panic!("{:?} isn't a cmp operation", other),
         }
+        Ok(())
     }
 
-    fn op_dup(&mut self, op: &OpCode) {
-        let variant = op.variants().unwrap()[0];
-        match variant {
-            Variant::Stack => {
-                let value = self.advance().unwrap();
-                if self.stack.len() == 0 {
-                    panic!("No elements in stack");
-                }
-                self.stack.push(self.stack[self.stack.len() - (value + 1)])
+    // Strings are stored in the program as their length followed by one word per character and
+    // are referenced by the address of the length. Two strings are equal if their contents are,
+    // no matter where they are stored
+    fn op_cmp_str_eq(&mut self) -> Result<()> {
+        let [lhs, rhs] = self.pop_operands(Operation::CmpStrEq)?;
+        let res = self.string_at(lhs)? == self.string_at(rhs)?;
+        self.stack.push(res as usize);
+        Ok(())
+    }
+
+    // Strings are their length followed by one word per character and are referenced by the
+    // address of the length. Literals are in the program, the ones made while running are
+    // addressed as if they came right after it, which is never executed since running past the
+    // end stops. Neither kind ever changes or is freed
+    fn string_at(&self, addr: usize) -> Result<&[usize]> {
+        let (words, start) = match addr.checked_sub(self.program.len()) {
+            Some(start) => (&self.strings, start),
+            None => (&self.program, addr),
+        };
+        words
+            .get(start)
+            .and_then(|&len| words.get(start + 1..start.checked_add(len)?.checked_add(1)?))
+            .ok_or_else(|| anyhow!("No string at {} at pc={}", addr, self.instruction_pc))
+    }
+
+    // Returns the address of the new string
+    fn new_string(&mut self, chars: Vec<usize>) -> usize {
+        let addr = self.program.len() + self.strings.len();
+        self.strings.push(chars.len());
+        self.strings.extend(chars);
+        addr
+    }
+
+    // Allocations are stored in memory as their length followed by the elements and are
+    // referenced by the address of the length. Memory is never freed
+    fn op_alloc(&mut self) -> Result<()> {
+        let [len] = self.pop_operands(Operation::Alloc)?;
+        let addr = self.memory.len();
+        let end = (addr + 1)
+            .checked_add(len)
+            .filter(|&end| end <= self.max_memory)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Can't allocate {} values (limit {}) at pc={}",
+                    len,
+                    self.max_memory,
+                    self.instruction_pc
+                )
+            })?;
+        self.memory.push(len);
+        self.memory.resize(end, 0);
+        self.stack.push(addr);
+        Ok(())
+    }
+
+    // [addr, index] -> [value]
+    fn op_load(&mut self) -> Result<()> {
+        let [addr, index] = self.pop_operands(Operation::Load)?;
+        let element = self.element_addr(addr, index)?;
+        self.stack.push(self.memory[element]);
+        Ok(())
+    }
+
+    // [addr, index, value] -> []
+    fn op_store(&mut self) -> Result<()> {
+        let [addr, index, value] = self.pop_operands(Operation::Store)?;
+        let element = self.element_addr(addr, index)?;
+        self.memory[element] = value;
+        Ok(())
+    }
+
+    // `addr` has to hold a length that fits in memory, whether alloc returned it isn't tracked
+    fn element_addr(&self, addr: usize, index: usize) -> Result<usize> {
+        let len = match self.memory.get(addr) {
+            Some(&len) if len < self.memory.len() - addr => len,
+            _ => {
+                return Err(anyhow!(
+                    "No allocation at {} at pc={}",
+                    addr,
+                    self.instruction_pc
+                ))
             }
-            other => panic!("Invalid dup variant ({:?})", other),
+        };
+        if index >= len {
+            return Err(anyhow!(
+                "Index {} out of bounds for length {} at pc={}",
+                index,
+                len,
+                self.instruction_pc
+            ));
         }
+        Ok(addr + 1 + index)
+    }
+
+    // Pushes a copy of the operand, so with a number it's the same as push
+    fn op_dup(&mut self, variant: Variant) -> Result<()> {
+        let operand = self.next_operand(variant)?;
+        let value = self.read_operand(&operand)?;
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn op_call(&mut self, op: &OpCode) -> bool {
-        let value = self.advance().unwrap();
-        let variant = op.variants().unwrap()[0];
+    fn op_call(&mut self, variant: Variant) -> Result<bool> {
+        let operand = self.next_operand(variant)?;
+        let value = operand.value;
         match variant {
             Variant::Direct => {
                 self.call_stack.push(self.pc); // 0 current, +1 is operand, +2 next
                 self.pc = value;
             }
-            Variant::Native => {
-                if value == NativeFunctions::Print as usize {
-                    println!("{}", self.stack[self.stack.len() - 1]);
+            Variant::Register | Variant::Stack | Variant::StackAbsolute => {
+                let addr = self.read_operand(&operand)?;
+                self.call_stack.push(self.pc);
+                self.pc = addr;
+            }
+            Variant::Native => match NativeFunctions::from_usize(value) {
+                Some(NativeFunctions::Print) => {
+                    // Values are two's complement, so print them as signed
+                    let value = self.native_arg(1, 0)? as isize;
+                    writeln!(self.output, "{}", value)?;
+                    self.output.flush()?;
                     // println!("{}", self.stack.pop().unwrap());
-                    self.stack.push(0); // all functions must return something
+                    self.stack.push(0); // see NativeFunctions::returns_value
+                }
+                Some(NativeFunctions::PrintHex) => {
+                    let value = self.native_arg(1, 0)?;
+                    writeln!(self.output, "{:#x}", value)?;
+                    self.output.flush()?;
+                    self.stack.push(0);
+                }
+                Some(NativeFunctions::Exit) => {
+                    // The code is the argument, an empty stack exits with 0
+                    self.exit_code = self.stack.last().copied().unwrap_or(0);
+                    return Ok(false);
+                }
+                Some(NativeFunctions::Pow) => {
+                    let base = self.native_arg(2, 0)?;
+                    // Negative exponents have no integer result, and anything past u32::MAX
+                    // overflows for every base but 0, 1 and -1
+                    let exp = self.native_arg(2, 1)?;
+                    let exp = u32::try_from(exp).map_err(|_| {
+                        anyhow!(
+                            "pow exponent {} is out of range at pc={}",
+                            exp as isize,
+                            self.instruction_pc
+                        )
+                    })?;
+                    self.stack.push(base.wrapping_pow(exp));
+                }
+                Some(NativeFunctions::Abs) => {
+                    let value = self.native_arg(1, 0)? as isize;
+                    self.stack.push(value.wrapping_abs() as usize);
+                }
+                Some(NativeFunctions::Min) => {
+                    let lhs = self.native_arg(2, 0)? as isize;
+                    let rhs = self.native_arg(2, 1)? as isize;
+                    self.stack.push(lhs.min(rhs) as usize);
+                }
+                Some(NativeFunctions::Max) => {
+                    let lhs = self.native_arg(2, 0)? as isize;
+                    let rhs = self.native_arg(2, 1)? as isize;
+                    self.stack.push(lhs.max(rhs) as usize);
+                }
+                Some(NativeFunctions::StackDepth) => self.stack.push(self.stack.len()),
+                Some(NativeFunctions::Trace) => {
+                    let stack = self.format_stack();
+                    write!(self.output, "{}", stack)?;
+                    self.output.flush()?;
+                    self.stack.push(0);
                 }
-                if value == NativeFunctions::Exit as usize {
-                    return false;
+                Some(NativeFunctions::Assert) => {
+                    if self.native_arg(1, 0)? == 0 {
+                        return Err(anyhow!("assertion failed at pc={}", self.instruction_pc));
+                    }
+                    self.stack.push(0);
                 }
+                Some(NativeFunctions::StrLen) => {
+                    let string = self.native_arg(1, 0)?;
+                    let len = self.string_at(string)?.len();
+                    self.stack.push(len);
+                }
+                Some(NativeFunctions::Concat) => {
+                    let lhs = self.native_arg(2, 0)?;
+                    let rhs = self.native_arg(2, 1)?;
+                    let chars = [self.string_at(lhs)?, self.string_at(rhs)?].concat();
+                    let addr = self.new_string(chars);
+                    self.stack.push(addr);
+                }
+                Some(NativeFunctions::PrintStr) => {
+                    let string = self.native_arg(1, 0)?;
+                    let string: String = self
+                        .string_at(string)?
+                        .iter()
+                        .map(|&c| {
+                            u32::try_from(c)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .unwrap_or(char::REPLACEMENT_CHARACTER)
+                        })
+                        .collect();
+                    writeln!(self.output, "{}", string)?;
+                    self.output.flush()?;
+                    self.stack.push(0);
+                }
+                None => {
+                    return Err(anyhow!(
+                        "Unknown native function {} at pc={}",
+                        value,
+                        self.instruction_pc
+                    ))
+                }
+            },
+            other => {
+                return Err(anyhow!(
+                    "Invalid call variant {:?} at pc={}",
+                    other,
+                    self.instruction_pc
+                ))
             }
-            _ => panic!("Invalid call variant {:?}", variant),
         }
 
-        return true;
+        return Ok(true);
     }
 
-    fn op_ret(&mut self) {
-        self.pc = self.call_stack.pop().unwrap();
+    // Arguments are left on the stack for the caller to clean up, so natives only read them.
+    // `index` counts from the first pushed argument
+    fn native_arg(&self, count: usize, index: usize) -> Result<usize> {
+        if self.stack.len() < count {
+            return Err(self.underflow(Operation::Call, count));
+        }
+        Ok(self.stack[self.stack.len() - count + index])
     }
 
-    fn op_not(&mut self) {
-        let res = self.stack.pop().unwrap() == 0;
-        self.stack.push(res as usize);
+    // Returning with nothing to return to, like from main, stops the program. The returned
+    // value is on top of the stack and becomes the exit code
+    fn op_ret(&mut self) -> bool {
+        match self.call_stack.pop() {
+            Some(pc) => {
+                self.pc = pc;
+                true
+            }
+            None => {
+                self.exit_code = self.stack.last().copied().unwrap_or(0);
+                false
+            }
+        }
     }
 
-    fn op_swap(&mut self) {
-        let first = self.stack.pop().unwrap();
-        let second = self.stack.pop().unwrap();
-        self.stack.push(first);
-        self.stack.push(second);
+    fn op_not(&mut self) -> Result<()> {
+        let [value] = self.pop_operands(Operation::Not)?;
+        self.stack.push((value == 0) as usize);
+        Ok(())
     }
 
-    fn op_jmp(&mut self, op: &OpCode, operation: Operation) {
-        let variant = op.variants().unwrap()[0];
-        let value = self.advance().unwrap();
+    // Swaps the top with the value `depth` below it, the top two without a depth
+    fn op_swap(&mut self, variant: Variant) -> Result<()> {
+        let depth = match variant {
+            Variant::Direct => self.next_operand(variant)?.value,
+            Variant::None => 1,
+            other => {
+                return Err(anyhow!(
+                    "Invalid swap depth {:?} at pc={}",
+                    other,
+                    self.instruction_pc
+                ))
+            }
+        };
+
+        let len = self.stack.len();
+        if len <= depth {
+            return Err(self.underflow(Operation::Swap, depth.saturating_add(1)));
+        }
+        self.stack.swap(len - 1, len - 1 - depth);
+        Ok(())
+    }
+
+    fn op_jmp(&mut self, variant: Variant, operation: Operation) -> Result<()> {
+        let operand = self.next_operand(variant)?;
 
         match operation {
             Operation::Jmp => {
-                self.pc = self.value_from_variant(variant, value).unwrap();
+                self.pc = self.read_operand(&operand)?;
             }
             Operation::JmpIf => {
-                let cond = self.stack.pop().unwrap();
+                let [cond] = self.pop_operands(operation)?;
                 if cond != 0 {
-                    let addr = self.value_from_variant(variant, value).unwrap();
-                    self.pc = addr;
+                    self.pc = self.read_operand(&operand)?;
+                }
+            }
+            Operation::JmpIfNot => {
+                let [cond] = self.pop_operands(operation)?;
+                if cond == 0 {
+                    self.pc = self.read_operand(&operand)?;
                 }
             }
             _ => panic!("Invalid jmp variant {:?}", variant),
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assembler::Assembler;
+    use shared::lexer::Lexer;
+
+    use super::*;
+    use crate::testing::Output;
+
+    fn vm(source: &str) -> (VM, Output) {
+        let mut asm = Assembler::new(Lexer::new(source, "test.rasm".to_string())).unwrap();
+        let program = asm.assemble().unwrap();
+        let output = Output::default();
+        let vm = VM::new(program, asm.entry()).with_output(Box::new(output.clone()));
+        (vm, output)
+    }
+
+    // Returns what was printed, or the error
+    fn run(source: &str) -> Result<String> {
+        let (mut vm, output) = vm(source);
+        vm.run()?;
+        Ok(output.text())
+    }
+
+    #[test]
+    fn print_is_signed() {
+        let output = run("push 0\npush 1\nsub\ncall $print\nhalt").unwrap();
+        assert_eq!(output, "-1\n");
+    }
+
+    #[test]
+    fn print_hex_shows_the_raw_word() {
+        let output =
+            run("push 0\npush 1\nsub\ncall $print_hex\npush 255\ncall $print_hex").unwrap();
+        assert_eq!(output, "0xffffffffffffffff\n0xff\n");
+    }
+
+    // Everything the natives write goes to the output, in order. The string is at 13, after the
+    // six 2 word instructions and halt
+    #[test]
+    fn natives_write_to_the_output() {
+        let source = "push 7\ncall $print\ncall $print_hex\n\
+                      push 13\ncall $print_str\ncall $trace\nhalt\n.string \"hi\"";
+        let (mut machine, output) = vm(source);
+        machine.run().unwrap();
+        assert_eq!(
+            output.text(),
+            "7\n0x0\nhi\nStack:\n  0: 7\n  1: 0\n  2: 0\n  3: 13\n  4: 0\n"
+        );
+    }
+
+    // .double is at 10, after the 3 word mov and three 2 word instructions and halt
+    #[test]
+    fn call_through_a_register() {
+        let source =
+            "mov r(0), 10\npush 21\ncall r(0)\ncall $print\nhalt\n.double:\npush 2\nmult\nret";
+        assert_eq!(run(source).unwrap(), "42\n");
+    }
+
+    #[test]
+    fn call_through_the_stack() {
+        let source = "push 9\npush 21\ncall s(1)\ncall $print\nhalt\n.double:\npush 2\nmult\nret";
+        assert_eq!(run(source).unwrap(), "42\n");
+    }
+
+    fn stack(source: &str) -> Vec<usize> {
+        let (mut vm, _) = vm(source);
+        vm.run().unwrap();
+        vm.stack
+    }
+
+    #[test]
+    fn stack_is_relative_to_the_top() {
+        assert_eq!(
+            stack("push 1\npush 2\npush 3\npush s(0)\npush s(3)"),
+            [1, 2, 3, 3, 1]
+        );
+        assert_eq!(stack("push 1\npush 2\nmov s(1), 7"), [7, 2]);
+    }
+
+    #[test]
+    fn stack_absolute_is_from_the_bottom() {
+        assert_eq!(
+            stack("push 1\npush 2\npush 3\npush sa(0)\npush sa(3)"),
+            [1, 2, 3, 1, 1]
+        );
+        assert_eq!(stack("push 1\npush 2\nmov sa(1), 7"), [1, 7]);
+    }
+
+    #[test]
+    fn halt_stops_before_what_follows() {
+        assert_eq!(
+            run("push 1\ncall $print\nhalt\npush 2\ncall $print").unwrap(),
+            "1\n"
+        );
+        assert_eq!(stack("push 1\nhalt\npush 2"), [1]);
+    }
+
+    #[test]
+    fn memory_errors_instead_of_panicking() {
+        assert_eq!(
+            stack("push 2\nalloc\npush 1\npush 7\nstore\npush 0\npush 1\nload"),
+            [7]
+        );
+        let error = run("load").unwrap_err().to_string();
+        assert!(error.starts_with("Load needs 2 stack values"), "{}", error);
+        let error = run("push 5\npush 0\nload").unwrap_err().to_string();
+        assert_eq!(error, "No allocation at 5 at pc=4");
+        let error = run("push 0\npush 1\nsub\nalloc").unwrap_err().to_string();
+        assert!(error.starts_with("Can't allocate"), "{}", error);
+    }
+
+    #[test]
+    fn oversized_alloc_is_an_error() {
+        assert_eq!(
+            error("push 99999999999\nalloc"),
+            format!(
+                "Can't allocate 99999999999 values (limit {}) at pc=2",
+                DEFAULT_MAX_MEMORY
+            )
+        );
+
+        // The lengths count towards the limit
+        let (limited, _) = vm("push 3\nalloc\npush 3\nalloc");
+        let error = limited.with_max_memory(7).run().unwrap_err().to_string();
+        assert_eq!(error, "Can't allocate 3 values (limit 7) at pc=5");
+        let (limited, _) = vm("push 3\nalloc\npush 2\nalloc");
+        assert!(limited.with_max_memory(7).run().is_ok());
+    }
+
+    fn error(source: &str) -> String {
+        run(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn underflow_names_the_operation() {
+        assert_eq!(
+            error("push 1\nadd"),
+            "Add needs 2 stack values, found 1 at pc=2"
+        );
+        assert_eq!(
+            error("cmp_lt"),
+            "CmpLt needs 2 stack values, found 0 at pc=0"
+        );
+        assert_eq!(
+            error("push 1\nswap"),
+            "Swap needs 2 stack values, found 1 at pc=2"
+        );
+        assert_eq!(
+            error("push 1\npush 2\nswap 3"),
+            "Swap needs 4 stack values, found 2 at pc=4"
+        );
+    }
+
+    #[test]
+    fn jmp_if_not_jumps_on_zero_and_consumes_it() {
+        assert_eq!(stack("push 0\njmp_if_not .end\npush 1\n.end:\npush 2"), [2]);
+        assert_eq!(
+            stack("push 5\njmp_if_not .end\npush 1\n.end:\npush 2"),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn jmp_if_consumes_its_condition() {
+        assert_eq!(stack("push 1\njmp_if .end\npush 1\n.end:\npush 2"), [2]);
+        assert_eq!(stack("push 0\njmp_if .end\npush 1\n.end:\npush 2"), [1, 2]);
+        assert_eq!(
+            error("jmp_if 0"),
+            "JmpIf needs 1 stack values, found 0 at pc=0"
+        );
+    }
+
+    #[test]
+    fn stack_depth_counts_what_was_pushed() {
+        assert_eq!(stack("call $stack_depth"), [0]);
+        assert_eq!(
+            stack("push 7\npush 8\npush 9\ncall $stack_depth"),
+            [7, 8, 9, 3]
+        );
+    }
+
+    // cmp pushes the sign of lhs - rhs, lhs being pushed first
+    #[test]
+    fn cmp_is_three_way() {
+        assert_eq!(run("push 1\npush 2\ncmp\ncall $print").unwrap(), "-1\n");
+        assert_eq!(run("push 2\npush 2\ncmp\ncall $print").unwrap(), "0\n");
+        assert_eq!(run("push 3\npush 2\ncmp\ncall $print").unwrap(), "1\n");
+        // Signed, -1 is less than 1
+        assert_eq!(
+            run("push 0\npush 1\nsub\npush 1\ncmp\ncall $print").unwrap(),
+            "-1\n"
+        );
+    }
+
+    #[test]
+    fn json_dump() {
+        let (mut vm, _) = vm("push 2\nalloc\nmov r(3), 7\npush 5\nhalt");
+        vm.run().unwrap();
+        assert_eq!(
+            vm.to_json(),
+            "{\"pc\":9,\"stack\":[0,5],\"registers\":[0,0,0,7,0,0,0,0,0,0],\"call_stack\":[],\"memory\":[2,0,0]}"
+        );
+    }
+
+    #[test]
+    fn unbounded_push_loop_overflows() {
+        let (limited, _) = vm(".loop:\npush 1\njmp .loop");
+        let error = limited.with_max_stack(16).run().unwrap_err().to_string();
+        assert_eq!(error, "stack overflow (limit 16) at pc=0");
+
+        let (limited, _) = vm("push 1, 2, 3");
+        let error = limited.with_max_stack(2).run().unwrap_err().to_string();
+        assert!(error.starts_with("stack overflow (limit 2)"), "{}", error);
+        let (limited, _) = vm("push 1\ndup s(0)");
+        let error = limited.with_max_stack(1).run().unwrap_err().to_string();
+        assert!(error.starts_with("stack overflow (limit 1)"), "{}", error);
+    }
+
+    // The source is read before the destination is written, relative to the stack as it was
+    #[test]
+    fn mov_from_the_stack() {
+        let (mut machine, _) = vm("push 4\npush 5\nmov r(2), s(1)");
+        machine.run().unwrap();
+        assert_eq!(machine.register[2], 4);
+        assert_eq!(machine.stack, [4, 5]);
+
+        assert_eq!(stack("push 4\npush 5\npush 6\nmov s(0), s(2)"), [4, 5, 4]);
+        assert_eq!(stack("push 4\npush 5\nmov sa(1), sa(0)"), [4, 4]);
+        assert_eq!(stack("push 4\nmov r(0), 9\nmov s(0), r(0)"), [9]);
+    }
+
+    #[test]
+    fn exit_code_is_what_main_returned() {
+        let exit_code = |source: &str| {
+            let (mut machine, _) = vm(source);
+            machine.run().unwrap();
+            machine.exit_code()
+        };
+        assert_eq!(exit_code("push 3\nret"), 3);
+        assert_eq!(exit_code("ret"), 0);
+        assert_eq!(exit_code("push 3\nhalt"), 0);
+        assert_eq!(exit_code("push 5\ncall $exit"), 5);
+    }
+
+    #[test]
+    fn bulk_push() {
+        assert_eq!(stack("push 1, 2, 3\npush_n 4, 5"), [1, 2, 3, 4, 5]);
+        assert_eq!(stack("push_n 3"), [3]);
+
+        // A count with fewer values after it than it says
+        let push_n = OpCode::new(Operation::PushN, [Variant::Direct; 3]).as_usize();
+        let error = VM::new(vec![push_n, 3, 1], 0).run().unwrap_err();
+        assert_eq!(error.to_string(), "Missing operand at pc=0");
+    }
+
+    #[test]
+    fn stack_indices_are_bounds_checked() {
+        assert_eq!(
+            error("push 1\npush s(1)"),
+            "Stack(1) is outside the stack of 1 values at pc=2"
+        );
+        assert_eq!(
+            error("push 1\npush sa(1)"),
+            "StackAbsolute(1) is outside the stack of 1 values at pc=2"
+        );
+        assert_eq!(
+            error("mov s(0), 1"),
+            "Stack(0) is outside the stack of 0 values at pc=0"
+        );
+
+        let push = OpCode::new(Operation::Push, [Variant::Stack; 3]).as_usize();
+        let error = VM::new(vec![push, usize::MAX], 0).run().unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Stack(18446744073709551615) is outside"));
+    }
+
+    #[test]
+    fn pop_n() {
+        assert_eq!(stack("push 1, 2, 3, 4\npop_n 3"), [1]);
+        assert_eq!(stack("push 1\npop_n 0"), [1]);
+        assert_eq!(
+            error("push 1, 2\npop_n 3"),
+            "PopN needs 3 stack values, found 2 at pc=4"
+        );
+
+        // The count missing at the end of the program
+        let pop_n = OpCode::new(Operation::PopN, [Variant::Direct; 3]).as_usize();
+        let error = VM::new(vec![pop_n], 0).run().unwrap_err();
+        assert_eq!(error.to_string(), "Missing operand at pc=0");
+    }
+
+    #[test]
+    fn registers_are_bounds_checked() {
+        assert_eq!(stack("mov r(9), 4\npush r(9)"), [4]);
+        assert_eq!(
+            error("mov r(10), 4"),
+            "Register r(10) doesn't exist, there are 10 at pc=0"
+        );
+        assert_eq!(
+            error("push r(10)"),
+            "Register r(10) doesn't exist, there are 10 at pc=0"
+        );
+    }
+
+    #[test]
+    fn trace_has_a_line_per_instruction() {
+        let (machine, output) =
+            vm("push 2\n.loop:\npush 1\nsub\npush s(0)\njmp_if .loop\ncall $print");
+        let trace = Output::default();
+        let mut machine = machine.with_trace(Box::new(trace.clone()));
+        machine.run().unwrap();
+
+        let trace = trace.text();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), machine.instruction_count());
+        assert_eq!(lines.len(), 1 + 2 * 4 + 1);
+        assert_eq!(lines[0], "0x0: push 2 ; depth 0");
+        assert_eq!(lines[9], "0x9: call $print ; depth 1");
+        // Only the program's own output goes to the output
+        assert_eq!(output.text(), "0\n");
+    }
+
+    fn arithmetic(mode: Arithmetic, operation: Operation, lhs: isize, rhs: isize) -> Result<isize> {
+        let push = OpCode::new(Operation::Push, [Variant::Direct; 3]).as_usize();
+        let op = OpCode::new(operation, [Variant::None; 3]).as_usize();
+        let program = vec![push, lhs as usize, push, rhs as usize, op];
+        let mut machine = VM::new(program, 0).with_arithmetic(mode);
+        machine.run()?;
+        Ok(machine.stack[0] as isize)
+    }
+
+    #[test]
+    fn arithmetic_modes_at_overflow() {
+        let cases = [
+            (Operation::Add, isize::MAX, 1, isize::MIN, isize::MAX),
+            (Operation::Sub, isize::MIN, 1, isize::MAX, isize::MIN),
+            (Operation::Mult, isize::MAX, 2, -2, isize::MAX),
+            (Operation::Mult, isize::MIN, 2, 0, isize::MIN),
+            (Operation::Div, isize::MIN, -1, isize::MIN, isize::MAX),
+        ];
+        for (operation, lhs, rhs, wrapped, saturated) in cases {
+            let run = |mode| arithmetic(mode, operation, lhs, rhs);
+            assert_eq!(run(Arithmetic::Wrapping).unwrap(), wrapped);
+            assert_eq!(run(Arithmetic::Saturating).unwrap(), saturated);
+            let error = run(Arithmetic::Checked).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("{:?} overflowed at pc=4", operation)
+            );
+        }
+
+        // Without overflow they all agree
+        for mode in [
+            Arithmetic::Wrapping,
+            Arithmetic::Checked,
+            Arithmetic::Saturating,
+        ] {
+            assert_eq!(arithmetic(mode, Operation::Sub, 3, 5).unwrap(), -2);
+            assert_eq!(arithmetic(mode, Operation::Mult, -3, 5).unwrap(), -15);
+            assert_eq!(arithmetic(mode, Operation::Mod, isize::MIN, -1).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        for mode in [
+            Arithmetic::Wrapping,
+            Arithmetic::Checked,
+            Arithmetic::Saturating,
+        ] {
+            let error = arithmetic(mode, Operation::Div, 7, 0).unwrap_err();
+            assert_eq!(error.to_string(), "Div by zero at pc=4");
+            let error = arithmetic(mode, Operation::Mod, 7, 0).unwrap_err();
+            assert_eq!(error.to_string(), "Mod by zero at pc=4");
+        }
+        assert_eq!(error("push 7\npush 0\ndiv"), "Div by zero at pc=4");
+    }
+
+    // Signed, rounding towards zero, with the remainder taking the sign of lhs
+    #[test]
+    fn division_of_negative_operands() {
+        let cases = [
+            (Operation::Div, -6, 2, -3),
+            (Operation::Div, -7, 2, -3),
+            (Operation::Div, 7, -2, -3),
+            (Operation::Div, -7, -2, 3),
+            (Operation::Mod, -7, 2, -1),
+            (Operation::Mod, 7, -2, 1),
+            (Operation::Mod, -7, -2, -1),
+        ];
+        for (operation, lhs, rhs, expected) in cases {
+            let value = arithmetic(Arithmetic::Wrapping, operation, lhs, rhs).unwrap();
+            assert_eq!(value, expected, "{:?} {} {}", operation, lhs, rhs);
+        }
+    }
+
+    #[test]
+    fn swap_with_depth() {
+        assert_eq!(stack("push 1, 2, 3, 4, 5\nswap 2"), [1, 2, 5, 4, 3]);
+        assert_eq!(stack("push 1, 2, 3\nswap"), [1, 3, 2]);
+        assert_eq!(stack("push 1, 2, 3\nswap 1"), [1, 3, 2]);
+        assert_eq!(stack("push 1, 2, 3\nswap 0"), [1, 2, 3]);
+
+        // The depth missing at the end of the program
+        let swap = OpCode::new(Operation::Swap, [Variant::Direct; 3]).as_usize();
+        let error = VM::new(vec![swap], 0).run().unwrap_err();
+        assert_eq!(error.to_string(), "Missing operand at pc=0");
+
+        // Only a number can be a depth
+        let swap = OpCode::new(Operation::Swap, [Variant::Register; 3]).as_usize();
+        let error = VM::new(vec![swap, 0], 0).run().unwrap_err();
+        assert_eq!(error.to_string(), "Invalid swap depth Register at pc=0");
+    }
+
+    // Every destination with every source, starting from 4 5 on the stack and 8 in r(1)
+    #[test]
+    fn mov_matrix() {
+        let cases: [(&str, [usize; 2], [usize; 2]); 12] = [
+            ("mov r(0), 3", [4, 5], [3, 8]),
+            ("mov r(0), r(1)", [4, 5], [8, 8]),
+            ("mov r(0), s(1)", [4, 5], [4, 8]),
+            ("mov r(0), sa(1)", [4, 5], [5, 8]),
+            ("mov s(1), 3", [3, 5], [0, 8]),
+            ("mov s(1), r(1)", [8, 5], [0, 8]),
+            ("mov s(1), s(0)", [5, 5], [0, 8]),
+            ("mov s(1), sa(1)", [5, 5], [0, 8]),
+            ("mov sa(1), 3", [4, 3], [0, 8]),
+            ("mov sa(1), r(1)", [4, 8], [0, 8]),
+            ("mov sa(1), s(1)", [4, 4], [0, 8]),
+            ("mov sa(1), sa(0)", [4, 4], [0, 8]),
+        ];
+        for (mov, stack, registers) in cases {
+            let (mut machine, _) = vm(&format!("push 4\npush 5\nmov r(1), 8\n{}", mov));
+            machine.run().unwrap();
+            assert_eq!(machine.stack, stack, "{}", mov);
+            assert_eq!(machine.register[..2], registers, "{}", mov);
+        }
+    }
+
+    #[test]
+    fn dup_of_every_readable_operand() {
+        assert_eq!(stack("push 4\npush 3\ndup s(1)"), [4, 3, 4]);
+        assert_eq!(stack("push 4\nmov r(0), 7\ndup r(0)"), [4, 7]);
+        assert_eq!(stack("push 4\npush 3\ndup sa(0)"), [4, 3, 4]);
+        assert_eq!(stack("push 4\ndup 9"), [4, 9]);
+    }
+
+    // One push, three times round the four instruction loop, then halt
+    #[test]
+    fn instruction_count_of_a_loop() {
+        let source = "push 3\n.loop:\npush 1\nsub\ndup s(0)\njmp_if .loop\nhalt";
+        let (machine, _) = vm(source);
+        let mut machine = machine.with_histogram();
+        machine.run().unwrap();
+        assert_eq!(machine.instruction_count(), 14);
+
+        let histogram = machine.op_histogram().unwrap();
+        let total: usize = histogram.iter().map(|(_, count)| count).sum();
+        assert_eq!(total, 14);
+        let ran: Vec<_> = histogram
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        let count = |operation| ran.iter().find(|(op, _)| *op == operation).unwrap().1;
+        assert_eq!(ran.len(), 5);
+        assert_eq!(count(Operation::Push), 4);
+        assert_eq!(count(Operation::Sub), 3);
+        assert_eq!(count(Operation::Dup), 3);
+        assert_eq!(count(Operation::JmpIf), 3);
+        assert_eq!(count(Operation::Halt), 1);
+
+        let (mut machine, _) = vm(source);
+        machine.run().unwrap();
+        assert_eq!(machine.instruction_count(), 14);
+        assert!(machine.op_histogram().is_none());
+    }
+
+    // The assembler encodes each operand, the disassembler shows it as it was written and the VM
+    // reads the value it names, starting from 4 5 on the stack and 8 in r(2)
+    #[test]
+    fn assembler_disassembler_and_vm_agree_on_operands() {
+        for (operand, value) in [
+            ("s(0)", 5),
+            ("s(1)", 4),
+            ("sa(0)", 4),
+            ("sa(1)", 5),
+            ("r(2)", 8),
+            ("42", 42),
+        ] {
+            let (mut machine, _) = vm(&format!("push 4\npush 5\nmov r(2), 8\ndup {}", operand));
+            let program = ProgramParser::new(machine.program().to_vec())
+                .parse()
+                .unwrap();
+            let dup = program.actions.last().unwrap();
+            assert_eq!(dup.operation, Operation::Dup);
+            assert_eq!(dup.operands[0].format(), operand);
+
+            machine.run().unwrap();
+            assert_eq!(machine.stack.last(), Some(&value), "{}", operand);
+        }
+    }
+
+    // Only the slot named changes, counted from the top for s and from the bottom for sa
+    #[test]
+    fn mov_a_number_into_a_stack_slot() {
+        let setup = "push 1\npush 2\npush 3\npush 4\n";
+        assert_eq!(stack(&format!("{}mov s(0), 9", setup)), [1, 2, 3, 9]);
+        assert_eq!(stack(&format!("{}mov s(2), 9", setup)), [1, 9, 3, 4]);
+        assert_eq!(stack(&format!("{}mov sa(0), 9", setup)), [9, 2, 3, 4]);
+        assert_eq!(stack(&format!("{}mov sa(2), 9", setup)), [1, 2, 9, 4]);
+        assert!(error(&format!("{}mov s(4), 9", setup)).contains("outside the stack"));
+    }
+
+    #[test]
+    fn garbage_words_are_errors() {
+        let error = |program: Vec<usize>| VM::new(program, 0).run().unwrap_err().to_string();
+        assert_eq!(
+            error(vec![usize::MAX]),
+            "Invalid opcode 0xffffffffffffffff at pc=0"
+        );
+        let call = |variant| OpCode::new(Operation::Call, [variant; 3]).as_usize();
+        assert_eq!(
+            error(vec![call(Variant::Native), 999]),
+            "Unknown native function 999 at pc=0"
+        );
+        assert_eq!(
+            error(vec![call(Variant::Indirect), 0]),
+            "Invalid call variant Indirect at pc=0"
+        );
+    }
+
+    #[test]
+    fn pow_exponents_have_to_fit() {
+        assert_eq!(stack("push 2, 10\ncall $pow"), [2, 10, 1024]);
+        assert_eq!(
+            error("push 2\npush 0\npush 1\nsub\ncall $pow"),
+            "pow exponent -1 is out of range at pc=7"
+        );
+        assert_eq!(
+            error("push 1, 4294967296\ncall $pow"),
+            "pow exponent 4294967296 is out of range at pc=4"
+        );
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        assert_eq!(stack("push 12\npush 10\nand"), [8]);
+        assert_eq!(stack("push 12\npush 10\nor"), [14]);
+        assert_eq!(stack("push 12\npush 10\nxor"), [6]);
+        assert_eq!(stack("push 0\npush 1\nsub\npush 255\nand"), [255]);
+        assert!(error("push 1\nxor").starts_with("Xor needs 2 stack values, found 1"));
     }
 }